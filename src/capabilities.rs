@@ -0,0 +1,320 @@
+//! Comparing client capabilities
+//!
+//! The language server is initialized with the capabilities of the first
+//! client and LSP has no way to renegotiate them. A client attaching later
+//! which supports more than the first one won't get the features the server
+//! enables only for capable clients, we can only tell the user about it.
+
+use serde_json::{json, Value};
+
+/// Capabilities which don't change what the server sends, or which the
+/// multiplexer takes care of itself, their differences aren't reported
+///
+/// - `dynamicRegistration` anywhere: a server can't register a feature
+///   dynamically for a client which didn't declare support, it registers it
+///   statically in the `initialize` result instead, which every client gets.
+/// - `general.markdown` and `general.regularExpressions`: they describe the
+///   client's markdown renderer and regex engine, the server output is still
+///   usable even if it was formatted for another client.
+/// - `workspace.workspaceFolders`: clients share the instance of their
+///   workspace root, folders they add later are tracked for each client.
+const IGNORED: &[&str] = &[
+    "dynamicRegistration",
+    "general.markdown",
+    "general.regularExpressions",
+    "workspace.workspaceFolders",
+];
+
+/// Find capabilities `client` supports which weren't negotiated with the server
+///
+/// Returns the paths of the capabilities like `window.workDoneProgress`. A
+/// capability is supported if it's set to anything but `false` or `null`,
+/// values of lists like supported kinds are compared separately.
+pub fn missing(negotiated: &Value, client: &Value) -> Vec<String> {
+    let mut missing = Vec::new();
+    compare(negotiated, client, &mut String::new(), &mut missing);
+    missing
+}
+
+fn compare(negotiated: &Value, client: &Value, path: &mut String, missing: &mut Vec<String>) {
+    if is_ignored(path) {
+        return;
+    }
+    match (negotiated, client) {
+        (_, Value::Null | Value::Bool(false)) => {}
+        (Value::Object(negotiated), Value::Object(client)) => {
+            for (key, client) in client {
+                let len = path.len();
+                push_key(path, key);
+                compare(
+                    negotiated.get(key).unwrap_or(&Value::Null),
+                    client,
+                    path,
+                    missing,
+                );
+                path.truncate(len);
+            }
+        }
+        // An object usually means the feature is supported even if all of its
+        // options are off, report the object unless one of its options is
+        // more specific.
+        (Value::Null | Value::Bool(false), Value::Object(_)) => {
+            let reported = missing.len();
+            compare(&Value::Object(Default::default()), client, path, missing);
+            if missing.len() == reported && is_relevant(client, path) {
+                missing.push(path.clone());
+            }
+        }
+        (Value::Array(negotiated), Value::Array(client)) => {
+            for value in client {
+                if !negotiated.contains(value) {
+                    missing.push(format!("{path}={value}"));
+                }
+            }
+        }
+        (Value::Null | Value::Bool(false), _) => missing.push(path.clone()),
+        // Some other value like a number or a different structure, there's no
+        // telling which one is more capable.
+        _ => {}
+    }
+}
+
+fn push_key(path: &mut String, key: &str) {
+    if !path.is_empty() {
+        path.push('.');
+    }
+    path.push_str(key);
+}
+
+/// Does `value` contain anything but ignored capabilities
+fn is_relevant(value: &Value, path: &mut String) -> bool {
+    if is_ignored(path) {
+        return false;
+    }
+    match value {
+        Value::Object(object) if !object.is_empty() => object.iter().any(|(key, value)| {
+            let len = path.len();
+            push_key(path, key);
+            let relevant = is_relevant(value, path);
+            path.truncate(len);
+            relevant
+        }),
+        _ => true,
+    }
+}
+
+fn is_ignored(path: &str) -> bool {
+    IGNORED.iter().any(|ignored| {
+        if ignored.contains('.') {
+            path == *ignored
+        } else {
+            path.rsplit('.').next() == Some(*ignored)
+        }
+    })
+}
+
+/// Capabilities of a typical editor for `initialize` requests we send ourselves
+///
+/// Language servers like rust-analyzer only enable features like settings
+/// pulled with `workspace/configuration`, work done progress or file watching
+/// for clients which support them, and the instance keeps the result for the
+/// editors attaching later. Editors supporting less may get features they
+/// can't use, the same as if another editor had started the instance.
+pub fn default_client() -> Value {
+    json!({
+        "general": {
+            "staleRequestSupport": {
+                "cancel": true,
+                "retryOnContentModified": [],
+            },
+        },
+        "window": {
+            "workDoneProgress": true,
+            "showMessage": {},
+            "showDocument": { "support": true },
+        },
+        "workspace": {
+            "applyEdit": true,
+            "workspaceEdit": {
+                "documentChanges": true,
+                "resourceOperations": ["create", "rename", "delete"],
+            },
+            "configuration": true,
+            "workspaceFolders": true,
+            "didChangeConfiguration": { "dynamicRegistration": true },
+            "didChangeWatchedFiles": {
+                "dynamicRegistration": true,
+                "relativePatternSupport": true,
+            },
+            "symbol": {},
+            "executeCommand": {},
+            "semanticTokens": { "refreshSupport": true },
+            "inlayHint": { "refreshSupport": true },
+            "codeLens": { "refreshSupport": true },
+            "diagnostics": { "refreshSupport": true },
+        },
+        "textDocument": {
+            "synchronization": { "didSave": true },
+            "completion": {
+                "completionItem": {
+                    "snippetSupport": true,
+                    "insertReplaceSupport": true,
+                    "labelDetailsSupport": true,
+                    "documentationFormat": ["markdown", "plaintext"],
+                    "resolveSupport": {
+                        "properties": ["documentation", "detail", "additionalTextEdits"],
+                    },
+                },
+            },
+            "hover": { "contentFormat": ["markdown", "plaintext"] },
+            "signatureHelp": {
+                "signatureInformation": {
+                    "documentationFormat": ["markdown", "plaintext"],
+                    "parameterInformation": { "labelOffsetSupport": true },
+                },
+            },
+            "definition": { "linkSupport": true },
+            "references": {},
+            "documentHighlight": {},
+            "documentSymbol": { "hierarchicalDocumentSymbolSupport": true },
+            "codeAction": {
+                "codeActionLiteralSupport": {
+                    "codeActionKind": {
+                        "valueSet": [
+                            "",
+                            "quickfix",
+                            "refactor",
+                            "refactor.extract",
+                            "refactor.inline",
+                            "refactor.rewrite",
+                            "source",
+                            "source.organizeImports",
+                        ],
+                    },
+                },
+                "resolveSupport": { "properties": ["edit"] },
+            },
+            "codeLens": {},
+            "formatting": {},
+            "rename": { "prepareSupport": true },
+            "publishDiagnostics": {
+                "relatedInformation": true,
+                "codeDescriptionSupport": true,
+            },
+            "foldingRange": {},
+            "selectionRange": {},
+            "semanticTokens": {
+                "requests": { "range": true, "full": { "delta": true } },
+                "tokenTypes": [],
+                "tokenModifiers": [],
+                "formats": ["relative"],
+            },
+            "inlayHint": {
+                "resolveSupport": {
+                    "properties": ["textEdits", "tooltip", "label.tooltip", "label.command"],
+                },
+            },
+        },
+    })
+}
+
+/// Merge the `overrides` into the capabilities `target`
+///
+/// Objects are merged recursively, any other value replaces the one in
+/// `target`.
+pub fn merge(target: &mut Value, overrides: &Value) {
+    match (target, overrides) {
+        (Value::Object(target), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (target, overrides) => *target = overrides.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn same_capabilities() {
+        let caps = json!({ "window": { "workDoneProgress": true } });
+        assert!(missing(&caps, &caps).is_empty());
+    }
+
+    #[test]
+    fn new_capabilities() {
+        let negotiated = json!({
+            "textDocument": { "completion": { "completionItem": { "snippetSupport": false } } },
+            "window": { "workDoneProgress": false },
+        });
+        let client = json!({
+            "textDocument": {
+                "completion": { "completionItem": { "snippetSupport": true } },
+                "diagnostic": { "relatedDocumentSupport": false },
+            },
+            "window": { "workDoneProgress": true },
+        });
+        assert_eq!(
+            missing(&negotiated, &client),
+            [
+                "textDocument.completion.completionItem.snippetSupport",
+                "textDocument.diagnostic",
+                "window.workDoneProgress",
+            ]
+        );
+
+        // Less capable clients are fine.
+        assert!(missing(&client, &negotiated).is_empty());
+    }
+
+    #[test]
+    fn new_list_values() {
+        let negotiated = json!({ "hover": { "contentFormat": ["plaintext"] } });
+        let client = json!({ "hover": { "contentFormat": ["markdown", "plaintext"] } });
+        assert_eq!(
+            missing(&negotiated, &client),
+            ["hover.contentFormat=\"markdown\""]
+        );
+    }
+
+    #[test]
+    fn ignored_capabilities() {
+        let client = json!({
+            "general": { "markdown": { "parser": "marked" } },
+            "textDocument": { "hover": { "dynamicRegistration": true } },
+        });
+        assert!(missing(&json!({}), &client).is_empty());
+
+        let client = json!({ "textDocument": { "hover": { "dynamicRegistration": true, "contentFormat": ["markdown"] } } });
+        assert_eq!(
+            missing(&json!({}), &client),
+            ["textDocument.hover.contentFormat"]
+        );
+    }
+
+    #[test]
+    fn merging_overrides() {
+        let mut caps = json!({
+            "textDocument": { "hover": { "contentFormat": ["plaintext"] } },
+            "window": null,
+        });
+        merge(
+            &mut caps,
+            &json!({
+                "textDocument": { "hover": { "contentFormat": ["markdown"] }, "inlayHint": {} },
+                "window": { "workDoneProgress": true },
+            }),
+        );
+        assert_eq!(
+            caps,
+            json!({
+                "textDocument": { "hover": { "contentFormat": ["markdown"] }, "inlayHint": {} },
+                "window": { "workDoneProgress": true },
+            })
+        );
+    }
+}
@@ -1,77 +1,132 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
 use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{bail, ensure, Context, Result};
-use percent_encoding::percent_decode_str;
+use anyhow::{bail, Context, Result};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
 use serde_json::Value;
-use tokio::io::BufReader;
+use tokio::io::{AsyncWriteExt, BufReader};
 use tokio::sync::mpsc::error::SendError;
-use tokio::sync::{mpsc, Mutex};
-use tokio::task;
-use tracing::{debug, error, info, warn, Instrument};
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+use tokio::time::Instant;
+use tokio::{select, task};
+use tracing::{debug, error, info, trace, warn, Instrument};
 use uriparse::URI;
 
+use crate::capabilities;
+use crate::config::{Config, ProjectConfig, RateLimitAction};
 use crate::instance::{self, Instance, InstanceKey, InstanceMap};
-use crate::lsp::ext::{self, LspMuxOptions, Tag};
+use crate::lsp::ext::{self, LspMuxOptions, RejectReason, Tag};
 use crate::lsp::jsonrpc::{
-    self, Message, Request, RequestId, ResponseError, ResponseSuccess, Version,
+    self, Message, Notification, Request, RequestId, ResponseError, ResponseSuccess, Version,
 };
 use crate::lsp::transport::{LspReader, LspWriter};
-use crate::lsp::InitializeParams;
+use crate::lsp::{ClientInfo, InitializeParams, WorkspaceFolder};
+use crate::metrics;
 use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf, Stream};
+use crate::workspace;
+
+/// How long a client which closed its output still gets responses to its
+/// pending requests
+const PENDING_RESPONSES_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many messages of a client can wait for room in a full language server
+/// queue while the client is still read from for responses and cancellations
+const PAUSED_MESSAGES: usize = 64;
 
 /// Read first client message and dispatch lsp mux commands
+///
+/// Returns once the client connection is closed.
 pub async fn process(
     socket: Stream,
     client_id: usize,
     instance_map: Arc<Mutex<InstanceMap>>,
+    auth_token: Option<Arc<String>>,
 ) -> Result<()> {
+    let config = instance_map.lock().await.config().clone();
     let (socket_read, socket_write) = socket.into_split();
-    let mut reader = LspReader::new(BufReader::new(socket_read), "client");
-    let writer = LspWriter::new(socket_write, "client");
+    let mut reader = LspReader::new(BufReader::new(socket_read), "client")
+        .max_content_length(config.max_message_size);
+    let mut writer = LspWriter::new(socket_write, "client").count_bytes(&metrics::BYTES_TO_CLIENTS);
 
     // Read the first client message, this must be `initialize` request.
-    let req = match reader
-        .read_message()
+    let req = match tokio::time::timeout(handshake_timeout(&config), reader.read_message())
         .await
+        .context("timed out waiting for `initialize` request")?
         .context("receive `initialize` request")?
         .context("channel closed")?
     {
         Message::Request(req) if req.method == "initialize" => req,
         _ => bail!("first client message was not `initialize` request"),
     };
+    // Answer in the same format the client uses.
+    writer.set_content_type(reader.content_type());
     let mut init_params = serde_json::from_value::<InitializeParams>(req.params.clone())
         .context("parse `initialize` request params")?;
 
     // Remove `lspMux` from `initializationOptions`, it's ra-multiplex extension
     // and we don't want to forward it to the real language server.
-    let options = init_params
+    let mut options = init_params
         .initialization_options
         .as_mut()
         .context("missing `initializationOptions` in `initialize` request")?
         .lsp_mux
         .take()
         .context("missing `lspMux` in `initializationOptions` in `initialize` request")?;
-    ensure!(
-        options.version == LspMuxOptions::PROTOCOL_VERSION,
-        "unsupported protocol version {:?}, expected {:?}",
-        &options.version,
-        LspMuxOptions::PROTOCOL_VERSION,
-    );
+    if !LspMuxOptions::is_compatible(&options.version) {
+        let message = format!(
+            "ra-multiplex client protocol version {:?} is incompatible with server v{} using protocol version {:?}",
+            &options.version,
+            env!("CARGO_PKG_VERSION"),
+            LspMuxOptions::PROTOCOL_VERSION,
+        );
+        return reject(writer, req.id, RejectReason::IncompatibleVersion, message).await;
+    }
+
+    // Take the token out so it doesn't end up in the logs.
+    let token = options.token.take();
+    if let Some(auth_token) = auth_token {
+        let valid = token.is_some_and(|token| constant_time_eq(&token, &auth_token));
+        if !valid {
+            let message = "missing or invalid auth token".to_owned();
+            return reject(writer, req.id, RejectReason::Unauthorized, message).await;
+        }
+    }
 
     debug!(?options, "lspmux initialization");
     match options.method {
+        ext::Request::Connect { .. } if instance_map.lock().await.draining() => {
+            let message = "server is draining".to_owned();
+            reject(writer, req.id, RejectReason::Draining, message).await
+        }
         ext::Request::Connect {
             server,
             args,
             env,
             cwd,
+            private,
+            compression,
+            client_name,
         } => {
+            let (reader, writer) = match compression {
+                Some(compression) => compress(reader, writer, compression, &config).await?,
+                None => (reader, writer),
+            };
             connect(
                 client_id,
                 instance_map,
-                (server, args, env, cwd),
+                ConnectOptions {
+                    server,
+                    args,
+                    env,
+                    cwd,
+                    private,
+                    client_name,
+                },
                 req,
                 init_params,
                 reader,
@@ -80,29 +135,169 @@ pub async fn process(
             .await
         }
         ext::Request::Status {} => status(instance_map, writer).await,
+        ext::Request::Health {} => health(instance_map, writer).await,
+        ext::Request::Kill { workspace_root } => kill(workspace_root, instance_map, writer).await,
+        ext::Request::Pin {
+            workspace_root,
+            pinned,
+        } => pin(workspace_root, pinned, instance_map, writer).await,
         ext::Request::Reload { cwd } => reload(cwd, instance_map, writer).await,
+        ext::Request::Drain { timeout } => drain(timeout, instance_map, writer).await,
+        ext::Request::Warmup {
+            server,
+            args,
+            env,
+            workspace_root,
+        } => warmup_request((server, args, env), workspace_root, instance_map, writer).await,
+        ext::Request::Trace { workspace_root } => {
+            trace(workspace_root, instance_map, reader, writer).await
+        }
+        ext::Request::Handover { workspace_root } => {
+            handover(workspace_root, instance_map, writer).await
+        }
+        ext::Request::IdMap {
+            workspace_root,
+            limit,
+        } => id_map(workspace_root, limit, instance_map, writer).await,
+    }
+}
+
+/// Answer the `initialize` request with an error and close the connection
+async fn reject(
+    mut writer: LspWriter<OwnedWriteHalf>,
+    id: RequestId,
+    reason: RejectReason,
+    message: String,
+) -> Result<()> {
+    let rejection = ext::Rejection {
+        reason,
+        server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        protocol_version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+    };
+    writer
+        .write_message(&Message::ResponseError(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: ext::Rejection::CODE,
+                message: message.clone(),
+                data: Some(serde_json::to_value(rejection).unwrap()),
+            },
+            id: Some(id),
+        }))
+        .await
+        .context("writing response")?;
+    _ = writer.into_inner().shutdown().await;
+    bail!(message)
+}
+
+/// Compare strings in time depending only on their length
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
     }
+    let diff = a
+        .bytes()
+        .zip(b.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    // Keep the optimizer from turning this into an early returning comparison.
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+#[test]
+fn comparing_tokens() {
+    assert!(constant_time_eq("secret", "secret"));
+    assert!(!constant_time_eq("secret", "secreT"));
+    assert!(!constant_time_eq("secret", "secret2"));
+    assert!(!constant_time_eq("", "secret"));
+}
+
+/// How long a client can take to send each handshake message
+fn handshake_timeout(config: &Config) -> Duration {
+    Duration::from_secs(config.handshake_timeout.into())
 }
 
 #[derive(Clone)]
 pub struct Client {
     id: usize,
-    sender: mpsc::Sender<Message>,
+    /// Name the client gave itself in the `connect` options
+    name: Option<String>,
+    sender: mpsc::UnboundedSender<Message>,
+    /// Messages in the channel `input_task` didn't take yet
+    queued: Arc<AtomicUsize>,
+    /// Warn when this many messages are queued, see `client_queue_warning`
+    queue_warning: Option<usize>,
+    /// Wakes up `output_task` and asks it to close the connection
+    disconnect: Arc<Notify>,
 }
 
 impl Client {
-    fn new(id: usize) -> (Client, mpsc::Receiver<Message>) {
-        let (sender, receiver) = mpsc::channel(16);
-        (Client { id, sender }, receiver)
+    fn new(
+        id: usize,
+        name: Option<String>,
+        queue_warning: Option<usize>,
+    ) -> (Client, mpsc::UnboundedReceiver<Message>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let disconnect = Arc::new(Notify::new());
+        (
+            Client {
+                id,
+                name,
+                sender,
+                queued: Arc::default(),
+                queue_warning,
+                disconnect,
+            },
+            receiver,
+        )
     }
 
     pub fn id(&self) -> usize {
         self.id
     }
 
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Number of messages waiting to be written to the client
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
     /// Send a message to the client channel
     pub async fn send_message(&self, message: Message) -> Result<(), SendError<Message>> {
-        self.sender.send(message).await
+        self.enqueue(message)
+    }
+
+    fn enqueue(&self, message: Message) -> Result<(), SendError<Message>> {
+        // Counted before sending, `input_task` can take it right away.
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Err(err) = self.sender.send(message) {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(err);
+        }
+        if self.queue_warning == Some(queued) {
+            warn!(client_id = self.id, queued, "client is reading slowly");
+        }
+        Ok(())
+    }
+
+    /// Send a message to the client channel without waiting
+    ///
+    /// The channel is unbounded so delivery to the other clients of the
+    /// instance never waits for this one, `input_task` disconnects clients
+    /// which stop reading. If the client input is already closed the client is
+    /// disconnected right away.
+    pub fn send_message_nowait(&self, message: Message) {
+        if self.enqueue(message).is_err() {
+            self.disconnect();
+        }
+    }
+
+    /// Ask the client to close its connection
+    pub fn disconnect(&self) {
+        self.disconnect.notify_one();
     }
 }
 
@@ -123,6 +318,352 @@ async fn status(
         .context("writing response")
 }
 
+async fn health(
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let health = InstanceMap::health(&instance_map).await;
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(health).unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+/// Answer an lspmux request selecting instances which don't exist
+async fn no_instance_found(mut writer: LspWriter<OwnedWriteHalf>) -> Result<()> {
+    writer
+        .write_message(&Message::ResponseError(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: 0,
+                message: "no instance found".into(),
+                data: None,
+            },
+            id: Some(RequestId::Number(0)),
+        }))
+        .await
+        .context("writing response")
+}
+
+async fn kill(
+    workspace_root: String,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let instances = {
+        let mut instance_map = instance_map.lock().await;
+        match instance_map.find_workspace_root(&workspace_root) {
+            Some(root) => instance_map.remove_by_workspace_root(&root),
+            None => Vec::new(),
+        }
+    };
+    if instances.is_empty() {
+        debug!(?workspace_root, "no instance found for workspace root");
+        return no_instance_found(writer).await;
+    }
+
+    let (instances, status) = task::spawn_blocking(move || {
+        let status = instances
+            .iter()
+            .map(|instance| instance.get_status())
+            .collect();
+        (instances, status)
+    })
+    .await
+    .unwrap();
+    for instance in instances {
+        instance.kill().await;
+    }
+
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(ext::KillResponse { instances: status }).unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+async fn pin(
+    workspace_root: String,
+    pinned: bool,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let instances = {
+        let instance_map = instance_map.lock().await;
+        match instance_map.find_workspace_root(&workspace_root) {
+            Some(root) => instance_map.get_by_workspace_root(&root),
+            None => Vec::new(),
+        }
+    };
+    if instances.is_empty() {
+        debug!(?workspace_root, "no instance found for workspace root");
+        return no_instance_found(writer).await;
+    }
+
+    for instance in &instances {
+        info!(?workspace_root, pinned, "changing pinned state");
+        instance.set_pinned(pinned);
+    }
+    let status = task::spawn_blocking(move || {
+        instances
+            .iter()
+            .map(|instance| instance.get_status())
+            .collect()
+    })
+    .await
+    .unwrap();
+
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(ext::PinResponse { instances: status }).unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+async fn id_map(
+    workspace_root: String,
+    limit: usize,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let instances = {
+        let instance_map = instance_map.lock().await;
+        match instance_map.find_workspace_root(&workspace_root) {
+            Some(root) => instance_map.get_by_workspace_root(&root),
+            None => Vec::new(),
+        }
+    };
+    if instances.is_empty() {
+        debug!(?workspace_root, "no instance found for workspace root");
+        return no_instance_found(writer).await;
+    }
+
+    let mut id_maps = Vec::new();
+    for instance in &instances {
+        id_maps.push(instance.id_map(limit).await);
+    }
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(ext::IdMapResponse { instances: id_maps }).unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+/// Hand the instances over to new language servers one after another
+///
+/// Without a workspace root every instance is handed over. Instances which
+/// couldn't be handed over keep their old language server.
+async fn handover(
+    workspace_root: Option<String>,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let instances = {
+        let instance_map = instance_map.lock().await;
+        match &workspace_root {
+            Some(workspace_root) => match instance_map.find_workspace_root(workspace_root) {
+                Some(root) => instance_map.get_by_workspace_root(&root),
+                None => Vec::new(),
+            },
+            None => instance_map.instances(),
+        }
+    };
+    if instances.is_empty() {
+        debug!(?workspace_root, "no instance found for workspace root");
+        return no_instance_found(writer).await;
+    }
+
+    let mut handed_over = Vec::new();
+    for instance in instances {
+        let key = instance.key();
+        let old_pid = instance.pid();
+        info!(workspace_root = key.workspace_root, "handing instance over");
+        let result = instance.handover().await;
+        if let Err(err) = &result {
+            error!(?err, "handover failed, keeping the old language server");
+        }
+        handed_over.push(ext::HandedOver {
+            server: key.server.clone(),
+            workspace_root: key.workspace_root.clone(),
+            old_pid,
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+            pid: result.ok(),
+        });
+    }
+
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(ext::HandoverResponse {
+                instances: handed_over,
+            })
+            .unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+async fn drain(
+    timeout: Option<u32>,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let timeout = timeout.map(|secs| Duration::from_secs(secs.into()));
+    info!(?timeout, "draining");
+    instance_map.lock().await.drain(timeout);
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess::null(
+            RequestId::Number(0),
+        )))
+        .await
+        .context("writing response")
+}
+
+async fn warmup_request(
+    command: (String, Vec<String>, BTreeMap<String, String>),
+    workspace_root: String,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    info!(?workspace_root, "warming up");
+    let instance = match warmup(instance_map, command, workspace_root).await {
+        Ok(instance) => instance,
+        Err(err) => {
+            warn!(?err, "warmup failed");
+            return writer
+                .write_message(&Message::ResponseError(ResponseError {
+                    jsonrpc: Version,
+                    error: jsonrpc::Error {
+                        code: 0,
+                        message: format!("{err:#}"),
+                        data: None,
+                    },
+                    id: Some(RequestId::Number(0)),
+                }))
+                .await
+                .context("writing response");
+        }
+    };
+    let status = task::spawn_blocking(move || instance.get_status())
+        .await
+        .unwrap();
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(ext::WarmupResponse { instance: status }).unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+/// Stream the metadata of the messages exchanged with the selected instances
+/// to an observer
+///
+/// Returns once the observer disconnects or all the instances are gone.
+async fn trace(
+    workspace_root: String,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut reader: LspReader<BufReader<OwnedReadHalf>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let instances = {
+        let instance_map = instance_map.lock().await;
+        match instance_map.find_workspace_root(&workspace_root) {
+            Some(root) => instance_map.get_by_workspace_root(&root),
+            None => Vec::new(),
+        }
+    };
+    if instances.is_empty() {
+        debug!(?workspace_root, "no instance found for workspace root");
+        return no_instance_found(writer).await;
+    }
+
+    // Merge the events of all instances, the forwarding tasks don't keep the
+    // instances alive and end when the instance or the observer is gone.
+    let (events, mut events_rx) = mpsc::channel(1);
+    for instance in &instances {
+        let mut trace = instance.trace();
+        let events = events.clone();
+        task::spawn(async move {
+            let mut skipped = 0;
+            loop {
+                match trace.recv().await {
+                    Ok(mut event) => {
+                        event.skipped = std::mem::take(&mut skipped);
+                        if events.send(event).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(lagged)) => skipped += lagged,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+    drop(events);
+
+    let status = task::spawn_blocking(move || {
+        instances
+            .iter()
+            .map(|instance| instance.get_status())
+            .collect()
+    })
+    .await
+    .unwrap();
+    info!(?workspace_root, "tracing");
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(ext::TraceResponse { instances: status }).unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")?;
+
+    loop {
+        select! {
+            event = events_rx.recv() => {
+                let Some(event) = event else {
+                    debug!("traced instances are gone");
+                    return Ok(());
+                };
+                let notif = Notification {
+                    jsonrpc: Version,
+                    method: "$/lspmux/trace".into(),
+                    params: serde_json::to_value(event).unwrap(),
+                };
+                writer
+                    .write_message(&notif.into())
+                    .await
+                    .context("writing trace event")?;
+            }
+            // Observers don't send anything, this only waits for them to
+            // disconnect.
+            message = reader.read_message() => {
+                if matches!(message, Ok(None) | Err(_)) {
+                    debug!("trace observer disconnected");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
 async fn reload(
     cwd: String,
     instance_map: Arc<Mutex<InstanceMap>>,
@@ -147,51 +688,247 @@ async fn reload(
             .await
             .context("writing response")?;
     } else {
-        writer
-            .write_message(&Message::ResponseError(ResponseError {
-                jsonrpc: Version,
-                error: jsonrpc::Error {
-                    code: 0,
-                    message: "no instance found".into(),
-                    data: None,
-                },
-                id: RequestId::Number(0),
-            }))
-            .await
-            .context("writing response")?;
         debug!(?cwd, "no instance found for path");
+        no_instance_found(writer).await?;
     }
 
     Ok(())
 }
 
+/// Confirm the compression the client asked for and use it for the rest of
+/// the connection
+///
+/// The client doesn't send anything else before the confirmation, nothing
+/// read ahead is lost.
+async fn compress(
+    reader: LspReader<BufReader<OwnedReadHalf>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+    compression: ext::Compression,
+    config: &Config,
+) -> Result<(
+    LspReader<BufReader<OwnedReadHalf>>,
+    LspWriter<OwnedWriteHalf>,
+)> {
+    debug!(?compression, "compressing connection");
+    let notif = Notification {
+        jsonrpc: Version,
+        method: "$/lspmux/compression".into(),
+        params: serde_json::to_value(ext::CompressionParams { compression }).unwrap(),
+    };
+    writer
+        .write_message(&notif.into())
+        .await
+        .context("writing compression confirmation")?;
+
+    let content_type = reader.content_type().map(str::to_owned);
+    let (read, write) = match compression {
+        ext::Compression::Zstd => (
+            OwnedReadHalf::zstd(reader.into_inner()),
+            writer.into_inner().zstd(),
+        ),
+    };
+    let reader =
+        LspReader::new(BufReader::new(read), "client").max_content_length(config.max_message_size);
+    let mut writer = LspWriter::new(write, "client").count_bytes(&metrics::BYTES_TO_CLIENTS);
+    writer.set_content_type(content_type.as_deref());
+    Ok((reader, writer))
+}
+
+/// Answer the `initialize` request with the reason the language server
+/// couldn't be started and close the connection
+async fn start_failed(
+    writer: LspWriter<OwnedWriteHalf>,
+    id: RequestId,
+    err: anyhow::Error,
+) -> Result<()> {
+    error!(?err, "cannot start language server");
+    let (reason, message) = match err.downcast_ref::<instance::StartError>() {
+        Some(start_error) => (start_error.reason, start_error.message.clone()),
+        None => (RejectReason::ServerFailed, format!("{err:#}")),
+    };
+    reject(writer, id, reason, message).await
+}
+
+/// Pick the instance for a client opening `folder`
+///
+/// Clients opened anywhere in a project share the instance, the project
+/// config can change its language server.
+async fn instance_key(
+    config: &Arc<Config>,
+    (server, args, env): (String, Vec<String>, BTreeMap<String, String>),
+    folder: String,
+    private: Option<usize>,
+) -> Result<InstanceKey> {
+    let (workspace_root, project) = {
+        let server = server.clone();
+        let config = config.clone();
+        task::spawn_blocking(move || {
+            let root = workspace::find_root(Path::new(&folder), &server, &config);
+            let project = ProjectConfig::load(&root);
+            (root, project)
+        })
+        .await
+        .unwrap()
+    };
+    let workspace_root = workspace_root
+        .into_os_string()
+        .into_string()
+        .ok()
+        .context("workspace root is not valid utf-8")?;
+    let mut project = project?;
+    // Anyone can commit a project config, opening a repository mustn't be
+    // enough to run whatever it says. `allowed_servers` only limits which
+    // server runs, its arguments and environment like `LD_PRELOAD` could
+    // still make it run anything.
+    if !config.trust_project_config {
+        if project.args.is_some() || !project.env.is_empty() {
+            warn!(
+                ?project,
+                "ignoring project config args and env, set `trust_project_config` to use them"
+            );
+            project.args = None;
+            project.env.clear();
+        }
+        if project.server.is_some() && config.allowed_servers.is_none() {
+            warn!(
+                ?project,
+                "ignoring project config server, set `trust_project_config` or `allowed_servers` to use it"
+            );
+            project.server = None;
+        }
+    }
+    if project.server.is_some() || project.args.is_some() || !project.env.is_empty() {
+        info!(?project, "using project config");
+    }
+
+    // Part of the key, instances with a different environment can't be
+    // shared.
+    let mut server_env = config.server_env.clone();
+    server_env.extend(env);
+    server_env.extend(project.env);
+    Ok(InstanceKey {
+        server: project.server.unwrap_or(server),
+        args: project.args.unwrap_or(args),
+        env: server_env,
+        workspace_root,
+        private,
+    })
+}
+
+/// Start the instance clients opening `folder` would get and keep it running
+/// until the first one connects
+///
+/// Like any new instance it's initialized with the params of its first
+/// client, here an imaginary one with no capabilities but those from
+/// `capabilities_override`.
+pub async fn warmup(
+    instance_map: Arc<Mutex<InstanceMap>>,
+    (server, args, env): (String, Vec<String>, BTreeMap<String, String>),
+    folder: String,
+) -> Result<Arc<Instance>> {
+    let config = instance_map.lock().await.config().clone();
+    let key = instance_key(&config, (server, args, env), folder, None).await?;
+    let uri = file_uri(&key.workspace_root);
+    let init_params = InitializeParams {
+        process_id: None,
+        client_info: Some(ClientInfo {
+            name: "ra-multiplex warmup".into(),
+            version: Some(env!("CARGO_PKG_VERSION").into()),
+        }),
+        locale: None,
+        root_path: None,
+        root_uri: Some(uri.clone()),
+        initialization_options: None,
+        capabilities: Some(capabilities::default_client()),
+        trace: None,
+        workspace_folders: vec![WorkspaceFolder {
+            uri,
+            name: workspace_name(&key.workspace_root).to_owned(),
+        }],
+    };
+    let instance = instance::get_or_spawn(instance_map, key, init_params).await?;
+    instance.warm_up().await;
+    Ok(instance)
+}
+
+/// Fields of [`ext::Request::Connect`] used by [`connect`]
+struct ConnectOptions {
+    server: String,
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+    cwd: Option<String>,
+    private: bool,
+    client_name: Option<String>,
+}
+
 /// Find or spawn a language server instance and connect the client to it
+///
+/// Returns once the client disconnects.
 async fn connect(
     client_id: usize,
     instance_map: Arc<Mutex<InstanceMap>>,
-    (server, args, env, cwd): (
-        String,
-        Vec<String>,
-        BTreeMap<String, String>,
-        Option<String>,
-    ),
+    options: ConnectOptions,
     req: Request,
     init_params: InitializeParams,
     mut reader: LspReader<BufReader<OwnedReadHalf>>,
     mut writer: LspWriter<OwnedWriteHalf>,
 ) -> Result<()> {
-    // Select the workspace root directory.
-    let workspace_root = select_workspace_root(&init_params, cwd.as_deref())
-        .context("could not get any workspace_root")?;
-
-    // Get an language server instance for this client.
-    let key = InstanceKey {
+    let ConnectOptions {
         server,
         args,
         env,
-        workspace_root,
+        cwd,
+        private,
+        client_name,
+    } = options;
+    if let Some(name) = &client_name {
+        tracing::Span::current().record("name", name.as_str());
+    }
+    // Select the workspace root directory.
+    let folder = select_workspace_root(&init_params, cwd.as_deref())
+        .context("could not get any workspace_root")?;
+    let folder = match task::spawn_blocking(move || canonical_folder(&folder))
+        .await
+        .unwrap()
+    {
+        Ok(folder) => folder,
+        Err(message) => {
+            warn!(message, "refusing client");
+            return reject(writer, req.id, RejectReason::InvalidWorkspace, message).await;
+        }
     };
-    let instance = instance::get_or_spawn(instance_map, key, init_params).await?;
+    let config = instance_map.lock().await.config().clone();
+    // Keyed by the connection nobody else can get the instance.
+    let private = private.then_some(client_id);
+    let key = instance_key(&config, (server, args, env), folder, private).await?;
+    let workspace_root = key.workspace_root.clone();
+    if config.no_multiplex {
+        record_workspace(&config, &workspace_root);
+        return passthrough(key, &config, req, init_params, reader, writer).await;
+    }
+    let handshake_timeout = handshake_timeout(&config);
+    let instance = match instance::get_or_spawn(instance_map, key, init_params.clone()).await {
+        Ok(instance) => instance,
+        Err(err) => return start_failed(writer, req.id, err).await,
+    };
+    let conflicts = instance.initialize_conflicts(&init_params);
+    if !conflicts.is_empty() {
+        let conflicts = conflicts.join(", ");
+        if config.strict_initialize {
+            warn!(
+                conflicts,
+                "refusing client, its `initialize` params conflict with the instance"
+            );
+            let message =
+                format!("initialize params conflict with the shared language server: {conflicts}");
+            return reject(writer, req.id, RejectReason::InitializeConflict, message).await;
+        }
+        warn!(
+            conflicts,
+            "client `initialize` params differ from the first client of the instance, the server won't know about them"
+        );
+    }
+    record_workspace(&config, &workspace_root);
 
     // Respond to client's `initialize` request using a response result from
     // the first time this server instance was initialized, it might not be
@@ -210,9 +947,9 @@ async fn connect(
     // Wait for the client to send `initialized` notification. We don't want to
     // forward it since the server only expects one and we already sent a fake
     // one during the server handshake.
-    match reader
-        .read_message()
+    match tokio::time::timeout(handshake_timeout, reader.read_message())
         .await
+        .context("timed out waiting for `initialized` notification")?
         .context("receive `initialized` notification")?
         .context("channel closed")?
     {
@@ -223,15 +960,64 @@ async fn connect(
     }
     info!("initialized client");
 
-    let (client, client_rx) = Client::new(client_id);
-    task::spawn(input_task(client_rx, writer).in_current_span());
-    instance.add_client(client.clone()).await;
+    let (client, client_rx) = Client::new(client_id, client_name, config.client_queue_warning);
+    task::spawn(
+        input_task(
+            client_rx,
+            client.queued.clone(),
+            writer,
+            client.disconnect.clone(),
+            Duration::from_secs(config.client_write_timeout.into()),
+        )
+        .in_current_span(),
+    );
+    instance
+        .add_client(client.clone(), init_params.trace.unwrap_or_default())
+        .await;
 
-    task::spawn(output_task(reader, client, instance).in_current_span());
+    let coalesce_changes = config
+        .coalesce_changes
+        .map(|millis| Duration::from_millis(millis.into()));
+    let rate_limiter = config
+        .rate_limit
+        .map(|rate| RateLimiter::new(rate, config.rate_limit_action));
+    output_task(
+        reader,
+        client,
+        instance.clone(),
+        &config,
+        coalesce_changes,
+        rate_limiter,
+    )
+    .await;
+    if private.is_some() {
+        info!("closing private instance");
+        instance.close();
+    }
 
     Ok(())
 }
 
+/// Characters percent-encoded in the path of a `file://` URI
+const URI_PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Format a file path as a LSP `URI`, the reverse of [`parse_root_uri`]
+pub fn file_uri(path: &str) -> String {
+    // Windows paths start with the drive letter.
+    let slash = if path.starts_with('/') { "" } else { "/" };
+    format!("file://{slash}{}", utf8_percent_encode(path, URI_PATH))
+}
+
 // Parse a file path as String out of a LSP `URI` type.
 fn parse_root_uri(root_uri: &str) -> Result<String> {
     let (scheme, _, mut path, _, _) = URI::try_from(root_uri)
@@ -275,6 +1061,21 @@ fn parsing_root_uris() {
     assert_eq!(p("file:///e:/").unwrap(), "e:/");
 }
 
+#[cfg(test)]
+#[test]
+fn file_uris_roundtrip() {
+    for path in [
+        "/home/user/proj",
+        "/with space/#1?",
+        "/100%",
+        "c:/dev/proj",
+        "/",
+    ] {
+        assert_eq!(parse_root_uri(&file_uri(path)).unwrap(), path);
+    }
+    assert_eq!(file_uri("/a b"), "file:///a%20b");
+}
+
 fn select_workspace_root<'a>(
     init_params: &'a InitializeParams,
     proxy_cwd: Option<&'a str>,
@@ -312,38 +1113,201 @@ fn select_workspace_root<'a>(
     bail!("could not determine a suitable workspace_root");
 }
 
+/// Resolve the folder the client opened
+///
+/// The language server runs in it, it has to be an existing directory.
+/// Different paths of the same directory, like one with a trailing slash, end
+/// up the same.
+fn canonical_folder(folder: &str) -> Result<String, String> {
+    let path = fs::canonicalize(folder)
+        .map_err(|err| format!("workspace folder {folder:?} is not accessible: {err}"))?;
+    if !path.is_dir() {
+        return Err(format!("workspace folder {folder:?} is not a directory"));
+    }
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| format!("workspace folder {path:?} is not valid utf-8"))
+}
+
+/// Record the workspace on the client span, logged by everything the client
+/// does from now on
+fn record_workspace(config: &Config, workspace_root: &str) {
+    let workspace = if config.log_workspace_name {
+        workspace_name(workspace_root)
+    } else {
+        workspace_root
+    };
+    tracing::Span::current().record("workspace", workspace);
+}
+
+/// Name of the workspace root directory, the whole root if it has none
+fn workspace_name(workspace_root: &str) -> &str {
+    Path::new(workspace_root)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(workspace_root)
+}
+
+#[cfg(test)]
+#[test]
+fn workspace_names() {
+    assert_eq!(workspace_name("/home/user/my-crate"), "my-crate");
+    assert_eq!(workspace_name("/home/user/my-crate/"), "my-crate");
+    assert_eq!(workspace_name("/"), "/");
+}
+
+/// Spawn a language server only for this client and relay all messages
+/// unchanged between them
+///
+/// Used with `server --no-multiplex` to tell whether a problem is caused by
+/// the multiplexing, the server is killed once the client disconnects.
+async fn passthrough(
+    key: InstanceKey,
+    config: &Config,
+    mut req: Request,
+    init_params: InitializeParams,
+    reader: LspReader<BufReader<OwnedReadHalf>>,
+    writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let child = instance::ensure_allowed(&key.server, config)
+        .and_then(|()| instance::spawn_child(&key, config));
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => return start_failed(writer, req.id, err).await,
+    };
+    let mut server_input = child.stdin.take().unwrap();
+    let mut server_output = child.stdout.take().unwrap();
+
+    // Forward the `initialize` request without `lspMux`, the server answers it
+    // directly.
+    req.params = serde_json::to_value(init_params).unwrap();
+    let mut init_writer = LspWriter::new(&mut server_input, "server");
+    init_writer.set_content_type(reader.content_type());
+    init_writer
+        .write_message(&req.into())
+        .await
+        .context("forward `initialize` request")?;
+    info!("relaying messages without multiplexing");
+
+    let mut client_output = reader.into_inner();
+    let mut client_input = writer.into_inner();
+    select! {
+        res = tokio::io::copy(&mut client_output, &mut server_input) => {
+            res.context("relay client messages")?;
+            info!("client disconnected");
+        }
+        res = tokio::io::copy(&mut server_output, &mut client_input) => {
+            res.context("relay server messages")?;
+            info!("server exited");
+            // Ends the zstd frame of a compressed connection.
+            _ = client_input.shutdown().await;
+        }
+    }
+    Ok(())
+}
+
 /// Receive messages from channel and write them to the client input socket
-async fn input_task(mut rx: mpsc::Receiver<Message>, mut writer: LspWriter<OwnedWriteHalf>) {
+///
+/// A client which doesn't accept a message for `write_timeout` isn't keeping up
+/// with reading its messages and is disconnected, its queue would otherwise
+/// grow without bounds.
+async fn input_task(
+    mut rx: mpsc::UnboundedReceiver<Message>,
+    queued: Arc<AtomicUsize>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+    disconnect: Arc<Notify>,
+    write_timeout: Duration,
+) {
     // The other end of this channel is held by the `output_task` _and_ in the
     // `Instance` itself, this task depends on the `output_task` to detect a
     // client disconnect and call `Instance::cleanup_client`, otherwise we're
     // going to hang forever here.
     while let Some(message) = rx.recv().await {
-        if let Err(err) = writer.write_message(&message).await {
+        queued.fetch_sub(1, Ordering::Relaxed);
+        let Ok(res) = tokio::time::timeout(write_timeout, writer.write_message(&message)).await
+        else {
+            warn!(queued = rx.len(), "client is not keeping up, disconnecting");
+            disconnect.notify_one();
+            break;
+        };
+        if let Err(err) = res {
             match err.kind() {
                 // ignore benign errors, treat as socket close
                 ErrorKind::BrokenPipe => {}
                 // report fatal errors
                 _ => error!(?err, "error writing client input: {err}"),
             }
+            // The connection is only half-open now, make sure `output_task`
+            // doesn't keep the client attached to the instance.
+            disconnect.notify_one();
             break; // break on any error
         }
     }
+    // Ends the zstd frame of a compressed connection.
+    _ = tokio::time::timeout(write_timeout, writer.into_inner().shutdown()).await;
     debug!("client input closed");
     info!("client disconnected");
 }
 
 /// Read messages from client output socket and send them to the server channel
 async fn output_task(
-    mut reader: LspReader<BufReader<OwnedReadHalf>>,
+    reader: LspReader<BufReader<OwnedReadHalf>>,
     client: Client,
     instance: Arc<Instance>,
+    config: &Config,
+    coalesce_changes: Option<Duration>,
+    mut rate_limiter: Option<RateLimiter>,
 ) {
+    // Full document change waiting to be replaced by a newer change of the
+    // same document until the deadline.
+    let mut held_change: Option<(Notification, Instant)> = None;
+    // Messages waiting for room in the language server queue, in order.
+    let mut paused = VecDeque::new();
+    let mut closed = false;
+    let mut read = Box::pin(read_next(reader));
     loop {
-        let message = match reader.read_message().await {
+        let flush_at = held_change.as_ref().map(|(_, deadline)| *deadline);
+        let message = select! {
+            (reader, message) = &mut read, if !closed && paused.len() < PAUSED_MESSAGES => {
+                read = Box::pin(read_next(reader));
+                // Responses and cancellations help the server catch up, they
+                // skip the messages waiting for it to do so.
+                let skips = matches!(&message, Ok(Some(message)) if helps_server_drain(message));
+                if !skips && (!paused.is_empty() || instance.server_queue_full()) {
+                    if paused.is_empty() {
+                        debug!("language server queue is full, pausing client");
+                    }
+                    closed = matches!(message, Ok(None));
+                    paused.push_back(message);
+                    continue;
+                }
+                message
+            }
+            () = instance.server_queue_room(), if !paused.is_empty() => {
+                if paused.len() == 1 {
+                    debug!("resuming client");
+                }
+                paused.pop_front().unwrap()
+            }
+            () = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                let (notif, _) = held_change.take().unwrap();
+                if instance.send_message(notif.into()).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            () = client.disconnect.notified() => {
+                debug!("disconnecting client");
+                break;
+            }
+        };
+        let message = match message {
             Ok(Some(message)) => message,
             Ok(None) => {
                 debug!("client output closed");
+                // The client may only have closed its writing half and still
+                // read the responses.
+                wait_for_responses(&instance, &client).await;
                 break;
             }
             Err(err) => {
@@ -353,6 +1317,62 @@ async fn output_task(
         };
         instance.keep_alive();
 
+        // A newer full change replaces the held one, anything else has to
+        // wait until the held change is sent so nothing gets reordered.
+        let message = match (held_change.take(), message) {
+            (Some((held, deadline)), Message::Notification(notif))
+                if full_change_uri(&notif)
+                    .is_some_and(|uri| full_change_uri(&held) == Some(uri)) =>
+            {
+                if let Err(err) = instance.change_file(notif.params.clone()).await {
+                    warn!(?err, "error tracking file change");
+                }
+                held_change = Some((notif, deadline));
+                continue;
+            }
+            (Some((held, _)), message) => {
+                if instance.send_message(held.into()).await.is_err() {
+                    break;
+                }
+                message
+            }
+            (None, message) => message,
+        };
+
+        // The multiplexer answers these itself, they never reach the server.
+        match &message {
+            Message::Request(req)
+                if req.method != "shutdown" && !config.method_allowed(&req.method) =>
+            {
+                info!(
+                    method = req.method,
+                    "refusing request, its method isn't allowed"
+                );
+                let res = ResponseError {
+                    jsonrpc: Version,
+                    error: jsonrpc::Error {
+                        // JSON-RPC `MethodNotFound`
+                        code: -32601,
+                        message: format!("method {:?} is not allowed", req.method),
+                        data: None,
+                    },
+                    id: Some(req.id.clone()),
+                };
+                client.send_message_nowait(res.into());
+                continue;
+            }
+            Message::Notification(notif)
+                if notif.method != "exit" && !config.method_allowed(&notif.method) =>
+            {
+                info!(
+                    method = notif.method,
+                    "dropping notification, its method isn't allowed"
+                );
+                continue;
+            }
+            _ => {}
+        }
+
         match message {
             Message::Request(req) if req.method == "shutdown" => {
                 // Client requested the server to shut down but other clients might still be connected.
@@ -367,9 +1387,44 @@ async fn output_task(
                 break;
             }
 
-            Message::Request(mut req) => {
-                req.id = req.id.tag(Tag::ClientId(client.id));
-                if instance.send_message(req.into()).await.is_err() {
+            Message::Notification(notif) if notif.method == "exit" => {
+                // Usually we've already disconnected after `shutdown` but
+                // clients can send `exit` on its own as well, the server must
+                // keep running for other clients. If it has no other clients
+                // the garbage collector shuts it down after `instance_timeout`.
+                info!("client sent exit notification, closing connection");
+                break;
+            }
+
+            Message::Request(req) => {
+                if let Some(rate_limiter) = rate_limiter
+                    .as_mut()
+                    .filter(|_| config.method_rate_limited(&req.method))
+                {
+                    match rate_limiter.acquire(Instant::now()) {
+                        None => {}
+                        Some(wait) if rate_limiter.action == RateLimitAction::Delay => {
+                            trace!(?wait, "delaying request over the rate limit");
+                            tokio::time::sleep(wait).await;
+                        }
+                        Some(_) => {
+                            debug!(?req, "dropping request over the rate limit");
+                            let res = ResponseError {
+                                jsonrpc: Version,
+                                error: jsonrpc::Error {
+                                    // LSP `ServerCancelled`
+                                    code: -32802,
+                                    message: "rate limit exceeded".into(),
+                                    data: None,
+                                },
+                                id: Some(req.id),
+                            };
+                            client.send_message_nowait(res.into());
+                            continue;
+                        }
+                    }
+                }
+                if instance.send_request(client.id, req).await.is_err() {
                     break;
                 }
             }
@@ -377,7 +1432,11 @@ async fn output_task(
             Message::ResponseSuccess(mut res) => match res.id.untag() {
                 (Some(Tag::Forward), id) => {
                     res.id = id;
-                    if instance.send_message(res.into()).await.is_err() {
+                    if instance
+                        .respond_to_server(client.id, res.into())
+                        .await
+                        .is_err()
+                    {
                         break;
                     }
                 }
@@ -389,8 +1448,19 @@ async fn output_task(
                 }
             },
 
-            Message::ResponseError(res) => {
+            Message::ResponseError(mut res) => {
                 warn!(?res, "client responded with error");
+                // Errors for dropped or unexpected responses are only logged.
+                if let Some((Some(Tag::Forward), id)) = res.id.take().map(|id| id.untag()) {
+                    res.id = Some(id);
+                    if instance
+                        .respond_to_server(client.id, res.into())
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
             }
 
             Message::Notification(notif) if notif.method == "textDocument/didOpen" => {
@@ -399,6 +1469,60 @@ async fn output_task(
                 }
             }
 
+            Message::Notification(notif) if notif.method == "textDocument/didChange" => {
+                if let Err(err) = instance.change_file(notif.params.clone()).await {
+                    warn!(?err, "error tracking file change");
+                }
+                if let Some(window) = coalesce_changes {
+                    if full_change_uri(&notif).is_some() {
+                        held_change = Some((notif, Instant::now() + window));
+                        continue;
+                    }
+                }
+                if instance.send_message(notif.into()).await.is_err() {
+                    break;
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "workspace/didChangeConfiguration" => {
+                if instance
+                    .change_configuration(client.id, notif.params)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "$/setTrace" => {
+                if instance.set_trace(client.id, notif.params).await.is_err() {
+                    break;
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "$/cancelRequest" => {
+                if instance.cancel_request(client.id, notif).await.is_err() {
+                    break;
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "window/workDoneProgress/cancel" => {
+                if instance.cancel_progress(client.id, notif).await.is_err() {
+                    break;
+                }
+            }
+
+            Message::Notification(notif)
+                if notif.method == "workspace/didChangeWorkspaceFolders" =>
+            {
+                if let Err(err) = instance
+                    .change_workspace_folders(client.id, notif.params)
+                    .await
+                {
+                    warn!(?err, "error changing workspace folders");
+                }
+            }
+
             Message::Notification(notif) if notif.method == "textDocument/didClose" => {
                 if let Err(err) = instance.close_file(client.id, notif.params).await {
                     warn!(?err, "error closing file");
@@ -413,7 +1537,139 @@ async fn output_task(
         }
     }
 
+    if let Some((notif, _)) = held_change {
+        // Other clients may still have the document open.
+        let _ = instance.send_message(notif.into()).await;
+    }
     if let Err(err) = instance.cleanup_client(client).await {
         warn!(?err, "error cleaning up after a client");
     }
 }
+
+/// Wait until the server answered all pending requests of the client, at most
+/// for [`PENDING_RESPONSES_TIMEOUT`]
+async fn wait_for_responses(instance: &Instance, client: &Client) {
+    let answered = async {
+        while instance.has_pending_requests(client.id).await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    select! {
+        answered = tokio::time::timeout(PENDING_RESPONSES_TIMEOUT, answered) => {
+            if answered.is_err() {
+                debug!("pending requests weren't answered in time");
+            }
+        }
+        () = client.disconnect.notified() => {}
+    }
+}
+
+/// Token bucket limiting the requests of a client
+struct RateLimiter {
+    action: RateLimitAction,
+    /// Tokens added per second, also the bucket size
+    rate: f64,
+    /// Negative when delayed requests already used up future tokens
+    tokens: f64,
+    updated: Instant,
+    /// Whether the client was over the limit the last time, only the first
+    /// request over the limit is logged as a warning
+    limited: bool,
+}
+
+impl RateLimiter {
+    fn new(rate: u32, action: RateLimitAction) -> RateLimiter {
+        let rate = f64::from(rate.max(1));
+        RateLimiter {
+            action,
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+            limited: false,
+        }
+    }
+
+    /// Take a token for a request at `now`
+    ///
+    /// Returns how long the request has to wait for its token if there's none
+    /// left. With [`RateLimitAction::Delay`] the token is reserved, the
+    /// dropped requests don't take it.
+    fn acquire(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.limited = false;
+            return None;
+        }
+        if !self.limited {
+            warn!(rate = self.rate, action = ?self.action, "client exceeds the rate limit");
+            self.limited = true;
+        }
+        let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+        if self.action == RateLimitAction::Delay {
+            self.tokens -= 1.0;
+        }
+        Some(wait)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn rate_limiter_allows_bursts_then_the_rate() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(2, RateLimitAction::Drop);
+    limiter.updated = start;
+    assert_eq!(limiter.acquire(start), None);
+    assert_eq!(limiter.acquire(start), None);
+    assert_eq!(limiter.acquire(start), Some(Duration::from_millis(500)));
+    // Dropped requests don't use up tokens.
+    assert_eq!(limiter.acquire(start), Some(Duration::from_millis(500)));
+    assert_eq!(limiter.acquire(start + Duration::from_millis(500)), None);
+
+    let mut limiter = RateLimiter::new(2, RateLimitAction::Delay);
+    limiter.updated = start;
+    limiter.acquire(start);
+    limiter.acquire(start);
+    assert_eq!(limiter.acquire(start), Some(Duration::from_millis(500)));
+    // The delayed request already took the next token.
+    assert_eq!(limiter.acquire(start), Some(Duration::from_secs(1)));
+}
+
+/// Read the next client message
+///
+/// The reader is moved into the future and back out so a read can stay pending
+/// while other `select!` branches complete, dropping it halfway through a
+/// message would lose the rest of the stream.
+async fn read_next(
+    mut reader: LspReader<BufReader<OwnedReadHalf>>,
+) -> (LspReader<BufReader<OwnedReadHalf>>, Result<Option<Message>>) {
+    let message = reader.read_message().await;
+    (reader, message)
+}
+
+/// Can a client message go to the server while the server queue is full
+fn helps_server_drain(message: &Message) -> bool {
+    match message {
+        Message::ResponseSuccess(_) | Message::ResponseError(_) => true,
+        Message::Notification(notif) => notif.method == "$/cancelRequest",
+        Message::Request(_) => false,
+    }
+}
+
+/// Document URI of a `textDocument/didChange` notification replacing the whole
+/// document content
+fn full_change_uri(notif: &Notification) -> Option<&str> {
+    if notif.method != "textDocument/didChange" {
+        return None;
+    }
+    let changes = notif.params["contentChanges"].as_array()?;
+    if !changes
+        .iter()
+        .any(|change| change.get("range").is_none_or(Value::is_null))
+    {
+        return None;
+    }
+    notif.params["textDocument"]["uri"].as_str()
+}
@@ -1,14 +1,21 @@
-use std::collections::BTreeSet;
-use std::fs;
-use std::net::{IpAddr, Ipv4Addr};
-#[cfg(target_family = "unix")]
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fmt, fs};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use directories::ProjectDirs;
 use serde::de::{Error, Unexpected};
 use serde::{Deserialize, Deserializer};
 use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::instance::SERVER_QUEUE_HEADROOM;
+use crate::json_log::JsonLayer;
+use crate::lsp::ext::Compression;
 
 mod default {
     use super::*;
@@ -23,22 +30,218 @@ mod default {
         10
     }
 
+    pub fn listen_ipv6_only() -> Option<bool> {
+        None
+    }
+
+    pub fn listen_backlog() -> u32 {
+        1024
+    }
+
+    pub fn listen_reuse_address() -> bool {
+        true
+    }
+
+    pub fn listen_remove_stale_socket() -> bool {
+        true
+    }
+
+    pub fn max_clients() -> usize {
+        128
+    }
+
+    pub fn handshake_timeout() -> u32 {
+        // 5 seconds
+        5
+    }
+
+    pub fn client_write_timeout() -> u32 {
+        // 30 seconds
+        30
+    }
+
+    pub fn max_message_size() -> usize {
+        // 64 MiB
+        crate::lsp::transport::MAX_CONTENT_LENGTH
+    }
+
     pub fn listen() -> Address {
         // localhost & some random unprivileged port
         Address::Tcp(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 27_631)
     }
 
+    pub fn coalesce_changes() -> Option<u32> {
+        // disabled
+        None
+    }
+
+    pub fn rate_limit() -> Option<u32> {
+        // disabled
+        None
+    }
+
+    pub fn rate_limit_action() -> RateLimitAction {
+        RateLimitAction::Delay
+    }
+
+    pub fn rate_limited_methods() -> Option<BTreeSet<String>> {
+        // every request
+        None
+    }
+
+    pub fn server_queue_high_water() -> usize {
+        192
+    }
+
+    pub fn server_queue_low_water() -> usize {
+        64
+    }
+
     pub fn connect() -> Address {
         listen()
     }
 
+    pub fn connect_retry() -> u32 {
+        // disabled
+        0
+    }
+
+    pub fn spawn_server() -> bool {
+        false
+    }
+
+    pub fn compression() -> Option<Compression> {
+        None
+    }
+
     pub fn log_filters() -> String {
         "info".to_owned()
     }
 
+    pub fn log_workspace_name() -> bool {
+        false
+    }
+
+    pub fn log_format() -> LogFormat {
+        LogFormat::Pretty
+    }
+
     pub fn pass_environment() -> BTreeSet<String> {
         BTreeSet::new()
     }
+
+    pub fn server() -> String {
+        "rust-analyzer".into()
+    }
+
+    pub fn server_args() -> Vec<String> {
+        Vec::new()
+    }
+
+    pub fn server_env() -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+
+    pub fn server_clear_env() -> bool {
+        false
+    }
+
+    pub fn allowed_servers() -> Option<BTreeSet<String>> {
+        None
+    }
+
+    pub fn allowed_methods() -> Option<BTreeSet<String>> {
+        None
+    }
+
+    pub fn denied_methods() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn instance_root() -> InstanceRoot {
+        InstanceRoot::Workspace
+    }
+
+    pub fn root_markers() -> Option<Vec<String>> {
+        None
+    }
+
+    pub fn trust_project_config() -> bool {
+        false
+    }
+
+    pub fn watchdog_interval() -> Option<u32> {
+        None
+    }
+
+    pub fn watchdog_threshold() -> u32 {
+        3
+    }
+
+    pub fn rewrite_request_ids() -> bool {
+        true
+    }
+
+    pub fn client_queue_warning() -> Option<usize> {
+        None
+    }
+
+    pub fn max_in_flight_requests() -> Option<usize> {
+        None
+    }
+
+    pub fn prewarm_instances() -> bool {
+        false
+    }
+
+    pub fn warmup() -> Vec<String> {
+        Vec::new()
+    }
+
+    pub fn strict_initialize() -> bool {
+        false
+    }
+
+    pub fn capabilities_override() -> Option<serde_json::Value> {
+        None
+    }
+
+    pub fn metrics_listen() -> Option<Address> {
+        None
+    }
+
+    pub fn auth_token_file() -> Option<PathBuf> {
+        None
+    }
+
+    pub fn null_id_responses() -> NullIdResponses {
+        NullIdResponses::Broadcast
+    }
+
+    pub fn server_requests() -> ServerRequests {
+        ServerRequests::First
+    }
+
+    pub fn server_request_routes() -> BTreeMap<String, ServerRequests> {
+        // Every client would apply the edit.
+        BTreeMap::from([("workspace/applyEdit".into(), ServerRequests::Initiator)])
+    }
+
+    pub fn telemetry() -> Telemetry {
+        Telemetry::Drop
+    }
+
+    pub fn telemetry_file() -> Option<PathBuf> {
+        None
+    }
+
+    pub fn restart_message() -> RestartMessage {
+        RestartMessage::Info
+    }
+
+    pub fn diagnostics_severity() -> Option<DiagnosticSeverity> {
+        None
+    }
 }
 
 mod de {
@@ -69,6 +272,40 @@ mod de {
         }
     }
 
+    /// parse a u32, 0, false or "never" in a project config, `Some(None)`
+    /// means never
+    pub fn project_instance_timeout<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<Option<u32>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOf {
+            Bool(bool),
+            U32(u32),
+            Str(String),
+        }
+
+        match OneOf::deserialize(deserializer) {
+            Ok(OneOf::U32(0) | OneOf::Bool(false)) => Ok(Some(None)),
+            Ok(OneOf::U32(value)) => Ok(Some(Some(value))),
+            Ok(OneOf::Str(value)) if value == "never" => Ok(Some(None)),
+            Ok(OneOf::Bool(true)) => Err(Error::invalid_value(
+                Unexpected::Bool(true),
+                &"a non-negative integer, false or \"never\"",
+            )),
+            Ok(OneOf::Str(value)) => Err(Error::invalid_value(
+                Unexpected::Str(&value),
+                &"a non-negative integer, false or \"never\"",
+            )),
+            Err(_) => Err(Error::custom(
+                "invalid type: expected a non-negative integer, false or \"never\"",
+            )),
+        }
+    }
+
     /// make sure the value is greater than 0 to giver users feedback on invalid configuration
     pub fn gc_interval<'de, D>(deserializer: D) -> Result<u32, D::Error>
     where
@@ -84,7 +321,7 @@ mod de {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Address {
     Tcp(IpAddr, u16),
@@ -92,7 +329,120 @@ pub enum Address {
     Unix(PathBuf),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(ip_addr, port) => write!(f, "{}", SocketAddr::new(*ip_addr, *port)),
+            #[cfg(target_family = "unix")]
+            Address::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// How are log lines formatted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable text
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("invalid log format {s:?}, expected \"pretty\" or \"json\""),
+        }
+    }
+}
+
+/// What to do with server error responses which have a `null` request ID
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NullIdResponses {
+    /// Send the response to all clients of the instance
+    Broadcast,
+    /// Don't send the response to any client
+    Drop,
+}
+
+/// What to do with client requests over the `rate_limit`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    /// Hold the request and everything after it back until it's allowed
+    Delay,
+    /// Answer the request with an error without sending it to the server
+    Drop,
+}
+
+/// Which directory containing the folder a client opened is the root of its
+/// instance
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceRoot {
+    /// The whole project, the cargo workspace for rust-analyzer
+    Workspace,
+    /// The closest directory with a `Cargo.toml` or another root marker
+    Nearest,
+    /// The folder itself
+    Folder,
+}
+
+/// What to do with `telemetry/event` notifications from the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Telemetry {
+    /// Discard the event
+    Drop,
+    /// Log the event at debug level
+    Log,
+    /// Send the event to all clients of the instance
+    Forward,
+}
+
+/// Whether clients are told when their language server was restarted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RestartMessage {
+    /// Send an informational `window/showMessage` notification
+    Info,
+    /// Only log the restart
+    Off,
+}
+
+/// Least severe diagnostics forwarded to the clients
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticSeverity {
+    Error = 1,
+    Warning = 2,
+    Information = 3,
+    Hint = 4,
+}
+
+/// Which clients answer requests the server sends to the client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerRequests {
+    /// Send the request to the client which connected first
+    First,
+    /// Send the request to all clients, the first response is used
+    Broadcast,
+    /// Send the request to the client whose pending request is making the
+    /// server send it, like `workspace/executeCommand` for a
+    /// `workspace/applyEdit`, or the first client if there's none
+    Initiator,
+    /// Answer the request with an error without sending it to any client
+    Drop,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(default = "default::instance_timeout")]
@@ -106,14 +456,160 @@ pub struct Config {
     #[serde(default = "default::listen")]
     pub listen: Address,
 
+    #[serde(default = "default::listen_ipv6_only")]
+    pub listen_ipv6_only: Option<bool>,
+
+    #[serde(default = "default::listen_backlog")]
+    pub listen_backlog: u32,
+
+    #[serde(default = "default::listen_reuse_address")]
+    pub listen_reuse_address: bool,
+
+    #[serde(default = "default::listen_remove_stale_socket")]
+    pub listen_remove_stale_socket: bool,
+
+    #[serde(default = "default::max_clients")]
+    pub max_clients: usize,
+
+    #[serde(default = "default::handshake_timeout")]
+    pub handshake_timeout: u32,
+
+    #[serde(default = "default::client_write_timeout")]
+    pub client_write_timeout: u32,
+
+    #[serde(default = "default::max_message_size")]
+    pub max_message_size: usize,
+
+    #[serde(default = "default::coalesce_changes")]
+    pub coalesce_changes: Option<u32>,
+
+    #[serde(default = "default::rate_limit")]
+    pub rate_limit: Option<u32>,
+
+    #[serde(default = "default::rate_limit_action")]
+    pub rate_limit_action: RateLimitAction,
+
+    #[serde(default = "default::rate_limited_methods")]
+    pub rate_limited_methods: Option<BTreeSet<String>>,
+
+    #[serde(default = "default::server_queue_high_water")]
+    pub server_queue_high_water: usize,
+
+    #[serde(default = "default::server_queue_low_water")]
+    pub server_queue_low_water: usize,
+
     #[serde(default = "default::connect")]
     pub connect: Address,
 
+    #[serde(default = "default::connect_retry")]
+    pub connect_retry: u32,
+
+    #[serde(default = "default::spawn_server")]
+    pub spawn_server: bool,
+
+    #[serde(default = "default::compression")]
+    pub compression: Option<Compression>,
+
     #[serde(default = "default::log_filters")]
     pub log_filters: String,
 
+    #[serde(default = "default::log_format")]
+    pub log_format: LogFormat,
+
+    #[serde(default = "default::log_workspace_name")]
+    pub log_workspace_name: bool,
+
+    #[serde(default = "default::metrics_listen")]
+    pub metrics_listen: Option<Address>,
+
+    #[serde(default = "default::auth_token_file")]
+    pub auth_token_file: Option<PathBuf>,
+
     #[serde(default = "default::pass_environment")]
     pub pass_environment: BTreeSet<String>,
+
+    #[serde(default = "default::null_id_responses")]
+    pub null_id_responses: NullIdResponses,
+
+    #[serde(default = "default::server_requests")]
+    pub server_requests: ServerRequests,
+
+    #[serde(default = "default::telemetry")]
+    pub telemetry: Telemetry,
+
+    #[serde(default = "default::telemetry_file")]
+    pub telemetry_file: Option<PathBuf>,
+
+    #[serde(default = "default::restart_message")]
+    pub restart_message: RestartMessage,
+
+    #[serde(default = "default::diagnostics_severity")]
+    pub diagnostics_severity: Option<DiagnosticSeverity>,
+
+    #[serde(default = "default::server")]
+    pub server: String,
+
+    #[serde(default = "default::server_args")]
+    pub server_args: Vec<String>,
+
+    #[serde(default = "default::server_clear_env")]
+    pub server_clear_env: bool,
+
+    #[serde(default = "default::allowed_servers")]
+    pub allowed_servers: Option<BTreeSet<String>>,
+
+    #[serde(default = "default::allowed_methods")]
+    pub allowed_methods: Option<BTreeSet<String>>,
+
+    #[serde(default = "default::denied_methods")]
+    pub denied_methods: BTreeSet<String>,
+
+    #[serde(default = "default::instance_root")]
+    pub instance_root: InstanceRoot,
+
+    #[serde(default = "default::root_markers")]
+    pub root_markers: Option<Vec<String>>,
+
+    #[serde(default = "default::trust_project_config")]
+    pub trust_project_config: bool,
+
+    #[serde(default = "default::rewrite_request_ids")]
+    pub rewrite_request_ids: bool,
+
+    #[serde(default = "default::client_queue_warning")]
+    pub client_queue_warning: Option<usize>,
+
+    #[serde(default = "default::max_in_flight_requests")]
+    pub max_in_flight_requests: Option<usize>,
+
+    #[serde(default = "default::watchdog_interval")]
+    pub watchdog_interval: Option<u32>,
+
+    #[serde(default = "default::watchdog_threshold")]
+    pub watchdog_threshold: u32,
+
+    #[serde(default = "default::prewarm_instances")]
+    pub prewarm_instances: bool,
+
+    #[serde(default = "default::warmup")]
+    pub warmup: Vec<String>,
+
+    #[serde(default = "default::strict_initialize")]
+    pub strict_initialize: bool,
+
+    #[serde(default = "default::capabilities_override")]
+    pub capabilities_override: Option<serde_json::Value>,
+
+    // Tables have to come after plain values in TOML.
+    #[serde(default = "default::server_env")]
+    pub server_env: BTreeMap<String, String>,
+
+    #[serde(default = "default::server_request_routes")]
+    pub server_request_routes: BTreeMap<String, ServerRequests>,
+
+    /// Give every client its own language server, set by `server --no-multiplex`
+    #[serde(skip)]
+    pub no_multiplex: bool,
 }
 
 #[cfg(test)]
@@ -137,9 +633,57 @@ impl Default for Config {
             instance_timeout: default::instance_timeout(),
             gc_interval: default::gc_interval(),
             listen: default::listen(),
+            listen_ipv6_only: default::listen_ipv6_only(),
+            listen_backlog: default::listen_backlog(),
+            listen_reuse_address: default::listen_reuse_address(),
+            listen_remove_stale_socket: default::listen_remove_stale_socket(),
+            max_clients: default::max_clients(),
+            handshake_timeout: default::handshake_timeout(),
+            client_write_timeout: default::client_write_timeout(),
+            max_message_size: default::max_message_size(),
+            coalesce_changes: default::coalesce_changes(),
+            rate_limit: default::rate_limit(),
+            rate_limit_action: default::rate_limit_action(),
+            rate_limited_methods: default::rate_limited_methods(),
+            server_queue_high_water: default::server_queue_high_water(),
+            server_queue_low_water: default::server_queue_low_water(),
             connect: default::connect(),
+            connect_retry: default::connect_retry(),
+            spawn_server: default::spawn_server(),
+            compression: default::compression(),
             log_filters: default::log_filters(),
+            log_format: default::log_format(),
+            log_workspace_name: default::log_workspace_name(),
+            metrics_listen: default::metrics_listen(),
+            auth_token_file: default::auth_token_file(),
             pass_environment: default::pass_environment(),
+            null_id_responses: default::null_id_responses(),
+            server_requests: default::server_requests(),
+            server_env: default::server_env(),
+            server_request_routes: default::server_request_routes(),
+            telemetry: default::telemetry(),
+            telemetry_file: default::telemetry_file(),
+            restart_message: default::restart_message(),
+            diagnostics_severity: default::diagnostics_severity(),
+            server: default::server(),
+            server_args: default::server_args(),
+            server_clear_env: default::server_clear_env(),
+            allowed_servers: default::allowed_servers(),
+            allowed_methods: default::allowed_methods(),
+            denied_methods: default::denied_methods(),
+            instance_root: default::instance_root(),
+            root_markers: default::root_markers(),
+            trust_project_config: default::trust_project_config(),
+            rewrite_request_ids: default::rewrite_request_ids(),
+            client_queue_warning: default::client_queue_warning(),
+            max_in_flight_requests: default::max_in_flight_requests(),
+            watchdog_interval: default::watchdog_interval(),
+            watchdog_threshold: default::watchdog_threshold(),
+            prewarm_instances: default::prewarm_instances(),
+            warmup: default::warmup(),
+            strict_initialize: default::strict_initialize(),
+            capabilities_override: default::capabilities_override(),
+            no_multiplex: false,
         }
     }
 }
@@ -155,7 +699,103 @@ impl Config {
         let path = config_path.display();
         let config_data =
             fs::read(&config_path).with_context(|| format!("cannot read config file `{path}`"))?;
-        toml::from_slice(&config_data).with_context(|| format!("cannot parse config file `{path}`"))
+        let config: Config = toml::from_slice(&config_data)
+            .with_context(|| format!("cannot parse config file `{path}`"))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid config file `{path}`"))?;
+        Ok(config)
+    }
+
+    /// Check the options which can't be used together
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.server_queue_low_water < self.server_queue_high_water,
+            "`server_queue_low_water` must be less than `server_queue_high_water`",
+        );
+        let max_high_water = Semaphore::MAX_PERMITS - SERVER_QUEUE_HEADROOM;
+        ensure!(
+            self.server_queue_high_water <= max_high_water,
+            "`server_queue_high_water` can be at most {max_high_water}",
+        );
+        Ok(())
+    }
+
+    /// Override the port of TCP `listen` and `connect` addresses
+    ///
+    /// Unix socket addresses don't have a port and are left unchanged.
+    pub fn set_port(&mut self, port: u16) {
+        for address in [&mut self.listen, &mut self.connect] {
+            match address {
+                Address::Tcp(_, old_port) => *old_port = port,
+                #[cfg(target_family = "unix")]
+                Address::Unix(path) => warn!(?path, "ignoring port for unix socket address"),
+            }
+        }
+    }
+
+    /// Language server and arguments for clients which don't pick their own
+    /// `server`
+    ///
+    /// Configured arguments only make sense for the configured server, they're
+    /// not used if the server was overridden.
+    pub fn server_command(
+        &self,
+        server: Option<String>,
+        args: Vec<String>,
+    ) -> (String, Vec<String>) {
+        match server {
+            Some(server) => (server, args),
+            None => {
+                let mut server_args = self.server_args.clone();
+                server_args.extend(args);
+                (self.server.clone(), server_args)
+            }
+        }
+    }
+
+    /// May clients send requests and notifications with `method`
+    pub fn method_allowed(&self, method: &str) -> bool {
+        let allowed = self
+            .allowed_methods
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(method));
+        allowed && !self.denied_methods.contains(method)
+    }
+
+    /// Do requests with `method` count against the `rate_limit`
+    pub fn method_rate_limited(&self, method: &str) -> bool {
+        self.rate_limited_methods
+            .as_ref()
+            .is_none_or(|limited| limited.contains(method))
+    }
+
+    /// Values of the `pass_environment` variables set in our environment
+    pub fn passed_environment(&self) -> BTreeMap<String, String> {
+        self.pass_environment
+            .iter()
+            .filter_map(|key| Some((key.clone(), env::var(key).ok()?)))
+            .collect()
+    }
+
+    /// Read the shared secret clients must present to the server
+    ///
+    /// The `RA_MUX_AUTH_TOKEN` environment variable takes precedence over
+    /// the `auth_token_file` option. Surrounding whitespace is ignored.
+    pub fn auth_token(&self) -> Result<Option<String>> {
+        let token = match env::var("RA_MUX_AUTH_TOKEN") {
+            Ok(token) => token,
+            Err(_) => match &self.auth_token_file {
+                Some(path) => fs::read_to_string(path)
+                    .with_context(|| format!("cannot read auth token file {path:?}"))?,
+                None => return Ok(None),
+            },
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            bail!("auth token is empty");
+        }
+        Ok(Some(token.to_owned()))
     }
 
     /// Configure tracing-subscriber with env filter set to `log_filters` (if
@@ -166,10 +806,16 @@ impl Config {
         use tracing_subscriber::prelude::*;
         use tracing_subscriber::EnvFilter;
 
-        let format = tracing_subscriber::fmt::layer()
-            .without_time()
-            .with_target(false)
-            .with_writer(std::io::stderr);
+        let (pretty, json) = match self.log_format {
+            LogFormat::Pretty => {
+                let format = tracing_subscriber::fmt::layer()
+                    .without_time()
+                    .with_target(false)
+                    .with_writer(std::io::stderr);
+                (Some(format), None)
+            }
+            LogFormat::Json => (None, Some(JsonLayer)),
+        };
 
         let filter = EnvFilter::try_from_default_env()
             .or_else(|_| EnvFilter::try_new(&self.log_filters))
@@ -177,7 +823,146 @@ impl Config {
 
         tracing_subscriber::registry()
             .with(filter)
-            .with(format)
+            .with(pretty)
+            .with(json)
             .init();
     }
 }
+
+/// Overrides from a `.ra-multiplex.toml` file in the workspace root
+///
+/// They take precedence over the server, arguments and environment sent by
+/// the client, which take precedence over the `server`, `server_args` and
+/// `server_env` options. They're only used with `trust_project_config` set,
+/// with just `allowed_servers` set only the server is used and it has to be on
+/// the list.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub server: Option<String>,
+    pub args: Option<Vec<String>>,
+    /// Merged over the environment of the language server
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Overrides the `instance_timeout` of the workspace instances,
+    /// `Some(None)` never shuts them down
+    #[serde(default, deserialize_with = "de::project_instance_timeout")]
+    pub instance_timeout: Option<Option<u32>>,
+}
+
+impl ProjectConfig {
+    pub const FILE_NAME: &'static str = ".ra-multiplex.toml";
+
+    /// Load the project config of a workspace, a missing file is an empty
+    /// config
+    ///
+    /// The server must be a name looked up in `PATH` or an absolute path so a
+    /// project can't run an executable of its own by a relative path, it's
+    /// still subject to `allowed_servers`.
+    pub fn load(workspace_root: &Path) -> Result<Self> {
+        let path = workspace_root.join(Self::FILE_NAME);
+        let config_data = match fs::read(&path) {
+            Ok(config_data) => config_data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("cannot read {path:?}")),
+        };
+        let config = toml::from_slice::<Self>(&config_data)
+            .with_context(|| format!("cannot parse {path:?}"))?;
+        if let Some(server) = &config.server {
+            let server = Path::new(server);
+            ensure!(
+                server.is_absolute() || server.components().count() == 1,
+                "server {server:?} in {path:?} must be an absolute path or a name in `PATH`",
+            );
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn server_queue_water_marks_are_validated() {
+    assert!(Config::default().validate().is_ok());
+    let config =
+        toml::from_str::<Config>("server_queue_high_water = 16\nserver_queue_low_water = 16")
+            .unwrap();
+    assert!(config.validate().is_err());
+    let config =
+        toml::from_str::<Config>(&format!("server_queue_high_water = {}", i64::MAX)).unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn port_override_applies_to_listen_and_connect() {
+    let mut config = Config::default();
+    config.set_port(1234);
+
+    for address in [config.listen, config.connect] {
+        assert!(matches!(address, Address::Tcp(_, 1234)), "{address:?}");
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn method_policy() {
+    let config = toml::from_str::<Config>(
+        r#"
+        allowed_methods = ["textDocument/hover", "workspace/executeCommand"]
+        denied_methods = ["workspace/executeCommand"]
+        "#,
+    )
+    .unwrap();
+    assert!(config.method_allowed("textDocument/hover"));
+    assert!(!config.method_allowed("workspace/executeCommand"));
+    assert!(!config.method_allowed("textDocument/completion"));
+
+    let config =
+        toml::from_str::<Config>(r#"denied_methods = ["workspace/executeCommand"]"#).unwrap();
+    assert!(config.method_allowed("textDocument/completion"));
+    assert!(!config.method_allowed("workspace/executeCommand"));
+    assert!(Config::default().method_allowed("workspace/executeCommand"));
+}
+
+#[test]
+fn project_config() {
+    let dir = env::temp_dir().join(format!("ra-multiplex-project-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(ProjectConfig::FILE_NAME);
+
+    let config = ProjectConfig::load(&dir).unwrap();
+    assert!(config.server.is_none() && config.args.is_none());
+
+    fs::write(
+        &path,
+        "server = \"/usr/bin/rust-analyzer\"\nargs = [\"--verbose\"]\n",
+    )
+    .unwrap();
+    let config = ProjectConfig::load(&dir).unwrap();
+    assert_eq!(config.server.as_deref(), Some("/usr/bin/rust-analyzer"));
+    assert_eq!(config.args, Some(vec!["--verbose".to_owned()]));
+    assert_eq!(config.instance_timeout, None);
+
+    for (timeout, expected) in [
+        ("3600", Some(3600)),
+        ("0", None),
+        ("false", None),
+        ("\"never\"", None),
+    ] {
+        fs::write(&path, format!("instance_timeout = {timeout}")).unwrap();
+        let config = ProjectConfig::load(&dir).unwrap();
+        assert_eq!(config.instance_timeout, Some(expected), "{timeout}");
+    }
+
+    for invalid in [
+        "server = \"./evil\"",
+        "server = \"bin/evil\"",
+        "unknown = 1",
+        "instance_timeout = true",
+        "instance_timeout = \"always\"",
+    ] {
+        fs::write(&path, invalid).unwrap();
+        assert!(ProjectConfig::load(&dir).is_err(), "{invalid}");
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,168 @@
+//! Text of documents opened by clients
+//!
+//! The language server owns the documents once they're opened, we only keep a
+//! copy of their text so we can open them again after restarting a crashed
+//! language server.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::lsp;
+
+/// How are `Position::character` offsets counted
+///
+/// Negotiated with the `positionEncoding` server capability, defaults to UTF-16.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionEncoding {
+    Utf8,
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    pub fn from_capabilities(capabilities: &Value) -> PositionEncoding {
+        match capabilities["positionEncoding"].as_str() {
+            Some("utf-8") => PositionEncoding::Utf8,
+            Some("utf-32") => PositionEncoding::Utf32,
+            _ => PositionEncoding::Utf16,
+        }
+    }
+
+    fn len(self, ch: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => ch.len_utf8(),
+            PositionEncoding::Utf16 => ch.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+/// Apply one `textDocument/didChange` content change to the document text
+pub fn apply_change(
+    text: &mut String,
+    change: lsp::TextDocumentContentChangeEvent,
+    encoding: PositionEncoding,
+) -> Result<()> {
+    let Some(range) = change.range else {
+        // Without a range the change contains the whole document.
+        *text = change.text;
+        return Ok(());
+    };
+    let start = offset(text, range.start, encoding).context("range start")?;
+    let end = offset(text, range.end, encoding).context("range end")?;
+    if start > end {
+        bail!("range start is after range end");
+    }
+    text.replace_range(start..end, &change.text);
+    Ok(())
+}
+
+/// Convert a position to a byte offset into `text`
+///
+/// Like the specification says a character offset past the end of the line
+/// defaults back to the line end, a line past the end of the text defaults to
+/// the end of the text.
+fn offset(text: &str, position: lsp::Position, encoding: PositionEncoding) -> Result<usize> {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find(['\n', '\r']) {
+            Some(newline) => {
+                line_start += newline;
+                line_start += match text[line_start..].starts_with("\r\n") {
+                    true => 2,
+                    false => 1,
+                };
+            }
+            None => return Ok(text.len()),
+        }
+    }
+
+    let line = &text[line_start..];
+    let line = &line[..line.find(['\n', '\r']).unwrap_or(line.len())];
+    let mut units = 0;
+    for (index, ch) in line.char_indices() {
+        if units >= position.character {
+            if units > position.character {
+                bail!("position splits a character");
+            }
+            return Ok(line_start + index);
+        }
+        units += encoding.len(ch) as u32;
+    }
+    Ok(line_start + line.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn change(text: &mut String, change: Value, encoding: PositionEncoding) -> Result<()> {
+        apply_change(text, serde_json::from_value(change).unwrap(), encoding)
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Value {
+        json!({
+            "start": { "line": start.0, "character": start.1 },
+            "end": { "line": end.0, "character": end.1 },
+        })
+    }
+
+    #[test]
+    fn full_change_replaces_text() {
+        let mut text = "fn main() {}\n".to_owned();
+        change(&mut text, json!({ "text": "" }), PositionEncoding::Utf16).unwrap();
+        assert_eq!(text, "");
+    }
+
+    #[test]
+    fn incremental_changes() {
+        let mut text = "fn main() {\r\n}\nfoo".to_owned();
+        let encoding = PositionEncoding::Utf16;
+        change(
+            &mut text,
+            json!({ "range": range((0, 3), (0, 7)), "text": "test" }),
+            encoding,
+        )
+        .unwrap();
+        assert_eq!(text, "fn test() {\r\n}\nfoo");
+        change(
+            &mut text,
+            json!({ "range": range((1, 1), (2, 0)), "text": "" }),
+            encoding,
+        )
+        .unwrap();
+        assert_eq!(text, "fn test() {\r\n}foo");
+        change(
+            &mut text,
+            json!({ "range": range((1, 100), (100, 0)), "text": "!" }),
+            encoding,
+        )
+        .unwrap();
+        assert_eq!(text, "fn test() {\r\n}foo!");
+    }
+
+    #[test]
+    fn position_encodings() {
+        // `🦀` is 4 bytes, 2 UTF-16 code units and 1 UTF-32 code unit.
+        for (encoding, character) in [
+            (PositionEncoding::Utf8, 5),
+            (PositionEncoding::Utf16, 3),
+            (PositionEncoding::Utf32, 2),
+        ] {
+            let mut text = "a🦀b".to_owned();
+            change(
+                &mut text,
+                json!({ "range": range((0, 1), (0, character)), "text": "" }),
+                encoding,
+            )
+            .unwrap();
+            assert_eq!(text, "ab", "{encoding:?}");
+        }
+
+        let mut text = "a🦀b".to_owned();
+        let split = json!({ "range": range((0, 2), (0, 2)), "text": "" });
+        assert!(change(&mut text, split, PositionEncoding::Utf16).is_err());
+    }
+}
@@ -1,20 +1,48 @@
-use std::env;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{env, fs, process};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::de::{DeserializeOwned, IgnoredAny};
+use serde_json::json;
 use tokio::io::BufReader;
 
+use crate::capabilities;
+use crate::client::file_uri;
 use crate::config::Config;
-use crate::lsp::ext::{self, LspMuxOptions, StatusResponse};
-use crate::lsp::jsonrpc::{Message, Request, RequestId, Version};
+use crate::lsp::ext::{
+    self, HandoverResponse, HealthResponse, IdMapResponse, KillResponse, LspMuxOptions,
+    PinResponse, RejectReason, Rejection, StatusResponse, TraceDirection, TraceEvent, TraceKind,
+    TraceResponse, WarmupResponse,
+};
+use crate::lsp::jsonrpc::{Message, Notification, Request, RequestId, ResponseError, Version};
 use crate::lsp::transport::{LspReader, LspWriter};
-use crate::lsp::{InitializationOptions, InitializeParams};
-use crate::socketwrapper::Stream;
+use crate::lsp::{ClientInfo, InitializationOptions, InitializeParams, WorkspaceFolder};
+use crate::server;
+use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf, Stream};
 
 pub async fn ext_request<T>(config: &Config, method: ext::Request) -> Result<T>
 where
     T: DeserializeOwned,
 {
+    let (response, _, _) = ext_connect(config, method).await?;
+    Ok(response)
+}
+
+/// Send an lspmux request and keep the connection open for the messages
+/// following the response
+async fn ext_connect<T>(
+    config: &Config,
+    method: ext::Request,
+) -> Result<(
+    T,
+    LspReader<BufReader<OwnedReadHalf>>,
+    LspWriter<OwnedWriteHalf>,
+)>
+where
+    T: DeserializeOwned,
+{
+    let token = config.auth_token().context("auth token")?;
     let (reader, writer) = Stream::connect(&config.connect)
         .await
         .context("connect")?
@@ -31,6 +59,7 @@ where
                     initialization_options: Some(InitializationOptions {
                         lsp_mux: Some(LspMuxOptions {
                             version: LspMuxOptions::PROTOCOL_VERSION.into(),
+                            token,
                             method,
                         }),
                         other_options: serde_json::Map::default(),
@@ -52,7 +81,7 @@ where
         .await
         .context("send lspmux request")?;
 
-    match reader
+    let response = match reader
         .read_message()
         .await
         .context("read lspmux response")?
@@ -60,12 +89,39 @@ where
         .into_response()
         .context("received message was not a response")?
     {
-        Ok(success) => serde_json::from_value(success.result).context("parse response result"),
-        Err(error) => bail!(
-            "received error response: {msg:?}",
-            msg = Message::ResponseError(error),
+        Ok(success) => serde_json::from_value(success.result).context("parse response result")?,
+        Err(error) => match rejection(&error) {
+            Some(message) => bail!(message),
+            None => bail!(
+                "received error response: {msg:?}",
+                msg = Message::ResponseError(error),
+            ),
+        },
+    };
+    Ok((response, reader, writer))
+}
+
+/// Readable explanation of why the server refused the connection
+fn rejection(error: &ResponseError) -> Option<String> {
+    let data = error.error.data.clone()?;
+    let rejection = serde_json::from_value::<Rejection>(data).ok()?;
+    Some(match rejection.reason {
+        RejectReason::IncompatibleVersion => format!(
+            "ra-multiplex client v{} is incompatible with server v{}",
+            env!("CARGO_PKG_VERSION"),
+            rejection.server_version,
         ),
-    }
+        RejectReason::Unauthorized
+        | RejectReason::Draining
+        | RejectReason::ServerNotFound
+        | RejectReason::ServerPermissionDenied
+        | RejectReason::ServerExited
+        | RejectReason::ServerFailed
+        | RejectReason::InitializeConflict
+        | RejectReason::InvalidWorkspace => {
+            format!("server refused connection: {}", error.error.message)
+        }
+    })
 }
 
 pub async fn config(config: &Config) -> Result<()> {
@@ -82,6 +138,14 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
         return Ok(());
     }
 
+    if res.draining {
+        let clients = res
+            .instances
+            .iter()
+            .map(|instance| instance.clients.len())
+            .sum::<usize>();
+        println!("draining, {clients} clients remaining");
+    }
     for instance in res.instances {
         println!("- Instance");
         println!("  pid: {}", instance.pid);
@@ -94,7 +158,24 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
         }
         println!("  path: {:?}", instance.workspace_root);
         let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        println!("  uptime: {}s", now - instance.started);
         println!("  last used: {}s ago", now - instance.last_used);
+        println!("  pinned: {}", instance.pinned);
+        if instance.progress.is_empty() {
+            println!("  progress: ready");
+        } else {
+            println!("  progress:");
+            for progress in instance.progress {
+                print!("    - {}", progress.title);
+                if let Some(percentage) = progress.percentage {
+                    print!(" {percentage}%");
+                }
+                match progress.message {
+                    Some(message) => println!(" ({message})"),
+                    None => println!(),
+                }
+            }
+        }
         println!("  registered dynamic capabilities:");
         for cap in instance.registered_dyn_capabilities {
             println!("    - {}", cap);
@@ -103,6 +184,12 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
         for client in instance.clients {
             println!("    - Client");
             println!("      id: {}", client.id);
+            if let Some(name) = client.name {
+                println!("      name: {name}");
+            }
+            if client.queued_messages > 0 {
+                println!("      queued messages: {}", client.queued_messages);
+            }
             println!("      files:");
             for file in client.files {
                 println!("        - {}", file);
@@ -112,6 +199,191 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
     Ok(())
 }
 
+/// Fail unless the server and all its instances are healthy
+pub async fn health(config: &Config, json: bool) -> Result<()> {
+    let res = ext_request::<HealthResponse>(config, ext::Request::Health {}).await?;
+
+    if json {
+        let json = serde_json::to_string(&res).unwrap();
+        println!("{json}");
+    } else {
+        for instance in &res.instances {
+            let state = match (instance.running, instance.responsive) {
+                (true, true) => "ok",
+                (false, _) => "not running",
+                (true, false) => "not responding",
+            };
+            println!(
+                "{} (pid {}): {state}",
+                instance.workspace_root, instance.pid
+            );
+        }
+    }
+
+    if !res.healthy {
+        bail!("server is unhealthy");
+    }
+    if !json {
+        println!("healthy");
+    }
+    Ok(())
+}
+
+/// Resolve a user supplied path the same way clients' workspace roots are
+///
+/// The server picks the instance whose workspace root contains this path, so
+/// it may also point into a subdirectory. Paths which no longer exist are only
+/// made absolute, their instance may still be running.
+fn absolute_workspace_root(workspace_root: PathBuf) -> Result<String> {
+    let workspace_root = env::current_dir()
+        .context("unable to get current_dir")?
+        .join(workspace_root);
+    let workspace_root = fs::canonicalize(&workspace_root).unwrap_or(workspace_root);
+    Ok(workspace_root
+        .to_str()
+        .context("workspace root is not valid utf-8")?
+        .to_owned())
+}
+
+pub async fn kill(config: &Config, workspace_root: PathBuf) -> Result<()> {
+    let workspace_root = absolute_workspace_root(workspace_root)?;
+    let res = ext_request::<KillResponse>(config, ext::Request::Kill { workspace_root }).await?;
+    for instance in res.instances {
+        println!("killed {:?} (pid {})", instance.server, instance.pid);
+    }
+    Ok(())
+}
+
+pub async fn pin(config: &Config, workspace_root: PathBuf, pinned: bool) -> Result<()> {
+    let workspace_root = absolute_workspace_root(workspace_root)?;
+    let res = ext_request::<PinResponse>(
+        config,
+        ext::Request::Pin {
+            workspace_root,
+            pinned,
+        },
+    )
+    .await?;
+    let action = if pinned { "pinned" } else { "unpinned" };
+    for instance in res.instances {
+        println!("{action} {:?} (pid {})", instance.server, instance.pid);
+    }
+    Ok(())
+}
+
+pub async fn id_map(
+    config: &Config,
+    workspace_root: PathBuf,
+    limit: usize,
+    json: bool,
+) -> Result<()> {
+    let workspace_root = absolute_workspace_root(workspace_root)?;
+    let res = ext_request::<IdMapResponse>(
+        config,
+        ext::Request::IdMap {
+            workspace_root,
+            limit,
+        },
+    )
+    .await?;
+
+    if json {
+        let json = serde_json::to_string(&res).unwrap();
+        println!("{json}");
+        return Ok(());
+    }
+
+    for instance in res.instances {
+        println!(
+            "- Instance {:?} for {}",
+            instance.server, instance.workspace_root
+        );
+        println!("  client requests: {}", instance.client_requests);
+        for req in instance.oldest_client_requests {
+            println!(
+                "    client {} id {} (server id {}) {} {:.1}s",
+                req.client_id,
+                req.id,
+                req.server_id,
+                req.method,
+                req.age as f64 / 1000.0
+            );
+        }
+        println!("  server requests: {}", instance.server_requests);
+        for req in instance.oldest_server_requests {
+            println!(
+                "    id {} {} waiting for clients {:?} {:.1}s",
+                req.id,
+                req.method,
+                req.clients,
+                req.age as f64 / 1000.0
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn handover(config: &Config, workspace_root: Option<PathBuf>) -> Result<()> {
+    let workspace_root = workspace_root.map(absolute_workspace_root).transpose()?;
+    let res =
+        ext_request::<HandoverResponse>(config, ext::Request::Handover { workspace_root }).await?;
+    let mut failed = 0;
+    for instance in res.instances {
+        match (instance.pid, instance.error) {
+            (Some(pid), _) => println!(
+                "handed {:?} for {} over (pid {} -> {pid})",
+                instance.server, instance.workspace_root, instance.old_pid
+            ),
+            (None, error) => {
+                failed += 1;
+                println!(
+                    "couldn't hand {:?} for {} over (pid {}): {}",
+                    instance.server,
+                    instance.workspace_root,
+                    instance.old_pid,
+                    error.unwrap_or_default()
+                );
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} instances are still using the old language server");
+    }
+    Ok(())
+}
+
+pub async fn warmup(
+    config: &Config,
+    workspace_root: PathBuf,
+    server: Option<String>,
+    args: Vec<String>,
+) -> Result<()> {
+    let workspace_root = absolute_workspace_root(workspace_root)?;
+    let (server, args) = config.server_command(server, args);
+    let res = ext_request::<WarmupResponse>(
+        config,
+        ext::Request::Warmup {
+            server,
+            args,
+            env: config.passed_environment(),
+            workspace_root,
+        },
+    )
+    .await?;
+    let instance = res.instance;
+    println!(
+        "{:?} (pid {}) running for {}",
+        instance.server, instance.pid, instance.workspace_root
+    );
+    Ok(())
+}
+
+pub async fn drain(config: &Config, timeout: Option<u32>) -> Result<()> {
+    ext_request::<IgnoredAny>(config, ext::Request::Drain { timeout }).await?;
+    println!("draining, the server exits once all clients disconnected");
+    Ok(())
+}
+
 pub async fn reload(config: &Config) -> Result<()> {
     let cwd = env::current_dir()
         .context("unable to get current_dir")?
@@ -121,3 +393,263 @@ pub async fn reload(config: &Config) -> Result<()> {
     ext_request::<IgnoredAny>(config, ext::Request::Reload { cwd }).await?;
     Ok(())
 }
+
+/// Print the messages exchanged with the language servers of a workspace
+/// until they're gone
+pub async fn trace(config: &Config, workspace_root: PathBuf, json: bool) -> Result<()> {
+    let workspace_root = absolute_workspace_root(workspace_root)?;
+    // The writer is kept so the server doesn't see the observer disconnect.
+    let (res, mut reader, _writer) =
+        ext_connect::<TraceResponse>(config, ext::Request::Trace { workspace_root }).await?;
+    if !json {
+        for instance in &res.instances {
+            eprintln!("tracing {:?} (pid {})", instance.server, instance.pid);
+        }
+    }
+
+    while let Some(message) = reader.read_message().await.context("read trace event")? {
+        let Message::Notification(notif) = message else {
+            continue;
+        };
+        if notif.method != "$/lspmux/trace" {
+            continue;
+        }
+        if json {
+            println!("{}", notif.params);
+            continue;
+        }
+        let event =
+            serde_json::from_value::<TraceEvent>(notif.params).context("parse trace event")?;
+        if event.skipped > 0 {
+            println!("... {} messages skipped", event.skipped);
+        }
+        println!("{}", format_trace_event(&event));
+    }
+    Ok(())
+}
+
+fn format_trace_event(event: &TraceEvent) -> String {
+    let time = time::OffsetDateTime::from_unix_timestamp_nanos(i128::from(event.time) * 1_000_000)
+        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+    let direction = match event.direction {
+        TraceDirection::ToServer => "-->",
+        TraceDirection::FromServer => "<--",
+    };
+    let kind = match event.kind {
+        TraceKind::Request => "request",
+        TraceKind::Notification => "notification",
+        TraceKind::Response => "response",
+        TraceKind::Error => "error",
+    };
+    let mut line = format!(
+        "{:02}:{:02}:{:02}.{:03} {} {direction} {kind}",
+        time.hour(),
+        time.minute(),
+        time.second(),
+        time.millisecond(),
+        event.pid,
+    );
+    if let Some(method) = &event.method {
+        line += &format!(" {method}");
+    }
+    if let Some(id) = &event.id {
+        line += &format!(" id={id}");
+    }
+    line += &format!(" ({} bytes)", event.size);
+    line
+}
+
+#[cfg(test)]
+#[test]
+fn trace_event_format() {
+    let event = TraceEvent {
+        time: 3_723_004,
+        pid: 42,
+        direction: TraceDirection::FromServer,
+        kind: TraceKind::Request,
+        method: Some("workspace/configuration".into()),
+        id: Some(crate::lsp::jsonrpc::RequestId::Number(7)),
+        size: 120,
+        skipped: 0,
+    };
+    assert_eq!(
+        format_trace_event(&event),
+        "01:02:03.004 42 <-- request workspace/configuration id=7 (120 bytes)",
+    );
+}
+
+/// How long `doctor` waits for the language server to answer `initialize`
+const DOCTOR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Throwaway project `doctor` starts the language server for
+const DOCTOR_MANIFEST: &str = r#"[package]
+name = "ra-multiplex-doctor"
+version = "0.0.0"
+edition = "2021"
+"#;
+
+/// Check the setup step by step, from reaching the server to the language
+/// server answering `initialize` through it
+pub async fn doctor(config: &Config) -> Result<()> {
+    let mut failed = false;
+    let mut report = |check: &str, result: Result<String>| match result {
+        Ok(details) => println!("ok    {check}: {details}"),
+        Err(err) => {
+            failed = true;
+            println!("FAIL  {check}: {err:#}");
+        }
+    };
+
+    let health = ext_request::<HealthResponse>(config, ext::Request::Health {}).await;
+    let reachable = health.is_ok();
+    report(
+        "server",
+        health.map(|res| {
+            format!(
+                "reachable at {}, {} instances running",
+                config.connect,
+                res.instances.len()
+            )
+        }),
+    );
+
+    let (server, args) = config.server_command(None, Vec::new());
+    let language_server = match server::find_executable(&server) {
+        Some(path) => Ok(format!("{path:?}, {}", server::server_version(&path).await)),
+        None => Err(anyhow!(
+            "{server:?} not found, the ra-multiplex server may still find it in its own PATH"
+        )),
+    };
+    report("language server", language_server);
+
+    if reachable {
+        report("round trip", round_trip(config, server, args).await);
+    } else {
+        println!("skip  round trip: the server isn't reachable");
+    }
+
+    if failed {
+        bail!("some checks failed");
+    }
+    println!("everything works");
+    Ok(())
+}
+
+/// Initialize a private instance for a throwaway cargo project
+async fn round_trip(config: &Config, server: String, args: Vec<String>) -> Result<String> {
+    let dir = env::temp_dir().join(format!("ra-multiplex-doctor-{}", process::id()));
+    let result = initialize_project(config, &dir, server, args).await;
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+async fn initialize_project(
+    config: &Config,
+    dir: &Path,
+    server: String,
+    args: Vec<String>,
+) -> Result<String> {
+    fs::create_dir_all(dir.join("src")).with_context(|| format!("creating {dir:?}"))?;
+    fs::write(dir.join("Cargo.toml"), DOCTOR_MANIFEST).context("writing Cargo.toml")?;
+    fs::write(dir.join("src/lib.rs"), "").context("writing src/lib.rs")?;
+    let root = dir
+        .to_str()
+        .context("temporary directory is not valid utf-8")?;
+    let root_uri = file_uri(root);
+
+    let token = config.auth_token().context("auth token")?;
+    let (reader, writer) = Stream::connect(&config.connect)
+        .await
+        .context("connect")?
+        .into_split();
+    let mut writer = LspWriter::new(writer, "lspmux");
+    let mut reader = LspReader::new(BufReader::new(reader), "lspmux");
+    let params = InitializeParams {
+        initialization_options: Some(InitializationOptions {
+            lsp_mux: Some(LspMuxOptions {
+                version: LspMuxOptions::PROTOCOL_VERSION.into(),
+                token,
+                method: ext::Request::Connect {
+                    server,
+                    args,
+                    env: config.passed_environment(),
+                    cwd: None,
+                    private: true,
+                    compression: None,
+                    client_name: Some("doctor".into()),
+                },
+            }),
+            other_options: serde_json::Map::default(),
+        }),
+        process_id: Some(process::id().into()),
+        client_info: Some(ClientInfo {
+            name: "ra-multiplex doctor".into(),
+            version: Some(env!("CARGO_PKG_VERSION").into()),
+        }),
+        locale: None,
+        root_path: None,
+        root_uri: Some(root_uri.clone()),
+        capabilities: Some(capabilities::default_client()),
+        trace: None,
+        workspace_folders: vec![WorkspaceFolder {
+            uri: root_uri,
+            name: "ra-multiplex-doctor".into(),
+        }],
+    };
+    let started = Instant::now();
+    writer
+        .write_message(
+            &Request {
+                jsonrpc: Version,
+                method: "initialize".into(),
+                params: serde_json::to_value(params).unwrap(),
+                id: RequestId::Number(0),
+            }
+            .into(),
+        )
+        .await
+        .context("send initialize request")?;
+
+    let response = tokio::time::timeout(DOCTOR_TIMEOUT, reader.read_message())
+        .await
+        .context("timed out waiting for the initialize response")?
+        .context("read initialize response")?
+        .context("stream ended")?
+        .into_response()
+        .context("received message was not a response")?;
+    let result = match response {
+        Ok(success) => success.result,
+        Err(error) => match rejection(&error) {
+            Some(message) => bail!(message),
+            None => bail!("initialize failed: {}", error.error.message),
+        },
+    };
+    let elapsed = started.elapsed();
+    if !result["capabilities"].is_object() {
+        bail!("initialize response has no capabilities: {result}");
+    }
+
+    // The private instance shuts down when an initialized client disconnects.
+    writer
+        .write_message(
+            &Notification {
+                jsonrpc: Version,
+                method: "initialized".into(),
+                params: json!({}),
+            }
+            .into(),
+        )
+        .await
+        .context("send initialized notification")?;
+
+    let server_info = &result["serverInfo"];
+    let name = server_info["name"].as_str().unwrap_or("language server");
+    let version = server_info["version"]
+        .as_str()
+        .map(|version| format!(" {version}"))
+        .unwrap_or_default();
+    Ok(format!(
+        "{name}{version} answered initialize in {}ms",
+        elapsed.as_millis()
+    ))
+}
@@ -0,0 +1,27 @@
+//! Entry points for the `cargo fuzz` targets in `fuzz/`
+//!
+//! Run them with `cargo +nightly fuzz run lsp_reader`.
+
+use tokio::io::BufReader;
+use tokio::runtime;
+
+use crate::lsp::transport::LspReader;
+
+/// Read messages from arbitrary bytes until the reader gives up
+///
+/// The first byte picks the size of the read buffer so headers also end up
+/// split across reads. Whatever the rest is, the reader has to return
+/// messages, errors or the end of the stream and never panic or hang.
+pub fn read_messages(data: &[u8]) {
+    let Some((&capacity, data)) = data.split_first() else {
+        return;
+    };
+    let runtime = runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        let reader = BufReader::with_capacity(usize::from(capacity) + 1, data);
+        // Bodies are allocated up front, a huge `content-length` would slow
+        // down every run.
+        let mut reader = LspReader::new(reader, "fuzz").max_content_length(64 * 1024);
+        while !matches!(reader.read_message().await, Ok(None)) {}
+    });
+}
@@ -1,29 +1,44 @@
 use std::collections::hash_map::Entry;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::env;
+use std::fmt;
 use std::io::ErrorKind;
+use std::mem;
 use std::ops::Deref;
-use std::path::Path;
-use std::process::Stdio;
-use std::sync::atomic::{AtomicI64, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
-
-use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::Deserialize;
 use serde_json::{json, Value};
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
-use tokio::sync::mpsc::error::SendError;
-use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::{
+    broadcast, mpsc, oneshot, watch, Mutex, Notify, OwnedSemaphorePermit, Semaphore,
+};
+use tokio::task::JoinHandle;
 use tokio::{select, task};
 use tracing::{debug, error, field, info, instrument, trace, warn, Instrument};
 
+use crate::capabilities;
 use crate::client::Client;
-use crate::config::Config;
+use crate::config::{
+    Config, DiagnosticSeverity, NullIdResponses, ProjectConfig, RestartMessage, ServerRequests,
+    Telemetry,
+};
+use crate::document::{self, PositionEncoding};
 use crate::lsp::ext::Tag;
-use crate::lsp::jsonrpc::{Message, Notification, Request, RequestId, ResponseSuccess, Version};
+use crate::lsp::jsonrpc::{
+    self, Message, Notification, Request, RequestId, ResponseError, ResponseSuccess, Version,
+};
 use crate::lsp::transport::{LspReader, LspWriter};
 use crate::lsp::{self, ext};
+use crate::metrics;
+use crate::state;
 
 /// Specifies server configuration
 ///
@@ -34,43 +49,272 @@ pub struct InstanceKey {
     pub args: Vec<String>,
     pub env: BTreeMap<String, String>,
     pub workspace_root: String,
+    /// Client owning this private instance, `None` for instances shared with
+    /// every client of the workspace
+    pub private: Option<usize>,
+}
+
+/// Request ID of the `shutdown` request sent by `wait_task`
+const SHUTDOWN_REQUEST_ID: &str = "lspmux:shutdown_request";
+
+/// Prefix of the request IDs of `watchdog_task` pings, followed by the number
+/// of the ping
+const WATCHDOG_REQUEST_ID: &str = "lspmux:watchdog_ping:";
+
+/// How long to wait for each step of the shutdown handshake before killing the
+/// language server
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the remaining output of an exited language server
+/// before failing the requests it didn't answer
+const STDOUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a language server which failed the `initialize` handshake has to
+/// exit for the failure to be reported as an early exit
+const EARLY_EXIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many times in a row we try to restart a crashed language server
+const MAX_RESTARTS: u32 = 5;
+
+/// Delay before the first restart attempt, doubled with every next attempt
+const RESTART_BACKOFF: Duration = Duration::from_millis(500);
+
+/// A language server running for this long is no longer considered to be in a
+/// crash loop and the restart attempts start over
+const RESTART_RESET: Duration = Duration::from_secs(60);
+
+/// Room in the language server queue on top of `server_queue_high_water`
+///
+/// Clients paused at the high water mark still send responses and
+/// cancellations. If the queue fills up anyway while the server is
+/// restarting the clients sending more are disconnected.
+pub const SERVER_QUEUE_HEADROOM: usize = 64;
+
+/// How many trace events an observer can fall behind before they're dropped
+const TRACE_QUEUE_SIZE: usize = 1024;
+
+/// How many `telemetry/event`s can wait for the `telemetry_file` before
+/// they're dropped
+const TELEMETRY_QUEUE_SIZE: usize = 1024;
+
+/// How often dropped `telemetry/event`s are warned about at most
+const TELEMETRY_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a starting language server has to answer the `initialize` request
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a health check waits for a lock before reporting the server or
+/// an instance as unresponsive
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Context of errors starting a language server which tells the client what
+/// went wrong
+#[derive(Debug)]
+pub struct StartError {
+    pub reason: ext::RejectReason,
+    pub message: String,
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
 }
 
 /// Language server instance
 pub struct Instance {
     key: InstanceKey,
 
+    config: Arc<Config>,
+
     /// Language server child process id
-    pid: u32,
+    ///
+    /// Changes when the language server is restarted.
+    pid: AtomicU32,
+
+    /// Language server child process is running
+    ///
+    /// Cleared by `wait_task` when the process exits, set again after a
+    /// restart.
+    running: AtomicBool,
+
+    /// The first client's `initialize` request params, used to initialize
+    /// restarted language servers
+    init_req_params: lsp::InitializeParams,
 
     /// Server's response to `initialize` request
     init_result: lsp::InitializeResult,
 
+    /// Position encoding negotiated by the server in `init_result`
+    position_encoding: PositionEncoding,
+
     /// Handle for sending messages to the language server instance
     server: mpsc::Sender<Message>,
 
+    /// Notified by `stdin_task` when no more than `server_queue_low_water`
+    /// messages are left in the queue
+    server_queue_drained: Arc<Notify>,
+
+    /// Language servers started by [`Instance::handover`] for `wait_task` to
+    /// switch over to
+    handovers: mpsc::Sender<Handover>,
+
     /// Data of associated clients
     clients: Mutex<HashMap<usize, ClientData>>,
 
+    /// Documents currently opened by any client, keyed by URI
+    documents: Mutex<HashMap<String, lsp::TextDocumentItem>>,
+
+    /// Server requests forwarded to clients which are waiting for a response
+    ///
+    /// Keyed by the request ID as the server sent it.
+    server_requests: Mutex<HashMap<RequestId, PendingServerRequest>>,
+
+    /// Client requests waiting for the server to finish others with
+    /// `max_in_flight_requests` set
+    request_queue: Option<Arc<RequestQueue>>,
+
+    /// Progress tokens created by the server with
+    /// `window/workDoneProgress/create` which haven't ended yet
+    ///
+    /// Unlike client provided tokens these are sent to clients untagged.
+    server_progress_tokens: Mutex<HashSet<RequestId>>,
+
+    /// Work done progress of the server which didn't end yet, keyed by the
+    /// token as the server sent it
+    progress: Mutex<HashMap<RequestId, ext::Progress>>,
+
     /// Dynamic capabilities registered by the server
     dynamic_capabilities: Mutex<HashMap<String, lsp::Registration>>,
 
-    /// Wakes up `wait_task` and asks it to send SIGKILL to the instance.
+    /// Counts `workspace/didChangeConfiguration` notifications, orders the
+    /// configurations of the clients
+    configuration_changes: AtomicU64,
+
+    /// Trace level the server was last set to, the most verbose one of the
+    /// clients
+    server_trace: Mutex<lsp::TraceValue>,
+
+    /// Wakes up `wait_task` and asks it to shut down the instance.
     close: Notify,
 
+    /// Notified by `stdout_task` when the server responds to our `shutdown`
+    /// request.
+    shutdown: Notify,
+
+    /// Notified by `stdout_task` when the server stdout is closed
+    stdout_closed: Notify,
+
+    /// Number of the last `watchdog_task` ping the server answered
+    watchdog_pongs: watch::Sender<u64>,
+
+    /// Notified by `watchdog_task` when the server stopped answering, wakes up
+    /// `wait_task` to kill and restart it
+    unresponsive: Notify,
+
+    /// Metadata of the messages exchanged with the server for `trace`
+    /// observers
+    ///
+    /// Observers which don't keep up lose events instead of slowing down the
+    /// relay.
+    trace: broadcast::Sender<ext::TraceEvent>,
+
+    /// `telemetry/event` params for the task writing the `telemetry_file`
+    ///
+    /// A slow file system only loses events instead of stalling the server
+    /// output, the task ends with the instance.
+    telemetry: Option<mpsc::Sender<Value>>,
+
+    /// Time the instance was spawned, restarts don't reset it
+    ///
+    /// Uses UTC unix timestamp ([utc_now] function)
+    started: i64,
+
     /// Last time a message was sent to this instance
     ///
     /// Uses UTC unix timestamp ([utc_now] function)
     last_used: AtomicI64,
+
+    /// Don't shut down the instance when it's idle
+    pinned: AtomicBool,
+
+    /// The instance was pinned by a warmup, it's unpinned once the first
+    /// client connects
+    warming: AtomicBool,
+
+    /// `instance_timeout` of the project config, `Some(None)` never shuts the
+    /// instance down
+    ///
+    /// Read again when the last client leaves.
+    instance_timeout: Mutex<Option<Option<u32>>>,
 }
 
 impl Drop for Instance {
     fn drop(&mut self) {
         // Make sure we're not leaking anything
         debug!("instance dropped");
+        if let Some(queue) = &self.request_queue {
+            queue.close();
+        }
     }
 }
 
+/// Client ID and configuration of the client which changed it last
+fn current_configuration(clients: &HashMap<usize, ClientData>) -> Option<(usize, &Value)> {
+    clients
+        .values()
+        .filter_map(|client| {
+            let (change, params) = client.configuration.as_ref()?;
+            Some((change, client.id(), params))
+        })
+        .max_by_key(|(change, _, _)| *change)
+        .map(|(_, client_id, params)| (client_id, params))
+}
+
+/// Error response telling the server a request couldn't be answered
+fn request_failed(id: RequestId, message: &str) -> Message {
+    Message::ResponseError(ResponseError {
+        jsonrpc: Version,
+        error: jsonrpc::Error {
+            // LSP `RequestFailed`
+            code: -32803,
+            message: message.into(),
+            data: None,
+        },
+        id: Some(id),
+    })
+}
+
+fn workspace_folders_notification(
+    added: Vec<lsp::WorkspaceFolder>,
+    removed: Vec<lsp::WorkspaceFolder>,
+) -> Message {
+    let params = lsp::DidChangeWorkspaceFoldersParams {
+        event: lsp::WorkspaceFoldersChangeEvent { added, removed },
+    };
+    Message::Notification(Notification {
+        jsonrpc: Version,
+        method: "workspace/didChangeWorkspaceFolders".into(),
+        params: serde_json::to_value(params).unwrap(),
+    })
+}
+
+fn configuration_notification(params: Value) -> Message {
+    Message::Notification(Notification {
+        jsonrpc: Version,
+        method: "workspace/didChangeConfiguration".into(),
+        params,
+    })
+}
+
+fn set_trace_notification(value: lsp::TraceValue) -> Message {
+    Message::Notification(Notification {
+        jsonrpc: Version,
+        method: "$/setTrace".into(),
+        params: serde_json::to_value(lsp::SetTraceParams { value }).unwrap(),
+    })
+}
+
 /// Wrapper around client handle with additional data only the server instance
 /// knows about
 struct ClientData {
@@ -79,13 +323,144 @@ struct ClientData {
 
     /// URIs of files currently opened by this client
     files: HashSet<String>,
+
+    /// Workspace folders added by this client with
+    /// `workspace/didChangeWorkspaceFolders`, keyed by URI
+    workspace_folders: HashMap<String, lsp::WorkspaceFolder>,
+
+    /// Requests sent to the server which are still waiting for a response
+    ///
+    /// Keyed by the original request ID as the client sent it.
+    requests: HashMap<RequestId, PendingRequest>,
+
+    /// Params of the last `workspace/didChangeConfiguration` notification
+    /// sent by this client, with the value of
+    /// [`Instance::configuration_changes`] at the time
+    configuration: Option<(u64, Value)>,
+
+    /// Set by `initialize` and `$/setTrace`, decides which `$/logTrace`
+    /// notifications the client gets
+    trace: lsp::TraceValue,
+}
+
+/// Server request forwarded to clients
+struct PendingServerRequest {
+    method: String,
+    /// Clients which received the request and didn't respond yet
+    clients: HashSet<usize>,
+    /// When the request was forwarded to the clients
+    started: Instant,
+}
+
+/// Client request forwarded to the language server
+struct PendingRequest {
+    method: String,
+    /// When the request was sent to the server
+    started: Instant,
+    /// Released when the request is answered or failed
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Client requests waiting for one of the `max_in_flight_requests` permits
+///
+/// Only requests wait here, the rest of the client's messages like
+/// `$/cancelRequest` and responses to server requests go to the server right
+/// away so it can finish the requests holding the permits. Clients take turns,
+/// the requests of one client are sent in order.
+struct RequestQueue {
+    /// The permits are held by `PendingRequest`s
+    permits: Arc<Semaphore>,
+    waiting: Mutex<WaitingRequests>,
+    /// Wakes `request_queue_task` when a request starts waiting
+    request_waiting: Notify,
+}
+
+impl RequestQueue {
+    fn new(permits: usize) -> RequestQueue {
+        RequestQueue {
+            permits: Arc::new(Semaphore::new(permits)),
+            waiting: Mutex::default(),
+            request_waiting: Notify::new(),
+        }
+    }
+
+    /// Stop `request_queue_task`
+    fn close(&self) {
+        self.permits.close();
+        self.request_waiting.notify_one();
+    }
+}
+
+#[derive(Default)]
+struct WaitingRequests {
+    /// Clients with waiting requests in the order they get their turn
+    turns: VecDeque<usize>,
+    requests: HashMap<usize, VecDeque<Request>>,
+}
+
+impl WaitingRequests {
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    fn push(&mut self, client_id: usize, req: Request) {
+        let requests = self.requests.entry(client_id).or_default();
+        if requests.is_empty() {
+            self.turns.push_back(client_id);
+        }
+        requests.push_back(req);
+    }
+
+    /// Take the next request of the client whose turn it is
+    fn pop(&mut self) -> Option<(usize, Request)> {
+        let client_id = self.turns.pop_front()?;
+        let requests = self.requests.get_mut(&client_id)?;
+        let req = requests.pop_front()?;
+        if requests.is_empty() {
+            self.requests.remove(&client_id);
+        } else {
+            self.turns.push_back(client_id);
+        }
+        Some((client_id, req))
+    }
+
+    /// Take a waiting request out of the queue
+    fn remove(&mut self, client_id: usize, id: &RequestId) -> Option<Request> {
+        let requests = self.requests.get_mut(&client_id)?;
+        let index = requests.iter().position(|req| req.id == *id)?;
+        let req = requests.remove(index);
+        if requests.is_empty() {
+            self.remove_client(client_id);
+        }
+        req
+    }
+
+    fn remove_client(&mut self, client_id: usize) {
+        self.requests.remove(&client_id);
+        self.turns.retain(|&id| id != client_id);
+    }
 }
 
 impl ClientData {
     fn get_status(&self) -> ext::Client {
         ext::Client {
             id: self.client.id(),
+            name: self.client.name().map(String::from),
             files: self.files.iter().cloned().collect(),
+            queued_messages: self.client.queued(),
+        }
+    }
+
+    /// Forget a request the server has answered and record its latency
+    ///
+    /// Returns `false` if the request wasn't pending anymore.
+    fn finish_request(&mut self, id: &RequestId) -> bool {
+        match self.requests.remove(id) {
+            Some(request) => {
+                metrics::observe_latency(&request.method, request.started.elapsed());
+                true
+            }
+            None => false,
         }
     }
 }
@@ -109,6 +484,66 @@ impl Instance {
         self.last_used.store(utc_now(), Ordering::Relaxed);
     }
 
+    /// Exempt the instance from the idle shutdown or make it subject to it
+    /// again
+    ///
+    /// An unpinned instance is idle from now on even if it had no clients
+    /// for a while.
+    pub fn set_pinned(&self, pinned: bool) {
+        // Pinning explicitly overrides the warmup.
+        self.warming.store(false, Ordering::Relaxed);
+        self.pinned.store(pinned, Ordering::Relaxed);
+        if !pinned {
+            self.keep_alive();
+        }
+    }
+
+    /// Keep an instance nobody uses yet running until its first client
+    /// connects
+    ///
+    /// Instances which already have clients or are pinned are left alone.
+    pub async fn warm_up(&self) {
+        let clients = self.clients.lock().await;
+        if clients.is_empty() && !self.pinned.load(Ordering::Relaxed) {
+            self.pinned.store(true, Ordering::Relaxed);
+            self.warming.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Tell the clients their language server was restarted, unless
+    /// `restart_message` is off
+    async fn announce_restart(&self) {
+        if self.config.restart_message == RestartMessage::Off {
+            return;
+        }
+        let server = Path::new(&self.key.server)
+            .file_name()
+            .map_or(self.key.server.as_str(), |name| {
+                name.to_str().unwrap_or_default()
+            });
+        let params = lsp::ShowMessageParams {
+            typ: lsp::MESSAGE_TYPE_INFO,
+            message: format!("{server} restarted, re-indexing"),
+        };
+        let notif = Message::from(Notification {
+            jsonrpc: Version,
+            method: "window/showMessage".into(),
+            params: serde_json::to_value(params).unwrap(),
+        });
+        for client in self.clients.lock().await.values() {
+            client.send_message_nowait(notif.clone());
+        }
+    }
+
+    pub fn key(&self) -> &InstanceKey {
+        &self.key
+    }
+
+    /// PID of the current language server process
+    pub fn pid(&self) -> u32 {
+        self.pid.load(Ordering::Relaxed)
+    }
+
     /// How many seconds is the instance idle for
     pub fn idle(&self) -> i64 {
         i64::max(0, utc_now() - self.last_used.load(Ordering::Relaxed))
@@ -118,11 +553,46 @@ impl Instance {
         self.init_result.clone()
     }
 
+    /// Observe the messages exchanged with the server
+    pub fn trace(&self) -> broadcast::Receiver<ext::TraceEvent> {
+        self.trace.subscribe()
+    }
+
+    /// Publish the metadata of a message to the `trace` observers
+    fn trace_message(&self, direction: ext::TraceDirection, message: &Message) {
+        // Serializing only to measure the message isn't free, skip it when
+        // nobody is watching.
+        if self.trace.receiver_count() == 0 {
+            return;
+        }
+        let kind = match message {
+            Message::Request(_) => ext::TraceKind::Request,
+            Message::Notification(_) => ext::TraceKind::Notification,
+            Message::ResponseSuccess(_) => ext::TraceKind::Response,
+            Message::ResponseError(_) => ext::TraceKind::Error,
+        };
+        let time = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+        let _ = self.trace.send(ext::TraceEvent {
+            time: time as i64,
+            pid: self.pid.load(Ordering::Relaxed),
+            direction,
+            kind,
+            method: message.method().map(str::to_owned),
+            id: message.id().cloned(),
+            size: serde_json::to_vec(message).map_or(0, |body| body.len()),
+            skipped: 0,
+        });
+    }
+
     /// Add client to the instance so it can receive traffic from it
     ///
     /// It replays all registered dynamic capabilities to it.
-    pub async fn add_client(&self, client: Client) {
+    pub async fn add_client(&self, client: Client, trace: lsp::TraceValue) {
         let mut clients = self.clients.lock().await;
+        if self.warming.load(Ordering::Relaxed) {
+            debug!("first client connected to warmed up instance");
+            self.set_pinned(false);
+        }
         let dyn_capabilities = self.dynamic_capabilities.lock().await;
 
         if !dyn_capabilities.is_empty() {
@@ -146,10 +616,62 @@ impl Instance {
         let client = ClientData {
             client,
             files: HashSet::new(),
+            workspace_folders: HashMap::new(),
+            requests: HashMap::new(),
+            configuration: None,
+            trace,
         };
         if clients.insert(client.id(), client).is_some() {
             unreachable!("BUG: added two clients with the same ID");
         }
+        drop(dyn_capabilities);
+        let _ = self.update_server_trace(&clients).await;
+    }
+
+    /// Handle `$/setTrace` client notification
+    ///
+    /// The level only applies to the client, the server is set to the most
+    /// verbose level of all clients.
+    pub async fn set_trace(
+        &self,
+        client_id: usize,
+        params: Value,
+    ) -> Result<(), SendError<Message>> {
+        let params = match lsp::SetTraceParams::deserialize(&params) {
+            Ok(params) => params,
+            Err(err) => {
+                warn!(?err, "invalid $/setTrace params");
+                return Ok(());
+            }
+        };
+        let mut clients = self.clients.lock().await;
+        if let Some(client) = clients.get_mut(&client_id) {
+            debug!(trace = ?params.value, "changing client trace level");
+            client.trace = params.value;
+        }
+        self.update_server_trace(&clients).await
+    }
+
+    /// Send `$/setTrace` to the server if the most verbose client trace level
+    /// changed
+    ///
+    /// Without clients the server keeps its level until the next one
+    /// connects.
+    async fn update_server_trace(
+        &self,
+        clients: &HashMap<usize, ClientData>,
+    ) -> Result<(), SendError<Message>> {
+        let Some(trace) = clients.values().map(|client| client.trace).max() else {
+            return Ok(());
+        };
+        let mut server_trace = self.server_trace.lock().await;
+        if *server_trace == trace {
+            return Ok(());
+        }
+        debug!(?trace, "changing server trace level");
+        *server_trace = trace;
+        drop(server_trace);
+        self.send_message(set_trace_notification(trace)).await
     }
 
     /// Send cleanup messages and remove remove client for client map
@@ -166,30 +688,613 @@ impl Instance {
             bail!("client was not connected");
         };
 
+        // Start the idle timeout from the moment the last client leaves.
+        self.keep_alive();
+
+        if let Some(queue) = &self.request_queue {
+            queue.waiting.lock().await.remove_client(client.client.id());
+        }
+
+        // Other clients might keep the instance alive for a long time, make
+        // sure the server doesn't waste time on responses nobody will read.
+        for (id, request) in client.requests {
+            let params = lsp::CancelParams {
+                id: self.server_request_id(client.client.id(), id),
+            };
+            let notif = Notification {
+                jsonrpc: Version,
+                method: "$/cancelRequest".into(),
+                params: serde_json::to_value(params).unwrap(),
+            };
+            debug!(
+                method = request.method,
+                ?notif,
+                "cancelling pending request"
+            );
+            let _ = self.send_message(notif.into()).await;
+        }
+
+        let _ = self.update_server_trace(&clients).await;
+
+        // Answer server requests nobody else is going to answer.
+        let mut server_requests = self.server_requests.lock().await;
+        let mut unanswered = Vec::new();
+        let client_id = client.client.id();
+        server_requests.retain(|id, request| {
+            let was_waiting = request.clients.remove(&client_id);
+            if was_waiting && request.clients.is_empty() {
+                unanswered.push(id.clone());
+                return false;
+            }
+            true
+        });
+        drop(server_requests);
+        for id in unanswered {
+            debug!(?id, "client disconnected before answering server request");
+            let _ = self
+                .send_message(request_failed(id, "client disconnected"))
+                .await;
+        }
+
+        // Go back to the configuration of the client which changed it most
+        // recently before this one.
+        let was_current = client.configuration.as_ref().is_some_and(|(change, _)| {
+            clients.values().all(|other| {
+                other
+                    .configuration
+                    .as_ref()
+                    .is_none_or(|(other, _)| other < change)
+            })
+        });
+        if was_current {
+            if let Some((previous_client_id, previous)) = current_configuration(&clients) {
+                if Some(previous) != client.configuration.as_ref().map(|(_, params)| params) {
+                    info!(
+                        previous_client_id,
+                        "restoring configuration of another client"
+                    );
+                    let _ = self
+                        .send_message(configuration_notification(previous.clone()))
+                        .await;
+                }
+            }
+        }
+
+        let removed = client
+            .workspace_folders
+            .into_values()
+            .filter(|folder| !has_workspace_folder(&clients, &folder.uri))
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            debug!(?removed, "removing workspace folders of the last client");
+            let _ = self
+                .send_message(workspace_folders_notification(Vec::new(), removed))
+                .await;
+        }
+
         let files = client.files.into_iter().collect::<Vec<_>>();
-        self.close_all_files(&clients, files)
-            .await
-            .context("error closing files")?;
+        let closed = self.close_all_files(&clients, files).await;
+
+        let idle = clients.is_empty();
+        drop(clients);
+        if idle {
+            let instance_timeout = project_instance_timeout(&self.key.workspace_root).await;
+            *self.instance_timeout.lock().await = instance_timeout;
+        }
+
+        closed.context("error closing files")
+    }
+
+    /// Handle `workspace/didChangeWorkspaceFolders` client notification
+    ///
+    /// Folders are reference counted like documents, the server learns about a
+    /// folder when the first client adds it and only removes it once no client
+    /// has it anymore. Folders a client didn't add itself, like the workspace
+    /// root every client shares, can't be removed.
+    pub async fn change_workspace_folders(&self, client_id: usize, params: Value) -> Result<()> {
+        let params = serde_json::from_value::<lsp::DidChangeWorkspaceFoldersParams>(params)
+            .context("parsing params")?;
+        let mut clients = self.clients.lock().await;
+        info!(
+            added = ?params.event.added,
+            removed = ?params.event.removed,
+            "client changed workspace folders"
+        );
+
+        let mut added = Vec::new();
+        for folder in params.event.added {
+            if !has_workspace_folder(&clients, &folder.uri) {
+                added.push(folder.clone());
+            }
+            clients
+                .get_mut(&client_id)
+                .context("no matching client")?
+                .workspace_folders
+                .insert(folder.uri.clone(), folder);
+        }
 
+        let mut removed = Vec::new();
+        for folder in params.event.removed {
+            let client = clients.get_mut(&client_id).context("no matching client")?;
+            if client.workspace_folders.remove(&folder.uri).is_none() {
+                debug!(uri = ?folder.uri, "ignoring removal of a folder the client didn't add");
+                continue;
+            }
+            if has_workspace_folder(&clients, &folder.uri) {
+                debug!(uri = ?folder.uri, "workspace folder still used by another client");
+                continue;
+            }
+            removed.push(folder);
+        }
+
+        if !added.is_empty() || !removed.is_empty() {
+            let _ = self
+                .send_message(workspace_folders_notification(added, removed))
+                .await;
+        }
         Ok(())
     }
 
+    /// Handle `workspace/didChangeConfiguration` client notification
+    ///
+    /// All clients share one server so the last configuration sent by any
+    /// client is in effect. We remember each client's configuration to warn
+    /// when it overrides another client's and to restore the previous one when
+    /// the client disconnects.
+    pub async fn change_configuration(
+        &self,
+        client_id: usize,
+        params: Value,
+    ) -> Result<(), SendError<Message>> {
+        let mut clients = self.clients.lock().await;
+        if let Some((current_client_id, current)) = current_configuration(&clients) {
+            if current_client_id != client_id && *current != params {
+                warn!(
+                    overridden_client_id = current_client_id,
+                    "configuration overrides the configuration of another client"
+                );
+            }
+        }
+        if let Some(client) = clients.get_mut(&client_id) {
+            let change = self.configuration_changes.fetch_add(1, Ordering::Relaxed);
+            client.configuration = Some((change, params.clone()));
+        }
+        self.send_message(configuration_notification(params)).await
+    }
+
+    /// Handle `window/workDoneProgress/cancel` client notification
+    ///
+    /// Tokens the client provided itself were tagged in [`Self::send_request`]
+    /// and need the same tag, tokens created by the server are passed as is.
+    pub async fn cancel_progress(
+        &self,
+        client_id: usize,
+        mut notif: Notification,
+    ) -> Result<(), SendError<Message>> {
+        match lsp::WorkDoneProgressParams::deserialize(&notif.params) {
+            Ok(params) => {
+                if !self
+                    .server_progress_tokens
+                    .lock()
+                    .await
+                    .contains(&params.token)
+                {
+                    notif.params = serde_json::to_value(lsp::WorkDoneProgressParams {
+                        token: params.token.tag(Tag::ClientId(client_id)),
+                    })
+                    .unwrap();
+                }
+            }
+            Err(err) => warn!(?err, "invalid window/workDoneProgress/cancel params"),
+        }
+        self.send_message(notif.into()).await
+    }
+
+    /// Handle `$/cancelRequest` client notification
+    ///
+    /// The server knows the request by its tagged ID. Cancelling a request
+    /// which isn't pending anymore wouldn't do anything, those are dropped.
+    pub async fn cancel_request(
+        &self,
+        client_id: usize,
+        mut notif: Notification,
+    ) -> Result<(), SendError<Message>> {
+        let params = match lsp::CancelParams::deserialize(&notif.params) {
+            Ok(params) => params,
+            Err(err) => {
+                warn!(?err, "invalid $/cancelRequest params");
+                return Ok(());
+            }
+        };
+        let clients = self.clients.lock().await;
+        let Some(client) = clients.get(&client_id) else {
+            return Ok(());
+        };
+        // A request still waiting for a permit can be answered right here, the
+        // server doesn't know about it yet.
+        if let Some(queue) = &self.request_queue {
+            if let Some(req) = queue.waiting.lock().await.remove(client_id, &params.id) {
+                debug!(method = req.method, "cancelled request before it was sent");
+                client
+                    .client
+                    .send_message_nowait(Message::ResponseError(ResponseError {
+                        jsonrpc: Version,
+                        error: jsonrpc::Error {
+                            // LSP `RequestCancelled`
+                            code: -32800,
+                            message: "request cancelled".into(),
+                            data: None,
+                        },
+                        id: Some(req.id),
+                    }));
+                return Ok(());
+            }
+        }
+        let pending = client.requests.contains_key(&params.id);
+        drop(clients);
+        if !pending {
+            debug!(id = ?params.id, "dropping cancellation of a request which isn't pending");
+            return Ok(());
+        }
+        notif.params = serde_json::to_value(lsp::CancelParams {
+            id: self.server_request_id(client_id, params.id),
+        })
+        .unwrap();
+        self.send_message(notif.into()).await
+    }
+
+    /// Keep track of `$/progress` to tell whether the server is busy
+    async fn track_progress(&self, progress: &lsp::ProgressParams) {
+        let value = &progress.value;
+        let string = |key| value[key].as_str().map(str::to_owned);
+        let percentage = value["percentage"].as_u64().map(|p| p.min(100) as u32);
+        let mut tracked = self.progress.lock().await;
+        match value["kind"].as_str() {
+            Some("begin") => {
+                let title = string("title").unwrap_or_default();
+                debug!(title, "server started work");
+                tracked.insert(
+                    progress.token.clone(),
+                    ext::Progress {
+                        title,
+                        message: string("message"),
+                        percentage,
+                    },
+                );
+            }
+            Some("report") => {
+                if let Some(tracked) = tracked.get_mut(&progress.token) {
+                    tracked.message = string("message").or(tracked.message.take());
+                    tracked.percentage = percentage.or(tracked.percentage);
+                }
+            }
+            Some("end") => {
+                if let Some(ended) = tracked.remove(&progress.token) {
+                    debug!(title = ended.title, "server finished work");
+                    if tracked.is_empty() {
+                        info!("language server is ready");
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Messages waiting to be written to the language server
+    fn queued_to_server(&self) -> usize {
+        self.server.max_capacity() - self.server.capacity()
+    }
+
+    /// The language server queue reached `server_queue_high_water`
+    pub fn server_queue_full(&self) -> bool {
+        self.queued_to_server() >= self.config.server_queue_high_water
+    }
+
+    /// Wait until the language server queue is below `server_queue_low_water`
+    /// if it reached `server_queue_high_water`
+    ///
+    /// Clients hold their messages back in the meantime, the editors notice
+    /// the backpressure once the socket buffers are full.
+    pub async fn server_queue_room(&self) {
+        if !self.server_queue_full() {
+            return;
+        }
+        loop {
+            let drained = self.server_queue_drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+            if self.queued_to_server() <= self.config.server_queue_low_water {
+                break;
+            }
+            drained.await;
+        }
+    }
+
     /// Send a message to the language server channel
+    ///
+    /// While the language server is restarting the messages wait in the
+    /// channel until it's ready. The restart can take a while so we don't wait
+    /// for room in the channel, if it's full an error is returned and the
+    /// client should be disconnected.
     pub async fn send_message(&self, message: Message) -> Result<(), SendError<Message>> {
-        self.server.send(message).await
+        self.trace_message(ext::TraceDirection::ToServer, &message);
+        if self.running.load(Ordering::Relaxed) {
+            return self.server.send(message).await;
+        }
+        match self.server.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(message)) => {
+                error!("too many messages waiting for the language server to restart");
+                Err(SendError(message))
+            }
+            Err(TrySendError::Closed(message)) => Err(SendError(message)),
+        }
+    }
+
+    /// Send a server request to the clients selected by the
+    /// `server_request_routes` or `server_requests` option and remember
+    /// they're expected to respond
+    ///
+    /// If no client is connected the server gets an error response right away.
+    async fn forward_server_request(&self, clients: &HashMap<usize, ClientData>, mut req: Request) {
+        let first = || {
+            clients
+                .values()
+                .min_by_key(|client| client.id())
+                .into_iter()
+                .collect()
+        };
+        let route = self
+            .config
+            .server_request_routes
+            .get(&req.method)
+            .unwrap_or(&self.config.server_requests);
+        let targets = match route {
+            ServerRequests::First => first(),
+            ServerRequests::Broadcast => clients.values().collect::<Vec<_>>(),
+            ServerRequests::Initiator => match initiating_client(clients) {
+                Some(client) => vec![client],
+                None => {
+                    warn!(
+                        method = req.method,
+                        "can't tell which client the request is for, sending it to the first client"
+                    );
+                    first()
+                }
+            },
+            ServerRequests::Drop => {
+                debug!(?req, "dropping server request");
+                let _ = self
+                    .send_message(request_failed(req.id, "request dropped by ra-multiplex"))
+                    .await;
+                return;
+            }
+        };
+        if targets.is_empty() {
+            debug!(?req, "no client to answer server request");
+            let _ = self
+                .send_message(request_failed(req.id, "no client connected"))
+                .await;
+            return;
+        }
+
+        debug!(?req, clients = targets.len(), "forwarding server request");
+        let pending = PendingServerRequest {
+            method: req.method.clone(),
+            clients: targets.iter().map(|client| client.id()).collect(),
+            started: Instant::now(),
+        };
+        self.server_requests
+            .lock()
+            .await
+            .insert(req.id.clone(), pending);
+        req.id = req.id.tag(Tag::Forward);
+        for client in targets {
+            client.send_message_nowait(req.clone().into());
+        }
+    }
+
+    /// Forward a client response to a server request
+    ///
+    /// Only the first response is forwarded, later responses from other
+    /// clients are dropped.
+    pub async fn respond_to_server(
+        &self,
+        client_id: usize,
+        response: Message,
+    ) -> Result<(), SendError<Message>> {
+        let Some(id) = response.id() else {
+            return Ok(());
+        };
+        let mut server_requests = self.server_requests.lock().await;
+        match server_requests.entry(id.clone()) {
+            Entry::Occupied(e) if e.get().clients.contains(&client_id) => {
+                debug!(method = e.get().method, "client answered server request");
+                e.remove();
+            }
+            _ => {
+                debug!(
+                    ?id,
+                    "ignoring response to an already answered server request"
+                );
+                return Ok(());
+            }
+        }
+        drop(server_requests);
+        self.send_message(response).await
+    }
+
+    /// Answer all pending client requests with an error
+    ///
+    /// The language server exited or was replaced and won't answer them, a
+    /// new server doesn't know about them either.
+    async fn fail_pending_requests(&self, code: i64, message: &str) {
+        for (client_id, client) in self.clients.lock().await.iter_mut() {
+            for (id, request) in client.requests.drain() {
+                debug!(
+                    client_id,
+                    ?id,
+                    method = request.method,
+                    "failing request of the old language server"
+                );
+                client
+                    .client
+                    .send_message_nowait(Message::ResponseError(ResponseError {
+                        jsonrpc: Version,
+                        error: jsonrpc::Error {
+                            code,
+                            message: message.into(),
+                            data: None,
+                        },
+                        id: Some(id),
+                    }));
+            }
+        }
+    }
+
+    /// Whether a client request is still waiting for a response
+    pub async fn has_pending_requests(&self, client_id: usize) -> bool {
+        if let Some(queue) = &self.request_queue {
+            if queue.waiting.lock().await.requests.contains_key(&client_id) {
+                return true;
+            }
+        }
+        self.clients
+            .lock()
+            .await
+            .get(&client_id)
+            .is_some_and(|client| !client.requests.is_empty())
+    }
+
+    /// Send a client request to the language server and remember it's waiting
+    /// for a response
+    ///
+    /// With `max_in_flight_requests` reached the request is queued instead and
+    /// sent by `request_queue_task` once the server finished another one.
+    pub async fn send_request(
+        &self,
+        client_id: usize,
+        req: Request,
+    ) -> Result<(), SendError<Message>> {
+        let permit = match &self.request_queue {
+            Some(queue) => {
+                let mut waiting = queue.waiting.lock().await;
+                // Requests don't overtake the ones already waiting.
+                let permit = if waiting.is_empty() {
+                    queue.permits.clone().try_acquire_owned().ok()
+                } else {
+                    None
+                };
+                if permit.is_none() {
+                    debug!(
+                        client_id,
+                        method = req.method,
+                        "waiting for the server to finish a request"
+                    );
+                    waiting.push(client_id, req);
+                    queue.request_waiting.notify_one();
+                    return Ok(());
+                }
+                permit
+            }
+            None => None,
+        };
+        self.forward_request(client_id, req, permit).await
+    }
+
+    async fn forward_request(
+        &self,
+        client_id: usize,
+        mut req: Request,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), SendError<Message>> {
+        if let Some(client) = self.clients.lock().await.get_mut(&client_id) {
+            let request = PendingRequest {
+                method: req.method.clone(),
+                started: Instant::now(),
+                _permit: permit,
+            };
+            client.requests.insert(req.id.clone(), request);
+        }
+        req.id = self.server_request_id(client_id, req.id);
+        tag_progress_tokens(&mut req.params, client_id);
+        self.send_message(req.into()).await
+    }
+
+    /// ID of a client request as the language server sees it
+    ///
+    /// Tagged with the client ID so responses can be routed back, unless
+    /// `rewrite_request_ids` is disabled.
+    fn server_request_id(&self, client_id: usize, id: RequestId) -> RequestId {
+        if self.config.rewrite_request_ids {
+            id.tag(Tag::ClientId(client_id))
+        } else {
+            id
+        }
+    }
+
+    /// Find the `initialize` params of a new client which differ from the ones
+    /// the language server was initialized with
+    ///
+    /// Returns the paths of the differing keys like `rootUri` or
+    /// `capabilities.window.workDoneProgress`. Capabilities are only reported
+    /// if the client supports them and the server wasn't told about them, a
+    /// less capable client is fine.
+    pub fn initialize_conflicts(&self, init_params: &lsp::InitializeParams) -> Vec<String> {
+        let negotiated = &self.init_req_params;
+        let mut conflicts = Vec::new();
+        if init_params.root_uri != negotiated.root_uri {
+            conflicts.push("rootUri".to_owned());
+        }
+        fn options(params: &lsp::InitializeParams) -> Option<&serde_json::Map<String, Value>> {
+            Some(&params.initialization_options.as_ref()?.other_options)
+        }
+        let empty = serde_json::Map::new();
+        let negotiated_options = options(negotiated).unwrap_or(&empty);
+        let client_options = options(init_params).unwrap_or(&empty);
+        let mut keys = negotiated_options
+            .keys()
+            .chain(client_options.keys())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            if negotiated_options.get(key) != client_options.get(key) {
+                conflicts.push(format!("initializationOptions.{key}"));
+            }
+        }
+        let missing = capabilities::missing(
+            negotiated.capabilities.as_ref().unwrap_or(&Value::Null),
+            init_params.capabilities.as_ref().unwrap_or(&Value::Null),
+        );
+        conflicts.extend(
+            missing
+                .into_iter()
+                .map(|path| format!("capabilities.{path}")),
+        );
+        conflicts
     }
 
     /// Save registered capabilities to allow later replaying them to new clients
-    async fn register_capabilities(&self, params: Value) -> Result<()> {
+    ///
+    /// Returns the registrations clients don't know about yet, a restarted
+    /// server will usually register the same capabilities again.
+    async fn register_capabilities(&self, params: Value) -> Result<lsp::RegistrationParams> {
         let params =
             serde_json::from_value::<lsp::RegistrationParams>(params).context("parsing params")?;
 
         let mut dyn_capabilities = self.dynamic_capabilities.lock().await;
+        let mut new_registrations = Vec::new();
         for reg in params.registrations {
+            if !dyn_capabilities.contains_key(&reg.id) {
+                new_registrations.push(reg.clone());
+            }
             dyn_capabilities.insert(reg.id.clone(), reg);
         }
 
-        Ok(())
+        Ok(lsp::RegistrationParams {
+            registrations: new_registrations,
+        })
     }
 
     /// Remove cached capability registration to stop replaying them to new clients
@@ -229,6 +1334,11 @@ impl Instance {
             .insert(uri.clone());
 
         if send_notification {
+            self.documents
+                .lock()
+                .await
+                .insert(uri.clone(), params.text_document.clone());
+
             let notif = Notification {
                 jsonrpc: Version,
                 method: "textDocument/didOpen".into(),
@@ -241,6 +1351,31 @@ impl Instance {
         Ok(())
     }
 
+    /// Handle `textDocument/didChange` client notification
+    ///
+    /// Keeps our copy of the document text up to date, the notification is
+    /// forwarded unchanged.
+    pub async fn change_file(&self, params: Value) -> Result<()> {
+        let params = serde_json::from_value::<lsp::DidChangeTextDocumentParams>(params)
+            .context("parsing params")?;
+        let uri = &params.text_document.uri;
+
+        let mut documents = self.documents.lock().await;
+        let document = documents.get_mut(uri).context("file is not open")?;
+        for change in params.content_changes {
+            if let Err(err) =
+                document::apply_change(&mut document.text, change, self.position_encoding)
+            {
+                // Our copy is useless now, don't try reopening it later.
+                documents.remove(uri);
+                return Err(err).context("applying change");
+            }
+        }
+        document.version = params.text_document.version;
+
+        Ok(())
+    }
+
     /// Handle `textDocument/didClose` client notification
     pub async fn close_file(&self, client_id: usize, params: Value) -> Result<()> {
         let params = serde_json::from_value::<lsp::DidCloseTextDocumentParams>(params)
@@ -277,6 +1412,8 @@ impl Instance {
             }
 
             if send_notification {
+                self.documents.lock().await.remove(&uri);
+
                 let params = lsp::DidCloseTextDocumentParams {
                     text_document: lsp::TextDocumentIdentifier { uri },
                 };
@@ -293,6 +1430,102 @@ impl Instance {
         Ok(())
     }
 
+    /// Shut down the language server, even if it's pinned or not idle yet
+    pub fn close(&self) {
+        self.close.notify_one();
+    }
+
+    /// Replace the language server with a new process, like after it was
+    /// updated
+    ///
+    /// The new server is initialized while the old one keeps serving the
+    /// clients, they only wait for the documents to be opened again. Returns
+    /// the PID of the new server.
+    pub async fn handover(&self) -> Result<u32> {
+        ensure!(
+            self.running.load(Ordering::Relaxed),
+            "language server isn't running"
+        );
+        info!("starting language server to hand the instance over to");
+        let server = start_server(&self.key, self.init_req_params.clone(), &self.config)
+            .await
+            .context("starting new language server")?;
+        let (done, result) = oneshot::channel();
+        self.handovers
+            .send(Handover { server, done })
+            .await
+            .ok()
+            .context("instance is shutting down")?;
+        result.await.context("instance is shutting down")?
+    }
+
+    /// Disconnect all clients and shut down the language server
+    pub async fn kill(&self) {
+        info!(path = ?self.key.workspace_root, "killing instance");
+        self.close.notify_one();
+        for client in self.clients.lock().await.values() {
+            client.disconnect();
+        }
+    }
+
+    /// List the requests waiting for a response, at most `limit` of the
+    /// oldest ones in each direction
+    pub async fn id_map(&self, limit: usize) -> ext::IdMap {
+        let clients = self.clients.lock().await;
+        let mut client_requests = clients
+            .iter()
+            .flat_map(|(&client_id, client)| {
+                client
+                    .requests
+                    .iter()
+                    .map(move |(id, request)| (request.started, client_id, id, &request.method))
+            })
+            .collect::<Vec<_>>();
+        let client_count = client_requests.len();
+        client_requests.sort_by_key(|&(started, ..)| started);
+        let oldest_client_requests = client_requests
+            .into_iter()
+            .take(limit)
+            .map(
+                |(started, client_id, id, method)| ext::PendingClientRequest {
+                    client_id,
+                    id: id.clone(),
+                    server_id: self.server_request_id(client_id, id.clone()),
+                    method: method.clone(),
+                    age: started.elapsed().as_millis() as u64,
+                },
+            )
+            .collect();
+        drop(clients);
+
+        let server_requests = self.server_requests.lock().await;
+        let mut pending = server_requests.iter().collect::<Vec<_>>();
+        pending.sort_by_key(|(_, request)| request.started);
+        let oldest_server_requests = pending
+            .into_iter()
+            .take(limit)
+            .map(|(id, request)| {
+                let mut clients = request.clients.iter().copied().collect::<Vec<_>>();
+                clients.sort_unstable();
+                ext::PendingServerRequest {
+                    id: id.clone(),
+                    method: request.method.clone(),
+                    clients,
+                    age: request.started.elapsed().as_millis() as u64,
+                }
+            })
+            .collect();
+
+        ext::IdMap {
+            server: self.key.server.clone(),
+            workspace_root: self.key.workspace_root.clone(),
+            client_requests: client_count,
+            server_requests: server_requests.len(),
+            oldest_client_requests,
+            oldest_server_requests,
+        }
+    }
+
     pub fn get_status(&self) -> ext::Instance {
         let clients = self
             .clients
@@ -309,23 +1542,59 @@ impl Instance {
             .collect();
 
         ext::Instance {
-            pid: self.pid,
+            pid: self.pid.load(Ordering::Relaxed),
             server: self.key.server.clone(),
             args: self.key.args.clone(),
             env: self.key.env.clone(),
             workspace_root: self.key.workspace_root.clone(),
+            started: self.started,
             last_used: self.last_used.load(Ordering::Relaxed),
+            pinned: self.pinned.load(Ordering::Relaxed),
+            progress: self.progress.blocking_lock().values().cloned().collect(),
+            queued_messages: self.queued_to_server(),
             clients,
             registered_dyn_capabilities,
         }
     }
 }
 
-pub struct InstanceMap(HashMap<InstanceKey, Arc<Instance>>);
+/// Current values of the per-instance metrics
+pub struct InstanceGauges {
+    pub workspace_root: String,
+    pub server: String,
+    pub clients: usize,
+    pub pending_requests: usize,
+    /// Language server requests waiting for a response from the clients
+    pub pending_server_requests: usize,
+    /// Client messages waiting to be written to the language server
+    pub queued_to_server: usize,
+    /// Server messages waiting to be written to the clients, summed up
+    pub queued_to_clients: usize,
+}
+
+pub struct InstanceMap {
+    instances: HashMap<InstanceKey, Arc<Instance>>,
+    /// Instances whose language server is still being initialized
+    ///
+    /// The sender is dropped once the instance is inserted or failed to start.
+    starting: HashMap<InstanceKey, watch::Receiver<()>>,
+    /// Deadline of a requested drain, `Some(None)` waits for the clients
+    /// indefinitely
+    drain: Option<Option<Instant>>,
+    /// Wakes up the server accept loop when a drain is requested
+    drain_requested: Arc<Notify>,
+    config: Arc<Config>,
+}
 
 impl InstanceMap {
     pub async fn new(config: &Config) -> Arc<Mutex<Self>> {
-        let instance_map = Arc::new(Mutex::new(InstanceMap(HashMap::new())));
+        let instance_map = Arc::new(Mutex::new(InstanceMap {
+            instances: HashMap::new(),
+            starting: HashMap::new(),
+            drain: None,
+            drain_requested: Arc::new(Notify::new()),
+            config: Arc::new(config.clone()),
+        }));
         task::spawn(gc_task(
             instance_map.clone(),
             config.gc_interval,
@@ -334,23 +1603,228 @@ impl InstanceMap {
         instance_map
     }
 
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    /// Refuse new clients from now on and wake up the server to wait for the
+    /// connected ones
+    ///
+    /// Draining again replaces the timeout.
+    pub fn drain(&mut self, timeout: Option<Duration>) {
+        self.drain = Some(timeout.map(|timeout| Instant::now() + timeout));
+        self.drain_requested.notify_one();
+    }
+
+    pub fn draining(&self) -> bool {
+        self.drain.is_some()
+    }
+
+    /// Deadline of the current drain, see [`InstanceMap::drain`]
+    pub fn drain_deadline(&self) -> Option<Instant> {
+        self.drain.flatten()
+    }
+
+    /// Notified when a drain is requested
+    pub fn drain_requested(&self) -> Arc<Notify> {
+        self.drain_requested.clone()
+    }
+
     /// Finds an instance with the longest path such as
     /// `cwd.starts_with(workspace_root)` is true
     pub fn get_by_cwd(&self, cwd: &str) -> Option<&Instance> {
-        self.0
+        self.instances
             .iter()
             .filter(|(key, _)| Path::new(cwd).starts_with(&key.workspace_root))
             .max_by_key(|(key, _)| key.workspace_root.len())
             .map(|(_, inst)| inst.deref())
     }
 
+    /// Find the innermost `workspace_root` of any instance containing `path`
+    pub fn find_workspace_root(&self, path: &str) -> Option<String> {
+        self.instances
+            .keys()
+            .filter(|key| Path::new(path).starts_with(&key.workspace_root))
+            .max_by_key(|key| key.workspace_root.len())
+            .map(|key| key.workspace_root.clone())
+    }
+
+    pub fn instances(&self) -> Vec<Arc<Instance>> {
+        self.instances.values().cloned().collect()
+    }
+
+    /// Find all instances with this `workspace_root`
+    pub fn get_by_workspace_root(&self, workspace_root: &str) -> Vec<Arc<Instance>> {
+        self.instances
+            .iter()
+            .filter(|(key, _)| key.workspace_root == workspace_root)
+            .map(|(_, instance)| instance.clone())
+            .collect()
+    }
+
+    /// Remove all instances with this `workspace_root` from the map
+    ///
+    /// New clients will spawn a new instance, the removed instances should be
+    /// shut down with [`Instance::kill`].
+    pub fn remove_by_workspace_root(&mut self, workspace_root: &str) -> Vec<Arc<Instance>> {
+        let keys = self
+            .instances
+            .keys()
+            .filter(|key| key.workspace_root == workspace_root)
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.iter()
+            .filter_map(|key| self.instances.remove(key))
+            .collect()
+    }
+
+    /// Shared instances to start again after a restart, see [`crate::state`]
+    pub fn saved_instances(&self) -> Vec<state::SavedInstance> {
+        self.instances
+            .values()
+            .filter(|instance| instance.key.private.is_none())
+            .map(|instance| {
+                let key = instance.key.clone();
+                let mut init_params = instance.init_req_params.clone();
+                // The client that started it is long gone.
+                init_params.process_id = None;
+                state::SavedInstance {
+                    server: key.server,
+                    args: key.args,
+                    env: key.env,
+                    workspace_root: key.workspace_root,
+                    init_params,
+                }
+            })
+            .collect()
+    }
+
+    /// Shut down all instances and wait for them to exit
+    ///
+    /// Instances which don't exit within the grace period are killed when the
+    /// runtime is dropped.
+    pub async fn shutdown(instance_map: &Mutex<InstanceMap>) {
+        for instance in instance_map.lock().await.instances.values() {
+            instance.close.notify_one();
+        }
+
+        let wait = async {
+            while !instance_map.lock().await.instances.is_empty() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        // The shutdown handshake takes at most two timeouts then the instance
+        // is killed, give it a moment more to actually exit.
+        let grace_period = 2 * SHUTDOWN_TIMEOUT + Duration::from_secs(1);
+        if tokio::time::timeout(grace_period, wait).await.is_err() {
+            warn!("some instances didn't exit in time");
+        }
+    }
+
+    /// Check that the map and all instances can be locked in time
+    pub async fn health(instance_map: &Mutex<InstanceMap>) -> ext::HealthResponse {
+        let Ok(map) = tokio::time::timeout(HEALTH_TIMEOUT, instance_map.lock()).await else {
+            warn!("health check timed out locking the instance map");
+            return ext::HealthResponse {
+                healthy: false,
+                instances: Vec::new(),
+            };
+        };
+
+        let mut instances = Vec::new();
+        for instance in map.instances.values() {
+            let responsive = tokio::time::timeout(HEALTH_TIMEOUT, instance.clients.lock())
+                .await
+                .is_ok();
+            instances.push(ext::InstanceHealth {
+                pid: instance.pid.load(Ordering::Relaxed),
+                workspace_root: instance.key.workspace_root.clone(),
+                running: instance.running.load(Ordering::Relaxed),
+                responsive,
+            });
+        }
+        ext::HealthResponse {
+            healthy: instances
+                .iter()
+                .all(|instance| instance.running && instance.responsive),
+            instances,
+        }
+    }
+
+    pub async fn gauges(&self) -> Vec<InstanceGauges> {
+        let mut gauges = Vec::new();
+        for (key, instance) in &self.instances {
+            let clients = instance.clients.lock().await;
+            gauges.push(InstanceGauges {
+                workspace_root: key.workspace_root.clone(),
+                server: key.server.clone(),
+                clients: clients.len(),
+                pending_requests: clients.values().map(|client| client.requests.len()).sum(),
+                pending_server_requests: instance.server_requests.lock().await.len(),
+                queued_to_server: instance.queued_to_server(),
+                queued_to_clients: clients.values().map(|client| client.queued()).sum(),
+            });
+        }
+        gauges
+    }
+
     pub fn get_status(&self) -> ext::StatusResponse {
         ext::StatusResponse {
             instances: self
-                .0
+                .instances
                 .values()
                 .map(|instance| instance.get_status())
                 .collect(),
+            draining: self.draining(),
+        }
+    }
+}
+
+/// Find the client whose most recent pending request could make the server
+/// send `workspace/applyEdit`
+fn initiating_client(clients: &HashMap<usize, ClientData>) -> Option<&ClientData> {
+    const METHODS: &[&str] = &[
+        "workspace/executeCommand",
+        "textDocument/codeAction",
+        "codeAction/resolve",
+    ];
+    clients
+        .values()
+        .filter_map(|client| {
+            let started = client
+                .requests
+                .values()
+                .filter(|request| METHODS.contains(&request.method.as_str()))
+                .map(|request| request.started)
+                .max()?;
+            Some((started, client))
+        })
+        .max_by_key(|(started, _)| *started)
+        .map(|(_, client)| client)
+}
+
+/// Check whether any client added the workspace folder
+fn has_workspace_folder(clients: &HashMap<usize, ClientData>, uri: &str) -> bool {
+    clients
+        .values()
+        .any(|client| client.workspace_folders.contains_key(uri))
+}
+
+/// Namespace progress tokens provided by the client in request params
+///
+/// Clients pick these tokens themselves so they're likely to collide, tagging
+/// them like request IDs lets us route `$/progress` back to the right client.
+fn tag_progress_tokens(params: &mut Value, client_id: usize) {
+    let Value::Object(params) = params else {
+        return;
+    };
+    for key in ["workDoneToken", "partialResultToken"] {
+        let Some(token) = params.get_mut(key) else {
+            continue;
+        };
+        match RequestId::deserialize(&*token) {
+            Ok(id) => *token = serde_json::to_value(id.tag(Tag::ClientId(client_id))).unwrap(),
+            Err(err) => warn!(?err, ?token, "invalid progress token in `{key}`"),
         }
     }
 }
@@ -366,16 +1840,24 @@ async fn gc_task(
     loop {
         interval.tick().await;
 
-        for (key, instance) in &instance_map.lock().await.0 {
+        for (key, instance) in &instance_map.lock().await.instances {
             let clients = instance.clients.lock().await;
 
             let idle = instance.idle();
             debug!(path = ?key.workspace_root, idle, clients = clients.len(), "check instance");
+            if instance.pinned.load(Ordering::Relaxed) {
+                continue;
+            }
 
+            let instance_timeout = instance
+                .instance_timeout
+                .lock()
+                .await
+                .unwrap_or(instance_timeout);
             if let Some(instance_timeout) = instance_timeout {
                 // Close timed out instance
                 if idle > i64::from(instance_timeout) && clients.is_empty() {
-                    info!(pid = instance.pid, path = ?key.workspace_root, idle, "instance timed out");
+                    info!(pid = instance.pid.load(Ordering::Relaxed), path = ?key.workspace_root, idle, "instance timed out");
                     instance.close.notify_one();
                 }
             }
@@ -383,113 +1865,539 @@ async fn gc_task(
     }
 }
 
+/// `instance_timeout` override in the project config of a workspace
+async fn project_instance_timeout(workspace_root: &str) -> Option<Option<u32>> {
+    let workspace_root = PathBuf::from(workspace_root);
+    match task::spawn_blocking(move || ProjectConfig::load(&workspace_root))
+        .await
+        .unwrap()
+    {
+        Ok(project) => project.instance_timeout,
+        Err(err) => {
+            warn!(?err, "ignoring project config instance_timeout");
+            None
+        }
+    }
+}
+
 /// Find existing or spawn a new language server instance
 ///
 /// The instance is looked up based on `instance_key`. If an existing one is
 /// found then it's returned and `init_req_params` are discarded. If it's
 /// not found a new instance is spawned and initialized using the provided
 /// `init_req_params`, this insance is then inserted into the map and returned.
+///
+/// The map isn't locked while the language server initializes. Clients
+/// connecting to the same instance in the meantime wait for the first one,
+/// they only get the instance after its handshake is done and their messages
+/// wait in the client socket until then.
 pub async fn get_or_spawn(
     map: Arc<Mutex<InstanceMap>>,
     key: InstanceKey,
     init_req_params: lsp::InitializeParams,
 ) -> Result<Arc<Instance>> {
-    // We have locked a clone of an Arc of the map, we can assume noone else
-    // tries to spawn the same instance again. But we have to make sure `spawn`
-    // doesn't try to lock its copy as well. This is a bit unfortunate code
-    // organization but we want to have spawn in a separate tracing context and
-    // we want to include `wait_task` in it as well in it as well
-    match map.clone().lock().await.0.entry(key.clone()) {
-        Entry::Occupied(e) => {
+    let (ready, config) = loop {
+        let mut map_lock = map.lock().await;
+        let config = map_lock.config.clone();
+        ensure_allowed(&key.server, &config)?;
+        if let Some(instance) = map_lock.instances.get(&key) {
             info!("reusing language server instance");
-            Ok(e.get().clone())
+            return Ok(instance.clone());
         }
-        Entry::Vacant(e) => {
-            let instance = spawn(key, init_req_params, map)
-                .await
-                .context("spawning instance")?;
-            e.insert(instance.clone());
-            Ok(instance)
+        match map_lock.starting.get(&key) {
+            // Spawning clients which went away leave a closed channel behind,
+            // the next client takes over.
+            Some(starting) if starting.has_changed().is_ok() => {
+                let mut starting = starting.clone();
+                drop(map_lock);
+                debug!("waiting for language server instance to start");
+                // Only ever returns when the sender is dropped.
+                _ = starting.changed().await;
+            }
+            _ => {
+                let (ready, starting) = watch::channel(());
+                map_lock.starting.insert(key.clone(), starting);
+                break (ready, config);
+            }
         }
-    }
+    };
+
+    let result = spawn(key.clone(), init_req_params, map.clone(), config).await;
+
+    let mut map_lock = map.lock().await;
+    map_lock.starting.remove(&key);
+    let instance = result.context("spawning instance")?;
+    // The child's `wait_task` couldn't find it in the map to remove it.
+    ensure!(
+        instance.running.load(Ordering::Relaxed),
+        "language server exited during initialization"
+    );
+    map_lock.instances.insert(key, instance.clone());
+    drop(ready);
+    Ok(instance)
 }
 
-#[instrument(name = "instance", fields(pid = field::Empty), skip_all, parent = None)]
+#[instrument(
+    name = "instance",
+    fields(pid = field::Empty, workspace = %key.workspace_root),
+    skip_all,
+    parent = None
+)]
 async fn spawn(
     key: InstanceKey,
-    init_req_params: lsp::InitializeParams,
-    // Caller `get_or_spawn` is holding a lock to the map, we must not try to
-    // lock it within this function to not cause deadlock, only spawned tasks
-    // are allowed to lock it again.
+    mut init_req_params: lsp::InitializeParams,
     map: Arc<Mutex<InstanceMap>>,
+    config: Arc<Config>,
 ) -> Result<Arc<Instance>> {
-    let mut child = Command::new(&key.server)
+    if let Some(overrides) = &config.capabilities_override {
+        // Kept in the stored params too so restarts use them and clients
+        // aren't warned about capabilities the override added.
+        let capabilities = init_req_params.capabilities.get_or_insert(Value::Null);
+        capabilities::merge(capabilities, overrides);
+    }
+    let ServerProcess {
+        child,
+        pid,
+        reader,
+        writer,
+        init_result,
+    } = start_server(&key, init_req_params.clone(), &config).await?;
+
+    let queue_size = config.server_queue_high_water + SERVER_QUEUE_HEADROOM;
+    let (message_writer, rx) = mpsc::channel(queue_size);
+    let server_queue_drained = Arc::new(Notify::new());
+    let (stdin_writers, stdin_writers_rx) = mpsc::channel(1);
+    let stdin = ServerStdin {
+        writer,
+        reopened: Reopened::default(),
+        generation: 0,
+    };
+    stdin_writers
+        .send(StdinWriter::Server(stdin))
+        .await
+        .unwrap();
+    let (write_errors, write_errors_rx) = mpsc::unbounded_channel();
+    let (handovers, handovers_rx) = mpsc::channel(1);
+    let instance_timeout = project_instance_timeout(&key.workspace_root).await;
+
+    let position_encoding = PositionEncoding::from_capabilities(&init_result.capabilities);
+    // No permits at all would hold back every request forever.
+    let request_queue = config
+        .max_in_flight_requests
+        .filter(|&max| max > 0)
+        .map(|max| Arc::new(RequestQueue::new(max)));
+    let server_trace = init_req_params.trace.unwrap_or_default();
+    let telemetry = config.telemetry_file.clone().map(|path| {
+        let (sender, receiver) = mpsc::channel(TELEMETRY_QUEUE_SIZE);
+        task::spawn(telemetry_task(path, receiver).in_current_span());
+        sender
+    });
+    let instance = Arc::new(Instance {
+        key,
+        config,
+        pid: AtomicU32::new(pid),
+        running: AtomicBool::new(true),
+        init_req_params,
+        init_result,
+        position_encoding,
+        server: message_writer,
+        server_queue_drained: server_queue_drained.clone(),
+        handovers,
+        clients: Mutex::default(),
+        documents: Mutex::default(),
+        server_requests: Mutex::default(),
+        request_queue: request_queue.clone(),
+        server_progress_tokens: Mutex::default(),
+        progress: Mutex::default(),
+        dynamic_capabilities: Mutex::default(),
+        configuration_changes: AtomicU64::new(0),
+        server_trace: Mutex::new(server_trace),
+        close: Notify::new(),
+        shutdown: Notify::new(),
+        stdout_closed: Notify::new(),
+        watchdog_pongs: watch::Sender::new(0),
+        unresponsive: Notify::new(),
+        trace: broadcast::Sender::new(TRACE_QUEUE_SIZE),
+        telemetry,
+        started: utc_now(),
+        last_used: AtomicI64::new(utc_now()),
+        pinned: AtomicBool::new(false),
+        warming: AtomicBool::new(false),
+        instance_timeout: Mutex::new(instance_timeout),
+    });
+
+    let stdout = task::spawn(stdout_task(instance.clone(), reader).in_current_span());
+    let low_water = instance.config.server_queue_low_water;
+    task::spawn(
+        stdin_task(
+            rx,
+            stdin_writers_rx,
+            write_errors,
+            (server_queue_drained, low_water),
+        )
+        .in_current_span(),
+    );
+
+    let server = RunningServer {
+        child,
+        stdout,
+        generation: 0,
+    };
+    task::spawn(
+        wait_task(
+            instance.clone(),
+            map,
+            server,
+            stdin_writers,
+            write_errors_rx,
+            handovers_rx,
+        )
+        .in_current_span(),
+    );
+    if let Some(interval) = instance.config.watchdog_interval {
+        let interval = Duration::from_secs(interval.into());
+        let threshold = instance.config.watchdog_threshold;
+        task::spawn(
+            watchdog_task(Arc::downgrade(&instance), interval, threshold).in_current_span(),
+        );
+    }
+    if let Some(queue) = request_queue {
+        task::spawn(request_queue_task(Arc::downgrade(&instance), queue).in_current_span());
+    }
+
+    Ok(instance)
+}
+
+/// Language server process after the `initialize` handshake
+struct ServerProcess {
+    child: Child,
+    pid: u32,
+    reader: LspReader<BufReader<ChildStdout>>,
+    writer: LspWriter<ChildStdin>,
+    init_result: lsp::InitializeResult,
+}
+
+/// Start the language server process and initialize it
+async fn start_server(
+    key: &InstanceKey,
+    init_req_params: lsp::InitializeParams,
+    config: &Config,
+) -> Result<ServerProcess> {
+    let mut child = spawn_child(key, config)?;
+    let pid = child.id().context("child exited early, couldn't get PID")?;
+
+    let stdout = child.stdout.take().unwrap();
+    let mut reader = LspReader::new(BufReader::new(stdout), "server")
+        .max_content_length(config.max_message_size);
+
+    let stdin = child.stdin.take().unwrap();
+    let mut writer = LspWriter::new(stdin, "server").count_bytes(&metrics::BYTES_TO_SERVERS);
+
+    let handshake = tokio::time::timeout(
+        INITIALIZE_TIMEOUT,
+        initialize_handshake(init_req_params, &mut reader, &mut writer),
+    )
+    .await
+    .context("server handshake timed out")?;
+    let init_result = match handshake {
+        Ok(init_result) => init_result,
+        Err(err) => {
+            let err = err.context("server handshake");
+            // Servers which exit right away close their output before
+            // answering, like when they don't understand the arguments.
+            if let Ok(Ok(status)) = tokio::time::timeout(EARLY_EXIT_TIMEOUT, child.wait()).await {
+                return Err(err.context(StartError {
+                    reason: ext::RejectReason::ServerExited,
+                    message: format!(
+                        "language server {:?} exited immediately ({status})",
+                        key.server
+                    ),
+                }));
+            }
+            return Err(err);
+        }
+    };
+    // Write in the same format the server uses.
+    writer.set_content_type(reader.content_type());
+
+    info!("initialized server");
+
+    Ok(ServerProcess {
+        child,
+        pid,
+        reader,
+        writer,
+        init_result,
+    })
+}
+
+/// Refuse to run servers which aren't in `allowed_servers`
+pub fn ensure_allowed(server: &str, config: &Config) -> Result<()> {
+    if let Some(allowed_servers) = &config.allowed_servers {
+        ensure!(
+            allowed_servers.contains(server),
+            "language server {server:?} is not in `allowed_servers`",
+        );
+    }
+    Ok(())
+}
+
+/// Spawn the language server process
+///
+/// Its stdin and stdout are piped, stderr is logged.
+pub fn spawn_child(key: &InstanceKey, config: &Config) -> Result<Child> {
+    let mut command = Command::new(&key.server);
+    if config.server_clear_env {
+        command.env_clear();
+    }
+    let mut child = command
         .args(&key.args)
         .envs(&key.env)
         .current_dir(&key.workspace_root)
+        // Don't leave orphaned servers behind if ra-multiplex exits before they do
+        .kill_on_drop(true)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
-        .with_context(|| {
-            let InstanceKey {
-                server,
-                args,
-                env,
-                workspace_root,
-            } = &key;
-            let path = env
-                .get("PATH")
-                .map(<_>::to_owned)
-                // Display PATH from our environment Command will if none was
-                // passed from the client environment.
-                .or_else(|| env::var("PATH").ok())
-                .unwrap_or_default();
-            format!(
-                "spawning language server: server={server:?}, args={args:?}, \
-                cwd={workspace_root:?}, path={path:?}, env={env:?}",
-            )
+        .map_err(|err| {
+            let server = &key.server;
+            let (reason, message) = match err.kind() {
+                // The working directory not existing looks the same.
+                ErrorKind::NotFound if !Path::new(&key.workspace_root).is_dir() => (
+                    ext::RejectReason::ServerFailed,
+                    format!("workspace root {:?} doesn't exist", key.workspace_root),
+                ),
+                ErrorKind::NotFound if Path::new(server).components().count() > 1 => (
+                    ext::RejectReason::ServerNotFound,
+                    format!("language server not found at {server:?}"),
+                ),
+                ErrorKind::NotFound => (
+                    ext::RejectReason::ServerNotFound,
+                    format!("language server {server:?} not found on PATH"),
+                ),
+                ErrorKind::PermissionDenied => (
+                    ext::RejectReason::ServerPermissionDenied,
+                    format!("permission denied running language server {server:?}"),
+                ),
+                _ => (
+                    ext::RejectReason::ServerFailed,
+                    format!("cannot run language server {server:?}"),
+                ),
+            };
+            let err = anyhow::Error::from(err).context(spawn_details(key));
+            err.context(StartError { reason, message })
         })?;
 
-    let pid = child.id().context("child exited early, couldn't get PID")?;
-    tracing::Span::current().record("pid", pid);
+    if let Some(pid) = child.id() {
+        tracing::Span::current().record("pid", pid);
+    }
 
     info!(server = ?key.server, args = ?key.args, cwd = ?key.workspace_root, "spawned language server");
 
     let stderr = child.stderr.take().unwrap();
     task::spawn(stderr_task(stderr).in_current_span());
 
-    let stdout = child.stdout.take().unwrap();
-    let mut reader = LspReader::new(BufReader::new(stdout), "server");
+    Ok(child)
+}
 
-    let stdin = child.stdin.take().unwrap();
-    let mut writer = LspWriter::new(stdin, "server");
+/// Everything that went into spawning the language server, for the logs
+fn spawn_details(key: &InstanceKey) -> String {
+    let InstanceKey {
+        server,
+        args,
+        env,
+        workspace_root,
+        private: _,
+    } = key;
+    let path = env
+        .get("PATH")
+        .map(<_>::to_owned)
+        // Display PATH from our environment Command will if none was
+        // passed from the client environment.
+        .or_else(|| env::var("PATH").ok())
+        .unwrap_or_default();
+    format!(
+        "spawning language server: server={server:?}, args={args:?}, \
+        cwd={workspace_root:?}, path={path:?}, env={env:?}",
+    )
+}
 
-    let init_result = initialize_handshake(init_req_params, &mut reader, &mut writer)
+/// Start a new language server process for a crashed instance
+///
+/// The new server is initialized with the same `InitializeParams` and all
+/// documents clients have open are opened again, clients keep using the
+/// `InitializeResult` of the original server.
+async fn restart(
+    instance: &Arc<Instance>,
+    stdin_writers: &mpsc::Sender<StdinWriter>,
+    generation: usize,
+) -> Result<RunningServer> {
+    let ServerProcess {
+        child,
+        pid,
+        reader,
+        mut writer,
+        init_result: _,
+    } = start_server(
+        &instance.key,
+        instance.init_req_params.clone(),
+        &instance.config,
+    )
+    .await?;
+    instance.pid.store(pid, Ordering::Relaxed);
+    // Requests of the old server can't be answered anymore.
+    instance.server_requests.lock().await.clear();
+    instance.progress.lock().await.clear();
+    instance.running.store(true, Ordering::Relaxed);
+
+    let reopened = reopen(instance, &mut writer).await?;
+    let stdin = ServerStdin {
+        writer,
+        reopened,
+        generation,
+    };
+    stdin_writers
+        .send(StdinWriter::Server(stdin))
         .await
-        .context("server handshake")?;
+        .context("stdin task closed")?;
+    let stdout = task::spawn(stdout_task(instance.clone(), reader).in_current_span());
+
+    Ok(RunningServer {
+        child,
+        stdout,
+        generation,
+    })
+}
 
-    info!("initialized server");
+/// Open the documents and workspace folders of the clients in a new language
+/// server
+///
+/// Writes directly to the new stdin, nothing else must reach the server
+/// before the documents are open again.
+async fn reopen(instance: &Instance, writer: &mut LspWriter<ChildStdin>) -> Result<Reopened> {
+    let mut reopened = Reopened::default();
+    for document in instance.documents.lock().await.values() {
+        let params = lsp::DidOpenTextDocumentParams {
+            text_document: document.clone(),
+        };
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "textDocument/didOpen".into(),
+            params: serde_json::to_value(params).unwrap(),
+        };
+        debug!(uri = ?document.uri, "reopening file");
+        writer
+            .write_message(&notif.into())
+            .await
+            .context("reopening files")?;
+        reopened
+            .open
+            .insert(document.uri.clone(), Some(document.version));
+    }
 
-    let (message_writer, rx) = mpsc::channel(64);
+    let mut folders = HashMap::new();
+    for client in instance.clients.lock().await.values() {
+        folders.extend(client.workspace_folders.clone());
+    }
+    if !folders.is_empty() {
+        debug!(?folders, "adding workspace folders again");
+        writer
+            .write_message(&workspace_folders_notification(
+                folders.into_values().collect(),
+                Vec::new(),
+            ))
+            .await
+            .context("adding workspace folders")?;
+    }
 
-    let instance = Arc::new(Instance {
-        key,
-        pid,
-        init_result,
-        server: message_writer,
-        clients: Mutex::default(),
-        dynamic_capabilities: Mutex::default(),
-        close: Notify::new(),
-        last_used: AtomicI64::new(utc_now()),
-    });
+    let trace = *instance.server_trace.lock().await;
+    if trace != instance.init_req_params.trace.unwrap_or_default() {
+        debug!(?trace, "setting trace level again");
+        writer
+            .write_message(&set_trace_notification(trace))
+            .await
+            .context("setting trace level")?;
+    }
+    Ok(reopened)
+}
 
-    task::spawn(stdout_task(instance.clone(), reader).in_current_span());
-    task::spawn(stdin_task(rx, writer).in_current_span());
+/// Switch an instance over to the language server started by a handover
+///
+/// The messages for the server are held back while the documents are opened
+/// in the new one. Requests the old server was working on are failed with
+/// `ContentModified` so clients send them again, the old server's stdin is
+/// closed once the new one is in use.
+async fn hand_over(
+    instance: &Arc<Instance>,
+    stdin_writers: &mpsc::Sender<StdinWriter>,
+    server: ServerProcess,
+    generation: usize,
+) -> Result<RunningServer> {
+    let ServerProcess {
+        child,
+        pid,
+        reader,
+        mut writer,
+        init_result: _,
+    } = server;
+    let (hold, held) = oneshot::channel();
+    stdin_writers
+        .send(StdinWriter::Hold(Some(hold)))
+        .await
+        .context("stdin task closed")?;
+    let old_stdin = held.await.context("stdin task closed")?;
+    let reopened = match reopen(instance, &mut writer).await {
+        Ok(reopened) => reopened,
+        Err(err) => {
+            // Keep using the old server.
+            if let Some(old_stdin) = old_stdin {
+                _ = stdin_writers.send(StdinWriter::Server(old_stdin)).await;
+            }
+            return Err(err);
+        }
+    };
 
-    task::spawn(wait_task(instance.clone(), map, child).in_current_span());
+    // JSON-RPC `ContentModified`
+    instance
+        .fail_pending_requests(-32801, "language server was replaced")
+        .await;
+    instance.server_requests.lock().await.clear();
+    instance.progress.lock().await.clear();
+    instance.pid.store(pid, Ordering::Relaxed);
+    let stdin = ServerStdin {
+        writer,
+        reopened,
+        generation,
+    };
+    stdin_writers
+        .send(StdinWriter::Server(stdin))
+        .await
+        .context("stdin task closed")?;
+    let stdout = task::spawn(stdout_task(instance.clone(), reader).in_current_span());
+    drop(old_stdin);
+
+    Ok(RunningServer {
+        child,
+        stdout,
+        generation,
+    })
+}
 
-    Ok(instance)
+/// Wait for a language server replaced by a handover to exit
+///
+/// Its stdin is closed so it exits on its own, it's killed if it doesn't.
+async fn retire(mut child: Child) {
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, child.wait()).await {
+        Ok(Ok(status)) => debug!(%status, "old language server exited"),
+        Ok(Err(err)) => error!(?err, "error waiting for old language server"),
+        Err(_) => {
+            warn!("old language server didn't exit, killing it");
+            if let Err(err) = child.kill().await {
+                error!(?err, "failed to kill old language server");
+            }
+        }
+    }
 }
 
 #[instrument(skip_all)]
@@ -543,105 +2451,598 @@ async fn initialize_handshake(
 }
 
 /// Read errors from language server stderr and log them
+///
+/// Language servers use stderr for their own logs, each line is logged as a
+/// warning unless it looks like an error or a panic. Invalid UTF-8 is replaced.
 async fn stderr_task(stderr: ChildStderr) {
     let mut stderr = BufReader::new(stderr);
-    let mut buffer = String::new();
+    let mut buffer = Vec::new();
 
     loop {
         buffer.clear();
-        match stderr.read_line(&mut buffer).await {
+        match stderr.read_until(b'\n', &mut buffer).await {
             Ok(0) => {
                 // reached EOF
                 debug!("stderr closed");
                 break;
             }
             Ok(_) => {
-                let line = buffer.trim_end(); // remove trailing '\n' or possibly '\r\n'
-                error!(%line, "stderr");
+                let line = String::from_utf8_lossy(&buffer);
+                let line = line.trim_end(); // remove trailing '\n' or possibly '\r\n'
+                if is_error_line(line) {
+                    error!(%line, "stderr");
+                } else {
+                    warn!(%line, "stderr");
+                }
             }
             Err(err) => {
                 let err = anyhow::Error::from(err);
                 error!(?err, "error reading from stderr");
+                break;
+            }
+        }
+    }
+}
+
+/// Does a language server log line report an error
+fn is_error_line(line: &str) -> bool {
+    line.contains("ERROR") || line.contains("panicked at")
+}
+
+#[cfg(test)]
+#[test]
+fn stderr_levels() {
+    assert!(is_error_line(
+        "[ERROR rust_analyzer::main_loop] FetchWorkspaceError"
+    ));
+    assert!(is_error_line(
+        "thread 'Worker' panicked at crates/hir/src/lib.rs:1:1:"
+    ));
+    assert!(!is_error_line(
+        "[WARN project_model::workspace] unknown target"
+    ));
+    assert!(!is_error_line("Finished dev profile in 0.1s"));
+}
+
+/// Stdin of a language server
+struct ServerStdin {
+    writer: LspWriter<ChildStdin>,
+    /// Documents the server was opened with when it started
+    reopened: Reopened,
+    /// Identifies the server for `wait_task` when writing fails
+    generation: usize,
+}
+
+/// Tells `stdin_task` where to write the messages for the language server
+enum StdinWriter {
+    /// Write them to a new language server
+    Server(ServerStdin),
+    /// Keep them in the channel until there's a new language server, the
+    /// previous stdin is sent back once nothing is written to it anymore
+    Hold(Option<oneshot::Sender<Option<ServerStdin>>>),
+}
+
+/// Documents opened in a restarted language server with our copy of their
+/// text
+///
+/// The client notifications waiting in the channel were already applied to
+/// our copy, a server getting them too would apply them twice.
+#[derive(Default)]
+struct Reopened {
+    /// Documents the server has open, with their version if they were
+    /// reopened
+    open: HashMap<String, Option<u64>>,
+}
+
+impl Reopened {
+    /// Does the server already know about the document changes in `message`
+    fn is_stale(&mut self, message: &Message) -> bool {
+        let Message::Notification(notif) = message else {
+            return false;
+        };
+        let document = &notif.params["textDocument"];
+        let Some(uri) = document["uri"].as_str() else {
+            return false;
+        };
+        let reopened = || self.open.get(uri).copied().flatten();
+        match notif.method.as_str() {
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let stale = reopened()
+                    .zip(document["version"].as_u64())
+                    .is_some_and(|(reopened, version)| version <= reopened);
+                if !stale && notif.method == "textDocument/didOpen" {
+                    self.open.insert(uri.to_owned(), None);
+                }
+                stale
             }
+            // The document was closed before the server was started.
+            "textDocument/didClose" => self.open.remove(uri).is_none(),
+            _ => false,
         }
     }
 }
 
 /// Receive messages from clients' channel and write them into language server stdin
-async fn stdin_task(mut receiver: mpsc::Receiver<Message>, mut writer: LspWriter<ChildStdin>) {
+///
+/// A new stdin is received from `writers` every time the language server is
+/// restarted or replaced, until then the messages are kept in the channel. A
+/// message which couldn't be written is written again to the next stdin.
+async fn stdin_task(
+    mut receiver: mpsc::Receiver<Message>,
+    mut writers: mpsc::Receiver<StdinWriter>,
+    write_errors: mpsc::UnboundedSender<usize>,
+    (drained, low_water): (Arc<Notify>, usize),
+) {
     // Because we (stdin task) don't keep a reference to `self` it will be dropped when the
     // child closes and all the clients disconnect including the sender and this receiver
     // will not keep blocking (unlike in client input task)
-    while let Some(message) = receiver.recv().await {
-        if let Err(err) = writer.write_message(&message).await {
-            match err.kind() {
-                // stdin is closed, no need to log an error
-                ErrorKind::BrokenPipe => {}
-                _ => {
-                    let err = anyhow::Error::from(err);
-                    error!(?err, "error writing to stdin");
+    let mut stdin: Option<ServerStdin> = None;
+    let mut unsent = None;
+    loop {
+        select! {
+            new_writer = writers.recv() => match new_writer {
+                Some(StdinWriter::Server(new_stdin)) => stdin = Some(new_stdin),
+                Some(StdinWriter::Hold(held)) => {
+                    let old_stdin = stdin.take();
+                    if let Some(held) = held {
+                        _ = held.send(old_stdin);
+                    }
+                }
+                // The instance is gone for good.
+                None => break,
+            },
+            // Don't use `Option::or`, it would wait for a new message before
+            // returning the unsent one.
+            message = async {
+                match unsent.take() {
+                    Some(message) => Some(message),
+                    None => receiver.recv().await,
+                }
+            }, if stdin.is_some() => {
+                let Some(message) = message else {
+                    break;
+                };
+                if receiver.len() <= low_water {
+                    drained.notify_waiters();
+                }
+                let server = stdin.as_mut().unwrap();
+                if server.reopened.is_stale(&message) {
+                    trace!(?message, "skipping change the reopened document already has");
+                    continue;
+                }
+                if let Err(err) = server.writer.write_message(&message).await {
+                    unsent = Some(message);
+                    match err.kind() {
+                        // The server closed its stdin, it's either exiting
+                        // already or it can't be used anymore.
+                        ErrorKind::BrokenPipe => warn!("language server closed stdin"),
+                        // A message may have been written partially, the
+                        // server can't make sense of its input anymore.
+                        _ => {
+                            let err = anyhow::Error::from(err);
+                            error!(?err, "error writing to stdin");
+                        }
+                    }
+                    // Let `wait_task` restart the server, the message is sent
+                    // to the next one.
+                    let _ = write_errors.send(server.generation);
+                    stdin = None;
                 }
             }
-            break;
         }
     }
     debug!("stdin closed");
 }
 
+/// Ask the language server to shut down and exit so it can flush its state
+async fn shutdown_handshake(instance: &Instance, child: &mut Child) -> Result<()> {
+    let req = Request {
+        jsonrpc: Version,
+        method: "shutdown".into(),
+        params: Value::Null,
+        id: RequestId::String(SHUTDOWN_REQUEST_ID.into()),
+    };
+    instance
+        .send_message(req.into())
+        .await
+        .context("send shutdown request")?;
+    tokio::time::timeout(SHUTDOWN_TIMEOUT, instance.shutdown.notified())
+        .await
+        .context("waiting for shutdown response")?;
+
+    let notif = Notification {
+        jsonrpc: Version,
+        method: "exit".into(),
+        params: Value::Null,
+    };
+    instance
+        .send_message(notif.into())
+        .await
+        .context("send exit notification")?;
+    tokio::time::timeout(SHUTDOWN_TIMEOUT, child.wait())
+        .await
+        .context("waiting for child to exit")?
+        .context("error waiting for child")?;
+
+    Ok(())
+}
+
+/// A language server started by [`Instance::handover`]
+struct Handover {
+    server: ServerProcess,
+    /// Gets the PID of the new server once the instance uses it
+    done: oneshot::Sender<Result<u32>>,
+}
+
+/// The language server process of an instance
+struct RunningServer {
+    child: Child,
+    stdout: JoinHandle<()>,
+    /// Counts the servers of the instance, identifies the current one for
+    /// `stdin_task` write errors
+    generation: usize,
+}
+
 /// Wait for child and log when it exits
+///
+/// If the child exits on its own while there are still clients connected it's
+/// restarted, at most [`MAX_RESTARTS`] times in a row with an exponential
+/// backoff between the attempts. A child replaced by a handover is left to
+/// exit on its own.
 async fn wait_task(
     instance: Arc<Instance>,
     instance_map: Arc<Mutex<InstanceMap>>,
-    mut child: Child,
+    mut server: RunningServer,
+    stdin_writers: mpsc::Sender<StdinWriter>,
+    mut write_errors: mpsc::UnboundedReceiver<usize>,
+    mut handovers: mpsc::Receiver<Handover>,
 ) {
     let key = instance.key.clone();
+    let mut closing = false;
+    // We killed the current server ourselves.
+    let mut killed = false;
+    let mut restarts = 0;
+    let mut started = Instant::now();
     loop {
         select! {
-            _ = instance.close.notified() => {
-                if let Err(err) = child.start_kill() {
-                    error!(?err, "failed to close child");
+            // The garbage collector can ask us to close the instance again
+            // while we're waiting for it to exit, check for exit first.
+            biased;
+
+            exit = server.child.wait() => {
+                // Responses the server wrote before exiting are still
+                // delivered, nobody is going to answer the rest.
+                _ = tokio::time::timeout(STDOUT_DRAIN_TIMEOUT, instance.stdout_closed.notified()).await;
+                // JSON-RPC `InternalError`
+                instance.fail_pending_requests(-32603, "language server exited").await;
+                instance.running.store(false, Ordering::Relaxed);
+                // Keep the messages for the restarted server, the old stdin
+                // might not be closed yet if the server left other processes
+                // behind.
+                let _ = stdin_writers.send(StdinWriter::Hold(None)).await;
+                match exit {
+                    Ok(status) => log_exit(status, closing, killed),
+                    Err(err) => error!(?err, "error waiting for child"),
                 }
-            }
-            exit = child.wait() => {
-                // Remove the closing instance from the map so new clients spawn their own instance
-                instance_map.lock().await.0.remove(&key);
+                killed = false;
+
+                if !closing && !instance.clients.lock().await.is_empty() {
+                    if started.elapsed() > RESTART_RESET {
+                        restarts = 0;
+                    }
+                    let generation = server.generation + 1;
+                    if let Some(new_server) = restart_with_backoff(&instance, &stdin_writers, generation, &mut restarts).await {
+                        server = new_server;
+                        started = Instant::now();
+                        instance.announce_restart().await;
+                        continue;
+                    }
+                }
+
+                // Remove the closing instance from the map so new clients spawn
+                // their own instance, unless it was already replaced by a new
+                // one after being killed.
+                let mut map = instance_map.lock().await;
+                if map.instances.get(&key).is_some_and(|current| Arc::ptr_eq(current, &instance)) {
+                    map.instances.remove(&key);
+                }
+                drop(map);
 
                 // Disconnect all current clients
                 //
                 // We'll rely on the editor client to restart the ra-multiplex client,
                 // start a new connection and we'll spawn another instance like we'd with
-                // any other new client.
-                instance.clients.lock().await.clear();
-
-                match exit {
-                    Ok(status) => {
-                        #[cfg(unix)]
-                        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
-                        #[cfg(not(unix))]
-                        let signal = tracing::field::Empty;
-
-                        error!(
-                            success = status.success(),
-                            code = status.code(),
-                            signal,
-                            "child exited",
-                        );
-                    }
-                    Err(err) => error!(?err, "error waiting for child"),
+                // any other new client. Messages already queued for the clients are
+                // still delivered before the connection closes.
+                for (_, client) in instance.clients.lock().await.drain() {
+                    client.client.disconnect();
                 }
                 break;
             }
+            Some(failed) = write_errors.recv() => {
+                // Errors of servers which already exited are stale.
+                if failed == server.generation && !closing {
+                    warn!("can't write to language server, killing it");
+                    killed = true;
+                    if let Err(err) = server.child.start_kill() {
+                        error!(?err, "failed to kill child");
+                    }
+                }
+            }
+            _ = instance.unresponsive.notified(), if !closing => {
+                killed = true;
+                if let Err(err) = server.child.start_kill() {
+                    error!(?err, "failed to kill child");
+                }
+            }
+            Some(Handover { server: new_server, done }) = handovers.recv() => {
+                if closing {
+                    _ = done.send(Err(anyhow!("instance is shutting down")));
+                    continue;
+                }
+                let generation = server.generation + 1;
+                match hand_over(&instance, &stdin_writers, new_server, generation).await {
+                    Ok(new_server) => {
+                        let old_server = mem::replace(&mut server, new_server);
+                        // Whatever the old server still sends is out of date.
+                        old_server.stdout.abort();
+                        task::spawn(retire(old_server.child).in_current_span());
+                        restarts = 0;
+                        started = Instant::now();
+                        let pid = instance.pid.load(Ordering::Relaxed);
+                        info!(pid, "handed instance over to new language server");
+                        _ = done.send(Ok(pid));
+                    }
+                    Err(err) => _ = done.send(Err(err)),
+                }
+            }
+            _ = instance.close.notified() => {
+                closing = true;
+                if let Err(err) = shutdown_handshake(&instance, &mut server.child).await {
+                    warn!(?err, "language server didn't shut down cleanly, killing it");
+                    killed = true;
+                    if let Err(err) = server.child.start_kill() {
+                        error!(?err, "failed to close child");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Log how the language server exited
+///
+/// Exiting successfully after we asked it to shut down is expected, a server
+/// exiting on its own crashed and one killed by a signal nobody here sent is
+/// most likely out of memory.
+fn log_exit(status: ExitStatus, closing: bool, killed: bool) {
+    #[cfg(unix)]
+    let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+    #[cfg(not(unix))]
+    let signal = None::<i32>;
+
+    match (signal, status.code()) {
+        (Some(signal), _) if killed => debug!(signal, "language server was killed"),
+        (Some(signal), _) => error!(
+            signal,
+            "language server was killed by a signal, SIGKILL usually means the OOM killer",
+        ),
+        (None, Some(0)) if closing => debug!("language server exited"),
+        (None, Some(0)) => warn!("language server exited unexpectedly"),
+        (None, code) => error!(code, "language server exited with an error"),
+    }
+}
+
+/// Send the queued client requests as the server finishes others
+///
+/// Stops when the instance is dropped.
+async fn request_queue_task(instance: Weak<Instance>, queue: Arc<RequestQueue>) {
+    loop {
+        if queue.permits.is_closed() {
+            break;
+        }
+        // Only take a permit once a request waits for it, until then requests
+        // take free permits themselves.
+        if queue.waiting.lock().await.is_empty() {
+            queue.request_waiting.notified().await;
+            continue;
+        }
+        let Ok(permit) = queue.permits.clone().acquire_owned().await else {
+            break;
+        };
+        let Some(instance) = instance.upgrade() else {
+            break;
+        };
+        // It may have been cancelled in the meantime.
+        let Some((client_id, req)) = queue.waiting.lock().await.pop() else {
+            continue;
+        };
+        if instance
+            .forward_request(client_id, req, Some(permit))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Ping the language server every `interval` and have it restarted once it
+/// misses `threshold` pings in a row
+///
+/// Any response counts, most servers answer the unknown `$/lspmux/ping`
+/// request with an error. Stops when the instance is dropped.
+async fn watchdog_task(instance: Weak<Instance>, interval: Duration, threshold: u32) {
+    let mut ping = 0;
+    let mut missed = 0;
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(instance) = instance.upgrade() else {
+            break;
+        };
+        if !instance.running.load(Ordering::Relaxed) {
+            // Restarting, the new server gets a fresh start.
+            missed = 0;
+            continue;
+        }
+        ping += 1;
+        let mut pongs = instance.watchdog_pongs.subscribe();
+        let req = Request {
+            jsonrpc: Version,
+            method: "$/lspmux/ping".into(),
+            params: Value::Null,
+            id: RequestId::String(format!("{WATCHDOG_REQUEST_ID}{ping}")),
+        };
+        if instance.send_message(req.into()).await.is_err() {
+            break;
+        }
+        let answered = tokio::time::timeout(interval, pongs.wait_for(|&pong| pong >= ping))
+            .await
+            .is_ok();
+        if answered {
+            missed = 0;
+            continue;
+        }
+        missed += 1;
+        warn!(missed, "language server didn't answer watchdog ping");
+        if missed >= threshold {
+            error!("language server is unresponsive, restarting it");
+            instance.unresponsive.notify_one();
+            missed = 0;
+        }
+    }
+}
+
+/// Parse the tag of a server response ID
+///
+/// Without `rewrite_request_ids` client requests aren't tagged, an untagged
+/// response goes to a client waiting for a response with that ID.
+fn untag_response(
+    config: &Config,
+    clients: &HashMap<usize, ClientData>,
+    id: &RequestId,
+) -> (Option<Tag>, RequestId) {
+    match id.untag() {
+        (None, id) if !config.rewrite_request_ids => {
+            let client = clients
+                .values()
+                .find(|client| client.requests.contains_key(&id));
+            (client.map(|client| Tag::ClientId(client.id())), id)
+        }
+        untagged => untagged,
+    }
+}
+
+/// Remove the diagnostics less severe than `severity` from
+/// `textDocument/publishDiagnostics` params
+///
+/// The other fields, like the URI and version, are left alone. Diagnostics
+/// without a severity are kept, clients usually show them as errors.
+fn filter_diagnostics(params: &mut Value, severity: DiagnosticSeverity) {
+    let Some(diagnostics) = params
+        .get_mut("diagnostics")
+        .and_then(|diagnostics| diagnostics.as_array_mut())
+    else {
+        return;
+    };
+    diagnostics.retain(|diagnostic| {
+        diagnostic["severity"]
+            .as_u64()
+            .is_none_or(|level| level <= severity as u64)
+    });
+}
+
+/// Number of the watchdog ping if `message` is a response to one
+fn watchdog_pong(message: &Message) -> Option<u64> {
+    let id = match message {
+        Message::ResponseSuccess(res) => &res.id,
+        Message::ResponseError(res) => res.id.as_ref()?,
+        _ => return None,
+    };
+    let RequestId::String(id) = id else {
+        return None;
+    };
+    id.strip_prefix(WATCHDOG_REQUEST_ID)?.parse().ok()
+}
+
+/// Try restarting the language server until it succeeds or we run out of
+/// attempts
+async fn restart_with_backoff(
+    instance: &Arc<Instance>,
+    stdin_writers: &mpsc::Sender<StdinWriter>,
+    generation: usize,
+    restarts: &mut u32,
+) -> Option<RunningServer> {
+    while *restarts < MAX_RESTARTS {
+        let delay = RESTART_BACKOFF * 2_u32.pow(*restarts);
+        *restarts += 1;
+        warn!(attempt = *restarts, ?delay, "restarting language server");
+        tokio::time::sleep(delay).await;
+
+        match restart(instance, stdin_writers, generation).await {
+            Ok(server) => {
+                metrics::RESTARTS.fetch_add(1, Ordering::Relaxed);
+                return Some(server);
+            }
+            Err(err) => error!(?err, "failed to restart language server"),
+        }
+    }
+    error!("language server keeps crashing, giving up");
+    None
+}
+
+/// Append `telemetry/event`s to the `telemetry_file` as lines of JSON
+///
+/// The file stays open, it's opened again after an error.
+async fn telemetry_task(path: PathBuf, mut events: mpsc::Receiver<Value>) {
+    let mut file = None;
+    while let Some(params) = events.recv().await {
+        // Tokio only hands the write to a blocking thread, flush to wait for
+        // it to finish once there's nothing else to write.
+        let flush = events.is_empty();
+        if let Err(err) = append_telemetry(&mut file, &path, &params, flush).await {
+            warn!(?err, ?path, "failed to write telemetry event");
+            file = None;
         }
     }
 }
 
+async fn append_telemetry(
+    file: &mut Option<tokio::fs::File>,
+    path: &Path,
+    params: &Value,
+    flush: bool,
+) -> Result<()> {
+    let mut line = serde_json::to_vec(params).unwrap();
+    line.push(b'\n');
+    let file = match file {
+        Some(file) => file,
+        None => file.insert(
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .context("opening file")?,
+        ),
+    };
+    file.write_all(&line).await.context("writing file")?;
+    if flush {
+        file.flush().await.context("writing file")?;
+    }
+    Ok(())
+}
+
 /// Read messages from server stdout and send them to corresponding client channels
 async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<ChildStdout>>) {
+    let mut telemetry_dropped = 0;
+    let mut last_telemetry_warning: Option<Instant> = None;
     loop {
         let message = match reader.read_message().await {
             Ok(Some(message)) => message,
             Ok(None) => {
                 debug!("stdout closed");
+                instance.stdout_closed.notify_one();
                 break;
             }
             Err(err) => {
@@ -649,18 +3050,34 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                 continue;
             }
         };
+        instance.trace_message(ext::TraceDirection::FromServer, &message);
+
+        if let Some(ping) = watchdog_pong(&message) {
+            trace!(ping, "server answered watchdog ping");
+            instance.watchdog_pongs.send_replace(ping);
+            continue;
+        }
 
         // Lock _after_ we have a message to send, then send and immediately release the lock
-        let clients = instance.clients.lock().await;
+        let mut clients = instance.clients.lock().await;
         match message {
+            Message::ResponseSuccess(res) if res.id == SHUTDOWN_REQUEST_ID => {
+                debug!("server acknowledged shutdown");
+                instance.shutdown.notify_one();
+            }
+
             Message::ResponseSuccess(mut res) => {
                 // Forward successful response to the right client based on the
                 // Request ID tag.
-                match res.id.untag() {
+                match untag_response(&instance.config, &clients, &res.id) {
                     (Some(Tag::ClientId(client_id)), id) => {
                         res.id = id;
-                        if let Some(client) = clients.get(&client_id) {
-                            let _ = client.send_message(res.into()).await;
+                        if let Some(client) = clients.get_mut(&client_id) {
+                            if !client.finish_request(&res.id) {
+                                debug!(?res, "dropping response to a failed request");
+                                continue;
+                            }
+                            client.send_message_nowait(res.into());
                         } else {
                             debug!(?client_id, "no matching client");
                         }
@@ -674,15 +3091,37 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                 }
             }
 
+            Message::ResponseError(res) if res.id.is_none() => {
+                // We can't tell which request the response belongs to, the
+                // server most likely couldn't parse it. Either tell everyone
+                // or noone.
+                warn!(?res, "server responded with error to unknown request");
+                match instance.config.null_id_responses {
+                    NullIdResponses::Broadcast => {
+                        for client in clients.values() {
+                            client.send_message_nowait(res.clone().into());
+                        }
+                    }
+                    NullIdResponses::Drop => {}
+                }
+            }
+
             Message::ResponseError(mut res) => {
                 // Forward the error response to the right client based on the
                 // Request ID tag.
-                match res.id.untag() {
+                let Some(tagged_id) = res.id.take() else {
+                    unreachable!("null ID responses are handled above");
+                };
+                match untag_response(&instance.config, &clients, &tagged_id) {
                     (Some(Tag::ClientId(client_id)), id) => {
-                        warn!(?res, "server responded with error");
-                        res.id = id;
-                        if let Some(client) = clients.get(&client_id) {
-                            let _ = client.send_message(res.into()).await;
+                        warn!(?id, ?res, "server responded with error");
+                        if let Some(client) = clients.get_mut(&client_id) {
+                            if !client.finish_request(&id) {
+                                debug!(?id, ?res, "dropping response to a failed request");
+                                continue;
+                            }
+                            res.id = Some(id);
+                            client.send_message_nowait(res.into());
                         } else {
                             debug!(?client_id, "no matching client");
                         }
@@ -691,6 +3130,7 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                         // Drop the message
                     }
                     _ => {
+                        res.id = Some(tagged_id);
                         warn!(?res, "ignoring improperly tagged server response")
                     }
                 }
@@ -713,11 +3153,24 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                 // client responses.
                 trace!(?req, "server request {}", req.method.as_str());
 
+                if req.method == "window/workDoneProgress/create" {
+                    match lsp::WorkDoneProgressParams::deserialize(&req.params) {
+                        Ok(params) => {
+                            instance
+                                .server_progress_tokens
+                                .lock()
+                                .await
+                                .insert(params.token);
+                        }
+                        Err(err) => warn!(?err, "invalid window/workDoneProgress/create params"),
+                    }
+                }
+
                 let id = req.id;
                 req.id = id.tag(Tag::Drop);
 
                 for client in clients.values() {
-                    let _ = client.send_message(req.clone().into()).await;
+                    client.send_message_nowait(req.clone().into());
                 }
 
                 let _ = instance
@@ -725,21 +3178,6 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                     .await;
             }
 
-            Message::Request(mut req) if req.method == "workspace/configuration" => {
-                // Response to `workspace/configuration` should be the same from
-                // any client. So we'll just pick the first and let it answer.
-                debug!(?req, "server request workspace/configuration");
-
-                req.id = req.id.tag(Tag::Forward);
-
-                if let Some(client) = clients.values().next() {
-                    let _ = client.send_message(req.into()).await;
-                } else {
-                    // If there is no client connected at this moment we'll
-                    // ignore the request.
-                }
-            }
-
             Message::Request(mut req) if req.method == "client/registerCapability" => {
                 // These need to be forwarded to every client so they're aware
                 // of the capability. The response doesn't contain anything
@@ -750,14 +3188,19 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                 let id = req.id;
                 req.id = id.tag(Tag::Drop);
 
-                for client in clients.values() {
-                    let _ = client.send_message(req.clone().into()).await;
-                }
-
                 // We need to cache the dynamic capabilities registrations for
                 // any client that might come later.
-                if let Err(err) = instance.register_capabilities(req.params).await {
-                    warn!(?err, "error registering capabilities");
+                match instance.register_capabilities(req.params).await {
+                    Ok(params) if params.registrations.is_empty() => {
+                        debug!("capabilities are already registered");
+                    }
+                    Ok(params) => {
+                        req.params = serde_json::to_value(params).unwrap();
+                        for client in clients.values() {
+                            client.send_message_nowait(req.clone().into());
+                        }
+                    }
+                    Err(err) => warn!(?err, "error registering capabilities"),
                 }
 
                 let _ = instance
@@ -776,7 +3219,7 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                 req.id = id.tag(Tag::Drop);
 
                 for client in clients.values() {
-                    let _ = client.send_message(req.clone().into()).await;
+                    client.send_message_nowait(req.clone().into());
                 }
 
                 // We need to remove this registration from the cache so we
@@ -791,17 +3234,117 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
             }
 
             Message::Request(req) => {
-                // Unimplemented server -> client requests I've found in the LSP Spec.
-                // TODO workspace/workspaceFolders request
-                // TODO workspace/applyEdit request
-                debug!(message = ?req, "ignoring unknown server request");
+                // Requests like `workspace/configuration` need a real answer
+                // from a client, the response should be the same from any of
+                // them.
+                instance.forward_server_request(&clients, req).await;
+            }
+
+            Message::Notification(mut notif) if notif.method == "$/progress" => {
+                // Progress for a token provided by a client only goes to that
+                // client. Tokens created by the server with
+                // `window/workDoneProgress/create` aren't tied to any client
+                // request as far as we can tell, every client gets those.
+                let progress = match lsp::ProgressParams::deserialize(&notif.params) {
+                    Ok(progress) => progress,
+                    Err(err) => {
+                        warn!(?err, "invalid $/progress params");
+                        continue;
+                    }
+                };
+                instance.track_progress(&progress).await;
+                match progress.token.untag_client() {
+                    Some((client_id, token)) => {
+                        notif.params = serde_json::to_value(lsp::ProgressParams {
+                            token,
+                            value: progress.value,
+                        })
+                        .unwrap();
+                        if let Some(client) = clients.get(&client_id) {
+                            client.send_message_nowait(notif.into());
+                        } else {
+                            debug!(?client_id, "no matching client");
+                        }
+                    }
+                    None => {
+                        if progress.value["kind"] == "end" {
+                            instance
+                                .server_progress_tokens
+                                .lock()
+                                .await
+                                .remove(&progress.token);
+                        }
+                        for client in clients.values() {
+                            client.send_message_nowait(notif.clone().into());
+                        }
+                    }
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "telemetry/event" => {
+                if let Some(telemetry) = &instance.telemetry {
+                    if let Err(TrySendError::Full(_)) = telemetry.try_send(notif.params.clone()) {
+                        telemetry_dropped += 1;
+                        if last_telemetry_warning
+                            .is_none_or(|warned| warned.elapsed() >= TELEMETRY_WARN_INTERVAL)
+                        {
+                            warn!(
+                                dropped = telemetry_dropped,
+                                "telemetry file can't keep up, dropping events"
+                            );
+                            telemetry_dropped = 0;
+                            last_telemetry_warning = Some(Instant::now());
+                        }
+                    }
+                }
+                match instance.config.telemetry {
+                    Telemetry::Drop => {}
+                    Telemetry::Log => debug!(params = %notif.params, "telemetry event"),
+                    Telemetry::Forward => {
+                        for client in clients.values() {
+                            client.send_message_nowait(notif.clone().into());
+                        }
+                    }
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "$/logTrace" => {
+                // The server traces as verbosely as the most verbose client
+                // asked for, the others only get what they asked for.
+                for client in clients.values() {
+                    match client.trace {
+                        lsp::TraceValue::Off => {}
+                        lsp::TraceValue::Messages => {
+                            let mut notif = notif.clone();
+                            if let Some(params) = notif.params.as_object_mut() {
+                                params.remove("verbose");
+                            }
+                            client.send_message_nowait(notif.into());
+                        }
+                        lsp::TraceValue::Verbose => {
+                            client.send_message_nowait(notif.clone().into());
+                        }
+                    }
+                }
+            }
+
+            Message::Notification(mut notif)
+                if notif.method == "textDocument/publishDiagnostics"
+                    && instance.config.diagnostics_severity.is_some() =>
+            {
+                if let Some(severity) = instance.config.diagnostics_severity {
+                    filter_diagnostics(&mut notif.params, severity);
+                }
+                for client in clients.values() {
+                    client.send_message_nowait(notif.clone().into());
+                }
             }
 
             Message::Notification(notif) => {
                 // Server notifications don't expect a response. We can forward
                 // them to all clients.
                 for client in clients.values() {
-                    let _ = client.send_message(notif.clone().into()).await;
+                    client.send_message_nowait(notif.clone().into());
                 }
             }
         }
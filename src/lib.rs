@@ -1,9 +1,32 @@
+mod capabilities;
 mod client;
+mod document;
 mod instance;
+mod json_log;
 mod lsp;
+mod metrics;
 mod socketwrapper;
+mod state;
+#[cfg(all(test, unix))]
+mod tests;
+mod workspace;
 
 pub mod config;
 pub mod ext;
+#[cfg(fuzzing)]
+pub mod fuzz;
 pub mod proxy;
 pub mod server;
+
+use std::sync::LazyLock;
+
+use crate::lsp::ext::LspMuxOptions;
+
+/// Crate version followed by the client-server protocol version
+pub static VERSION: LazyLock<String> = LazyLock::new(|| {
+    format!(
+        "{} (protocol {})",
+        env!("CARGO_PKG_VERSION"),
+        LspMuxOptions::PROTOCOL_VERSION,
+    )
+});
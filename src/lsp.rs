@@ -26,10 +26,14 @@
 //! - Cancel notifications - contains an `id` property again, so we could multiplex this like any
 //!   other request
 //! - Progress notifications - contains a `token` property which could be used to identify the
-//!   client but the specification also says it has nothing to do with the request IDs
+//!   client but the specification also says it has nothing to do with the request IDs, tokens
+//!   provided by clients in request params are namespaced the same way as request IDs, tokens
+//!   created by the server are shared by all clients
 
 use serde_derive::{Deserialize, Serialize};
 
+use self::jsonrpc::RequestId;
+
 macro_rules! impl_json_debug {
     ( $($type:ty),* $(,)? ) => {
         $(
@@ -96,24 +100,47 @@ pub struct InitializationOptions {
     pub other_options: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Verbosity of `$/logTrace` notifications, ordered from the least verbose
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "camelCase")]
 pub enum TraceValue {
+    #[default]
     Off,
     Messages,
     Verbose,
 }
 
+/// Params for the `$/setTrace` notification
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SetTraceParams {
+    pub value: TraceValue,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WorkspaceFolder {
     pub uri: String,
     pub name: String,
 }
 
+/// Params for `workspace/didChangeWorkspaceFolders` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeWorkspaceFoldersParams {
+    pub event: WorkspaceFoldersChangeEvent,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceFoldersChangeEvent {
+    pub added: Vec<WorkspaceFolder>,
+    pub removed: Vec<WorkspaceFolder>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct InitializeResult {
-    capabilities: serde_json::Value,
+    pub capabilities: serde_json::Value,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     server_info: Option<ServerInfo>,
@@ -175,6 +202,42 @@ pub struct TextDocumentItem {
     pub text: String,
 }
 
+/// Params for `textDocument/didChange` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeTextDocumentParams {
+    pub text_document: VersionedTextDocumentIdentifier,
+    pub content_changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionedTextDocumentIdentifier {
+    pub uri: String,
+    pub version: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TextDocumentContentChangeEvent {
+    /// Missing range means the text is the whole new document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
 /// Params for `textDocument/didClose` notification
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -187,3 +250,40 @@ pub struct DidCloseTextDocumentParams {
 pub struct TextDocumentIdentifier {
     pub uri: String,
 }
+
+/// Params for `$/cancelRequest` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelParams {
+    pub id: RequestId,
+}
+
+/// Params for `window/showMessage` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ShowMessageParams {
+    /// `MessageType`, from 1 for errors to 4 for logs
+    #[serde(rename = "type")]
+    pub typ: u8,
+    pub message: String,
+}
+
+/// `MessageType` of informational messages
+pub const MESSAGE_TYPE_INFO: u8 = 3;
+
+/// Params for `window/workDoneProgress/create` request and
+/// `window/workDoneProgress/cancel` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkDoneProgressParams {
+    pub token: RequestId,
+}
+
+/// Params for `$/progress` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProgressParams {
+    /// Progress token has the same `integer | string` shape as request IDs
+    pub token: RequestId,
+    pub value: serde_json::Value,
+}
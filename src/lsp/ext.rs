@@ -10,6 +10,7 @@ use tracing::warn;
 use super::jsonrpc::RequestId;
 
 /// Additional metadata inserted into LSP RequestId
+#[derive(Debug, PartialEq, Eq)]
 pub enum Tag {
     /// Request is coming from a client connected with this ID
     ClientId(usize),
@@ -83,17 +84,36 @@ impl RequestId {
             }
         }
     }
+
+    /// Attempts to parse a client ID Tag out of a progress token
+    ///
+    /// Unlike [`untag`](RequestId::untag) this doesn't complain about untagged
+    /// IDs, servers are free to create their own progress tokens.
+    pub fn untag_client(&self) -> Option<(usize, RequestId)> {
+        match self {
+            RequestId::String(string) if string.starts_with("client_id:") => match self.untag() {
+                (Some(Tag::ClientId(client_id)), token) => Some((client_id, token)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LspMuxOptions {
     /// Version number of the protocol
     ///
-    /// Version is for now naively checked for equality with
-    /// [`PROTOCOL_VERSION`](LspMuxOptions::PROTOCOL_VERSION), the server will
-    /// refuse connections to mismatched clients.
+    /// The server refuses connections from clients whose major version
+    /// doesn't match [`PROTOCOL_VERSION`](LspMuxOptions::PROTOCOL_VERSION), see
+    /// [`is_compatible`](LspMuxOptions::is_compatible).
     pub version: String,
 
+    /// Shared secret, required if the server has an `auth_token_file`
+    /// configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
     #[serde(flatten)]
     pub method: Request,
 }
@@ -108,6 +128,84 @@ impl LspMuxOptions {
     /// This doesn't match the crate version, it starts at `"1"` and will only
     /// increase if we make a backwards-incompatible change.
     pub const PROTOCOL_VERSION: &'static str = "1";
+
+    /// Can a client using protocol `version` talk to this server
+    ///
+    /// Versions are compared like semver, a minor version like `"1.2"` only
+    /// adds backwards-compatible features so only the major version must
+    /// match.
+    pub fn is_compatible(version: &str) -> bool {
+        fn major(version: &str) -> &str {
+            version.split('.').next().unwrap_or_default()
+        }
+        major(version) == major(Self::PROTOCOL_VERSION)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn protocol_version_compatibility() {
+    assert!(LspMuxOptions::is_compatible("1"));
+    assert!(LspMuxOptions::is_compatible("1.3"));
+    assert!(!LspMuxOptions::is_compatible("2"));
+    assert!(!LspMuxOptions::is_compatible("10"));
+    assert!(!LspMuxOptions::is_compatible(""));
+}
+
+/// Compression of the connection between `ra-multiplex client` and the
+/// server
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+}
+
+/// Params of the `$/lspmux/compression` notification
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressionParams {
+    pub compression: Compression,
+}
+
+/// Why the server refused a client
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RejectReason {
+    /// The client protocol version isn't compatible with the server
+    IncompatibleVersion,
+    /// The auth token is missing or doesn't match
+    Unauthorized,
+    /// The server is draining and doesn't accept new clients
+    Draining,
+    /// The language server executable doesn't exist
+    ServerNotFound,
+    /// The language server executable can't be run by the server's user
+    ServerPermissionDenied,
+    /// The language server exited before answering the `initialize` request
+    ServerExited,
+    /// The language server couldn't be started for another reason
+    ServerFailed,
+    /// The client's `initialize` params conflict with the ones the shared
+    /// language server was initialized with and `strict_initialize` is on
+    InitializeConflict,
+    /// The workspace folder of the client doesn't exist or isn't a directory
+    InvalidWorkspace,
+}
+
+/// `data` of the error response to a refused `initialize` request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Rejection {
+    pub reason: RejectReason,
+    /// Crate version of the server
+    pub server_version: String,
+    /// [`PROTOCOL_VERSION`](LspMuxOptions::PROTOCOL_VERSION) of the server
+    pub protocol_version: String,
+}
+
+impl Rejection {
+    /// JSON-RPC error code of the response, "Invalid Request"
+    pub const CODE: i64 = -32600;
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -137,11 +235,47 @@ pub enum Request {
         /// fallback if the client doesn't provide any workspace root.
         #[serde(skip_serializing_if = "Option::is_none")]
         cwd: Option<String>,
+
+        /// Start a language server just for this client instead of sharing
+        /// one with other clients of the workspace, it's shut down when the
+        /// client disconnects.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        private: bool,
+
+        /// Compress the rest of the connection, the server confirms it with a
+        /// `$/lspmux/compression` notification which is the last uncompressed
+        /// message in both directions
+        ///
+        /// Servers which don't support it ignore it and don't confirm it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
+
+        /// Human readable name like `Neovim/ra-multiplex` shown in `status`
+        /// and the logs next to the client ID
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_name: Option<String>,
     },
 
     /// List instances and connected clients
     Status {},
 
+    /// Check the server and its instances are responsive
+    Health {},
+
+    /// Shut down instances and disconnect their clients
+    Kill {
+        /// Selects instances with the longest workspace root containing this path
+        workspace_root: String,
+    },
+
+    /// Exempt instances from the idle shutdown or make them subject to it
+    /// again
+    Pin {
+        /// Selects instances with the longest workspace root containing this path
+        workspace_root: String,
+        pinned: bool,
+    },
+
     /// Reload an instance
     ///
     /// For rust-analyzer send the `rust-analyzer/reloadWorkspace` extension request.
@@ -151,12 +285,68 @@ pub enum Request {
         /// `cwd.starts_with(workspace_root)` is true
         cwd: String,
     },
+
+    /// Stop accepting new clients and exit once the connected ones are gone
+    Drain {
+        /// Seconds to wait for the clients before shutting down anyway,
+        /// waits indefinitely if omitted
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u32>,
+    },
+
+    /// Start a language server for a workspace before any client needs it
+    ///
+    /// The instance is pinned until the first client connects to it.
+    Warmup {
+        /// The language server to run, like [`Connect`](Request::Connect)'s
+        server: String,
+
+        #[serde(default = "Vec::new")]
+        args: Vec<String>,
+
+        #[serde(default = "BTreeMap::new", skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+
+        /// Directory the clients will open, the instance's workspace root is
+        /// picked from it like for clients
+        workspace_root: String,
+    },
+
+    /// Observe the messages exchanged with instances
+    ///
+    /// After the response the server sends a `$/lspmux/trace` notification
+    /// with a [`TraceEvent`] for every message until the observer disconnects.
+    Trace {
+        /// Selects instances with the longest workspace root containing this path
+        workspace_root: String,
+    },
+
+    /// Replace the language servers of instances with new processes, like
+    /// after they were updated, without disconnecting their clients
+    Handover {
+        /// Selects instances with the longest workspace root containing this
+        /// path, every instance if omitted
+        #[serde(default)]
+        workspace_root: Option<String>,
+    },
+
+    /// List the requests instances are waiting for responses to, stale
+    /// entries point at requests which are never answered
+    IdMap {
+        /// Selects instances with the longest workspace root containing this path
+        workspace_root: String,
+        /// Most requests listed per instance and direction, the oldest first
+        limit: usize,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct StatusResponse {
     pub instances: Vec<Instance>,
+    /// The server exits once the remaining clients disconnect
+    #[serde(default)]
+    pub draining: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -168,29 +358,211 @@ pub struct Instance {
     pub env: BTreeMap<String, String>,
     pub workspace_root: String,
     pub registered_dyn_capabilities: Vec<String>,
+    /// UTC unix timestamp of the instance start
+    pub started: i64,
     pub last_used: i64,
+    /// The instance isn't shut down when it's idle
+    #[serde(default)]
+    pub pinned: bool,
+    /// Work done progress the server reported as started and not ended yet,
+    /// like indexing, the server is ready when it's empty
+    #[serde(default)]
+    pub progress: Vec<Progress>,
+    /// Client messages waiting to be written to the language server
+    #[serde(default)]
+    pub queued_messages: usize,
     pub clients: Vec<Client>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct Client {
-    pub id: usize,
-    pub files: Vec<String>,
+pub struct KillResponse {
+    /// Instances as they were just before being killed
+    pub instances: Vec<Instance>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PinResponse {
+    /// Instances after changing their pinned state
+    pub instances: Vec<Instance>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct StopResponse {
+pub struct WarmupResponse {
+    /// The started or already running instance
     pub instance: Instance,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceResponse {
+    /// Instances being traced
+    pub instances: Vec<Instance>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HandoverResponse {
+    pub instances: Vec<HandedOver>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HandedOver {
+    pub server: String,
+    pub workspace_root: String,
+    /// PID of the replaced language server
+    pub old_pid: u32,
+    /// PID of the new language server, `None` if the handover failed and the
+    /// old one is still in use
+    pub pid: Option<u32>,
+    /// Why the handover failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IdMapResponse {
+    pub instances: Vec<IdMap>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IdMap {
+    pub server: String,
+    pub workspace_root: String,
+    /// Client requests waiting for the language server, in total
+    pub client_requests: usize,
+    /// Language server requests waiting for clients, in total
+    pub server_requests: usize,
+    /// The oldest client requests
+    pub oldest_client_requests: Vec<PendingClientRequest>,
+    /// The oldest language server requests
+    pub oldest_server_requests: Vec<PendingServerRequest>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingClientRequest {
+    pub client_id: usize,
+    /// ID as the client sent it
+    pub id: RequestId,
+    /// ID the language server knows the request by
+    pub server_id: RequestId,
+    pub method: String,
+    /// Milliseconds since the request was sent to the language server
+    pub age: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingServerRequest {
+    /// ID as the language server sent it
+    pub id: RequestId,
+    pub method: String,
+    /// Clients which received the request and didn't respond yet
+    pub clients: Vec<usize>,
+    /// Milliseconds since the request was forwarded to the clients
+    pub age: u64,
+}
+
+/// Metadata of a message exchanged with a language server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceEvent {
+    /// UTC unix timestamp in milliseconds
+    pub time: i64,
+    /// Language server process the message was exchanged with
+    pub pid: u32,
+    pub direction: TraceDirection,
+    pub kind: TraceKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Request ID as the language server sees it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    /// Size of the serialized message in bytes
+    pub size: usize,
+    /// Events dropped before this one because the observer didn't keep up
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub skipped: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TraceDirection {
+    /// Sent to the language server, by a client or by ra-multiplex itself
+    ToServer,
+    /// Sent by the language server
+    FromServer,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TraceKind {
+    Request,
+    Notification,
+    Response,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthResponse {
+    /// The instance registry could be locked and all instances are healthy
+    pub healthy: bool,
+    pub instances: Vec<InstanceHealth>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceHealth {
+    pub pid: u32,
+    pub workspace_root: String,
+    /// The language server process is running, it's not while it's being
+    /// restarted after a crash
+    pub running: bool,
+    /// The instance state could be locked in time
+    pub responsive: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Client {
+    pub id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub files: Vec<String>,
+    /// Messages waiting to be written to the client
+    #[serde(default)]
+    pub queued_messages: usize,
+}
+
 #[cfg(test)]
 mod tests {
     use serde::de::DeserializeOwned;
     use serde::Serialize;
     use serde_json::{from_value, json, to_value, Value};
 
+    use super::Tag;
+    use crate::lsp::jsonrpc::RequestId;
     use crate::lsp::InitializationOptions;
 
     fn test<T>(input: Value)
@@ -269,4 +641,61 @@ mod tests {
             },
         }))
     }
+
+    #[test]
+    fn tagged_ids_roundtrip() {
+        let ids = [
+            RequestId::Number(0),
+            RequestId::Number(-17),
+            RequestId::String("".into()),
+            RequestId::String("42".into()),
+            RequestId::String("with:colons:inside".into()),
+        ];
+        for id in ids {
+            for (client_id, other_id) in [(0, 1), (7, 3)] {
+                let tagged = id.tag(Tag::ClientId(client_id));
+                assert_ne!(tagged, id.tag(Tag::ClientId(other_id)));
+                assert_eq!(tagged.untag(), (Some(Tag::ClientId(client_id)), id.clone()));
+            }
+            assert_eq!(id.tag(Tag::Drop).untag(), (Some(Tag::Drop), id.clone()));
+            assert_eq!(
+                id.tag(Tag::Forward).untag(),
+                (Some(Tag::Forward), id.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn numeric_and_string_ids_dont_collide() {
+        let number = RequestId::Number(1).tag(Tag::ClientId(0));
+        let string = RequestId::String("1".into()).tag(Tag::ClientId(0));
+        assert_ne!(number, string);
+        assert_eq!(number.untag().1, RequestId::Number(1));
+        assert_eq!(string.untag().1, RequestId::String("1".into()));
+    }
+
+    #[test]
+    fn server_progress_tokens_have_no_client() {
+        for token in [
+            RequestId::Number(1),
+            RequestId::String("rustAnalyzer/Indexing".into()),
+            RequestId::Number(1).tag(Tag::Drop),
+        ] {
+            assert_eq!(token.untag_client(), None);
+        }
+        assert_eq!(
+            RequestId::Number(1).tag(Tag::ClientId(3)).untag_client(),
+            Some((3, RequestId::Number(1))),
+        );
+    }
+
+    #[test]
+    fn untagged_ids_are_returned_unchanged() {
+        for id in [
+            RequestId::Number(1),
+            RequestId::String("client_id:x:n:1".into()),
+        ] {
+            assert_eq!(id.untag(), (None, id.clone()));
+        }
+    }
 }
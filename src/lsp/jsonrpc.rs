@@ -41,7 +41,9 @@ pub struct Notification {
 pub struct ResponseError {
     pub jsonrpc: Version,
     pub error: Error,
-    pub id: RequestId,
+    /// Can be `null` if the request ID couldn't be determined, for example
+    /// when the request was not valid JSON
+    pub id: Option<RequestId>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -73,19 +75,21 @@ pub struct Error {
     pub data: Option<serde_json::Value>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(untagged)]
-pub enum Params {
-    ByPosition(Vec<serde_json::Value>),
-    ByName(serde_json::Map<String, serde_json::Value>),
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum RequestId {
     Number(i64),
     String(String),
-    // It can also be null but we intentionally skip those as "invalid"
+    // It can also be null but only in error responses, see `ResponseError`
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestId::Number(number) => write!(f, "{number}"),
+            RequestId::String(string) => write!(f, "{string:?}"),
+        }
+    }
 }
 
 impl<S> PartialEq<S> for RequestId
@@ -137,6 +141,27 @@ impl<'de> serde::de::Deserialize<'de> for Version {
     }
 }
 
+impl Message {
+    /// Method of a request or a notification
+    pub fn method(&self) -> Option<&str> {
+        match self {
+            Message::Request(req) => Some(&req.method),
+            Message::Notification(notif) => Some(&notif.method),
+            Message::ResponseError(_) | Message::ResponseSuccess(_) => None,
+        }
+    }
+
+    /// ID of a request or a response
+    pub fn id(&self) -> Option<&RequestId> {
+        match self {
+            Message::Request(req) => Some(&req.id),
+            Message::ResponseError(res) => res.id.as_ref(),
+            Message::ResponseSuccess(res) => Some(&res.id),
+            Message::Notification(_) => None,
+        }
+    }
+}
+
 impl From<Request> for Message {
     fn from(value: Request) -> Self {
         Message::Request(value)
@@ -239,6 +264,18 @@ mod tests {
         }))
     }
 
+    #[test]
+    fn error_with_null_id() {
+        test(json!({
+            "jsonrpc": "2.0",
+            "error": {
+                "code": -32700,
+                "message": "Parse error",
+            },
+            "id": null,
+        }))
+    }
+
     #[test]
     fn error() {
         test(json!({
@@ -1,33 +1,47 @@
 use std::io::{self, ErrorKind};
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::{bail, ensure, Context, Result};
 use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::trace;
+use tracing::{field, trace};
 
 use crate::lsp::jsonrpc::Message;
 
+/// Longest accepted header line, real headers are much shorter
+const MAX_HEADER_LINE: usize = 1024;
+
+/// Default limit of a message body size, see [`LspReader::max_content_length`]
+pub const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+
 pub struct LspReader<R> {
     reader: R,
+    max_content_length: usize,
     batch: Vec<Message>,
     buffer: Vec<u8>,
     tag: &'static str,
+    /// Set after an error where we lost track of message boundaries
+    desynchronized: bool,
+    /// `content-type` header of the last message
+    content_type: Option<String>,
 }
 
 /// Every message begins with a HTTP-style header
 ///
 /// Headers are terminated by `\r\n` sequence and the final header is followed by another `\r\n`.
+/// Lines terminated by a bare `\n` and a body following the final header immediately are
+/// accepted from peers which don't stick to the specification.
 /// The currently recognized headers are `content-type` which is optional and contains a `string`
 /// (something like a MIME-type) and `content-length` which contains the length of the message body
 /// after the final `\r\n` of the header. Header names and values are separated by `: `.
 ///
-/// While we parse the `content-type` header ignore it completely and we don't forward it,
-/// expecting both the server and client to assume the default.
+/// Messages don't keep their `content-type`, it's a property of the connection. The reader
+/// remembers the last one it has seen and [`LspWriter::content_type`] can send it back to the
+/// same peer, a peer which doesn't send any gets the default by omitting the header.
 ///
 /// For mor details see <https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#headerPart>.
 pub struct Header {
     pub content_length: usize,
-    #[allow(dead_code)]
     pub content_type: Option<String>,
 }
 
@@ -38,20 +52,60 @@ where
     pub fn new(reader: R, tag: &'static str) -> Self {
         LspReader {
             reader,
+            max_content_length: MAX_CONTENT_LENGTH,
             batch: Vec::new(),
             buffer: Vec::with_capacity(1024),
             tag,
+            desynchronized: false,
+            content_type: None,
         }
     }
 
+    /// `content-type` header of the last message, if it had any
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Refuse messages with a bigger body instead of allocating a buffer for
+    /// them, defaults to [`MAX_CONTENT_LENGTH`]
+    pub fn max_content_length(mut self, max_content_length: usize) -> Self {
+        self.max_content_length = max_content_length;
+        self
+    }
+
+    /// Get the underlying reader back, including data it has buffered
+    ///
+    /// Messages left over from a batch are lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
     pub async fn read_header(&mut self) -> Result<Option<Header>> {
         let mut content_type = None;
         let mut content_length = None;
+        let mut first_line = true;
 
         loop {
+            // A peer which doesn't separate the headers from the body with an
+            // empty line starts the body right away, a header can't start with
+            // a JSON object or array.
+            if content_length.is_some() {
+                let next = match self.reader.fill_buf().await {
+                    Ok(buf) => buf.first().copied(),
+                    Err(err) => bail!(err),
+                };
+                if matches!(next, Some(b'{' | b'[')) {
+                    break;
+                }
+            }
             self.buffer.clear();
-            match self.reader.read_until(b'\n', &mut self.buffer).await {
-                Ok(0) => return Ok(None), // EOF
+            // `read_until` keeps refilling the buffer until it finds the end
+            // of the line, however the line is split across reads.
+            let mut line = (&mut self.reader).take(MAX_HEADER_LINE as u64);
+            match line.read_until(b'\n', &mut self.buffer).await {
+                // EOF between messages
+                Ok(0) if first_line => return Ok(None),
+                Ok(0) => bail!("unexpected end of stream in header"),
                 Ok(_) => {}
                 Err(err) => match err.kind() {
                     // reader is closed for some reason, no need to log an error about it
@@ -61,36 +115,56 @@ where
                     _ => bail!(err),
                 },
             }
-            let header_text = self
+            if !self.buffer.ends_with(b"\n") {
+                ensure!(
+                    self.buffer.len() < MAX_HEADER_LINE,
+                    "header line longer than {MAX_HEADER_LINE} bytes"
+                );
+                bail!("unexpected end of stream in header");
+            }
+            // Some peers end lines with a bare `\n`, that's unambiguous enough.
+            let line = self
                 .buffer
                 .strip_suffix(b"\r\n")
-                .context(r"malformed header, missing `\r\n` terminator")?;
-            let header_text = str::from_utf8(header_text)
-                .context("malformed header, ascii encoding is a subset of utf-8")?;
+                .or_else(|| self.buffer.strip_suffix(b"\n"))
+                .context("malformed header, missing line terminator")?;
 
-            if header_text.is_empty() {
+            first_line = false;
+            if line.is_empty() {
                 // headers are separated by an empty line from the body
                 break;
             }
-            let (name, value) = match header_text.split_once(": ") {
-                Some(split) => split,
-                None => bail!("malformed header, missing value separator: {}", header_text),
+            let Some((name, value)) = split_header(line) else {
+                bail!(
+                    "malformed header, missing value separator: {}",
+                    String::from_utf8_lossy(line)
+                );
             };
+            let value = str::from_utf8(value)
+                .context("malformed header, ascii encoding is a subset of utf-8")?;
 
-            match name.to_ascii_lowercase().as_str() {
-                "content-type" => {
-                    ensure!(content_type.is_none(), "repeated header content-type");
-                    content_type = Some(value.to_owned());
-                }
-                "content-length" => {
-                    ensure!(content_length.is_none(), "repeated header content-length");
-                    content_length = Some(value.parse::<usize>().context("content-length header")?);
-                }
-                _ => bail!("unknown header name: {name:?}"),
+            if name.eq_ignore_ascii_case(b"content-length") {
+                ensure!(content_length.is_none(), "repeated header content-length");
+                content_length = Some(value.parse::<usize>().context("content-length header")?);
+            } else if name.eq_ignore_ascii_case(b"content-type") {
+                ensure!(content_type.is_none(), "repeated header content-type");
+                // Peers send the same one with every message, keep the string
+                // of the last message instead of allocating a new one.
+                content_type = match self.content_type.take() {
+                    Some(last) if last == value => Some(last),
+                    _ => Some(value.to_owned()),
+                };
+            } else {
+                bail!("unknown header name: {:?}", String::from_utf8_lossy(name));
             }
         }
 
         let content_length = content_length.context("missing required header content-length")?;
+        ensure!(
+            content_length <= self.max_content_length,
+            "content-length {content_length} exceeds the maximum of {}",
+            self.max_content_length,
+        );
         Ok(Some(Header {
             content_length,
             content_type,
@@ -104,19 +178,31 @@ where
     ///
     /// Batch messages are transparently split into individual messages and
     /// delivered in order.
+    ///
+    /// An error in the message body only skips the message, but after an error
+    /// in the header we can't tell where the next message starts and the
+    /// reader behaves as if it was closed.
     pub async fn read_message(&mut self) -> Result<Option<Message>> {
         // return pending messages until the last batch is drained
         if let Some(pending) = self.batch.pop() {
-            trace!(message = ?pending, "<- {}", self.tag);
+            trace_message("recv", self.tag, &pending);
             return Ok(Some(pending));
         }
 
-        let header = self.read_header().await.context("parsing header")?;
-        let header = match header {
-            Some(header) => header,
-            None => return Ok(None),
+        if self.desynchronized {
+            return Ok(None);
+        }
+        let header = match self.read_header().await {
+            Ok(Some(header)) => header,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                self.desynchronized = true;
+                return Err(err.context("parsing header"));
+            }
         };
 
+        self.content_type = header.content_type;
+
         self.buffer.clear();
         self.buffer.resize(header.content_length, 0);
         if let Err(err) = self.reader.read_exact(&mut self.buffer).await {
@@ -130,38 +216,73 @@ where
             }
         }
 
+        // serde_json validates the UTF-8 while parsing, on the happy path we
+        // only go over the body once.
         let bytes = self.buffer.as_slice();
-        let body = str::from_utf8(bytes)
-            .with_context(|| {
-                let lossy_utf8 = String::from_utf8_lossy(bytes);
-                format!("parsing body `{lossy_utf8}`")
-            })
-            .context("parsing LSP message")?;
+        let body_context = || format!("parsing body `{}`", truncate_body(bytes));
 
         // handle batches
-        if body.starts_with('[') {
-            self.batch = serde_json::from_str(body)
-                .with_context(|| format!("parsing body `{body}`"))
+        if bytes.starts_with(b"[") {
+            self.batch = serde_json::from_slice(bytes)
+                .with_context(body_context)
                 .context("parsing LSP message")?;
             // we're popping the messages from the end of the vec
             self.batch.reverse();
             let message = self.batch.pop().context("received an empty batch")?;
-            trace!(?message, "<- {}", self.tag);
+            trace_message("recv", self.tag, &message);
             Ok(Some(message))
         } else {
-            let message = serde_json::from_str(body)
-                .with_context(|| format!("parsing body `{body}`"))
+            let message = serde_json::from_slice(bytes)
+                .with_context(body_context)
                 .context("parsing LSP message")?;
-            trace!(?message, "<- {}", self.tag);
+            trace_message("recv", self.tag, &message);
             Ok(Some(message))
         }
     }
 }
 
+/// Split a header line into its name and value at the first `: `
+fn split_header(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let split = line.windows(2).position(|sep| sep == b": ")?;
+    Some((&line[..split], &line[split + 2..]))
+}
+
+/// How much of an invalid message body is included in the error
+const MAX_LOGGED_BODY: usize = 1024;
+
+/// Lossy UTF-8 of the beginning of a message body for error messages
+fn truncate_body(bytes: &[u8]) -> String {
+    if bytes.len() <= MAX_LOGGED_BODY {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    let start = String::from_utf8_lossy(&bytes[..MAX_LOGGED_BODY]);
+    format!("{start}... ({} bytes total)", bytes.len())
+}
+
+/// Log a message with the fields identifying it
+fn trace_message(direction: &'static str, peer: &'static str, message: &Message) {
+    let arrow = match direction {
+        "recv" => "<-",
+        _ => "->",
+    };
+    trace!(
+        direction,
+        peer,
+        method = message.method(),
+        id = message.id().map(field::display),
+        ?message,
+        "{arrow} {peer}",
+    );
+}
+
 pub struct LspWriter<W> {
     writer: W,
     buffer: Vec<u8>,
     tag: &'static str,
+    /// Incremented by the size of every written message
+    bytes: Option<&'static AtomicU64>,
+    /// Sent as the `content-type` header if set
+    content_type: Option<String>,
 }
 
 impl<W> LspWriter<W>
@@ -173,20 +294,288 @@ where
             writer,
             buffer: Vec::with_capacity(1024),
             tag,
+            bytes: None,
+            content_type: None,
         }
     }
 
+    /// Get the underlying writer back
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Send a `content-type` header after the `content-length` of every message
+    pub fn set_content_type(&mut self, content_type: Option<&str>) {
+        self.content_type = content_type.map(String::from);
+    }
+
+    /// Add the size of every written message to `counter`
+    pub fn count_bytes(mut self, counter: &'static AtomicU64) -> Self {
+        self.bytes = Some(counter);
+        self
+    }
+
     /// serialize LSP message into a writer, prepending the appropriate content-length header
     pub async fn write_message(&mut self, message: &Message) -> io::Result<()> {
-        trace!(?message, "-> {}", self.tag);
+        trace_message("send", self.tag, message);
 
         self.buffer.clear();
         serde_json::to_writer(&mut self.buffer, message).expect("BUG: invalid message");
 
-        self.writer
-            .write_all(format!("Content-Length: {}\r\n\r\n", self.buffer.len()).as_bytes())
-            .await?;
+        let header = match &self.content_type {
+            Some(content_type) => format!(
+                "Content-Length: {}\r\nContent-Type: {content_type}\r\n\r\n",
+                self.buffer.len(),
+            ),
+            None => format!("Content-Length: {}\r\n\r\n", self.buffer.len()),
+        };
+        self.writer.write_all(header.as_bytes()).await?;
         self.writer.write_all(&self.buffer).await?;
-        self.writer.flush().await
+        self.writer.flush().await?;
+
+        if let Some(bytes) = self.bytes {
+            bytes.fetch_add(self.buffer.len() as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+
+    fn frame(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{body}", body.len())
+    }
+
+    async fn read_all(input: &[u8]) -> Vec<Result<Option<Message>>> {
+        let mut reader = LspReader::new(input, "test");
+        let mut results = Vec::new();
+        loop {
+            let result = reader.read_message().await;
+            let done = matches!(result, Ok(None));
+            results.push(result);
+            if done {
+                return results;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_body_skips_message() {
+        let input = frame("{not json") + &frame(MESSAGE);
+        let results = read_all(input.as_bytes()).await;
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_err());
+        assert!(matches!(results[1], Ok(Some(Message::Notification(_)))));
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_skips_message() {
+        let body = b"{\"jsonrpc\":\"2.0\",\"method\":\"\xff\",\"params\":{}}";
+        let mut input = format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+        input.extend_from_slice(body);
+        input.extend_from_slice(frame(MESSAGE).as_bytes());
+        let results = read_all(&input).await;
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_err());
+        assert!(matches!(results[1], Ok(Some(Message::Notification(_)))));
+    }
+
+    #[tokio::test]
+    async fn invalid_body_error_is_truncated() {
+        let body = "{".repeat(10 * MAX_LOGGED_BODY);
+        let results = read_all(frame(&body).as_bytes()).await;
+        let err = format!("{:#}", results[0].as_ref().unwrap_err());
+        assert!(err.len() < 2 * MAX_LOGGED_BODY, "{err}");
+        assert!(err.contains("(10240 bytes total)"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn invalid_header_closes_reader() {
+        for header in [
+            "Content-Length: 52\r\nX-Unknown: 1\r\n\r\n",
+            "Content-Length: 52\r\nContent-Length: 52\r\n\r\n",
+            "Content-Length: x\r\n\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "Content-Length 52\r\n\r\n",
+        ] {
+            let input = header.to_owned() + MESSAGE + &frame(MESSAGE);
+            let results = read_all(input.as_bytes()).await;
+            assert_eq!(results.len(), 2, "{header:?}");
+            assert!(results[0].is_err(), "{header:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn long_header_closes_reader() {
+        let input = "Content-Length: 52".to_owned() + &" ".repeat(MAX_HEADER_LINE) + "\r\n\r\n";
+        let input = input + MESSAGE + &frame(MESSAGE);
+        let results = read_all(input.as_bytes()).await;
+        assert_eq!(results.len(), 2);
+        let err = results[0].as_ref().unwrap_err();
+        assert!(format!("{err:#}").contains("header line longer"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn huge_content_length_closes_reader() {
+        let input = "Content-Length: 4000000000\r\n\r\n".to_owned() + MESSAGE;
+        let mut reader = LspReader::new(input.as_bytes(), "test").max_content_length(1024);
+        let err = reader.read_message().await.unwrap_err();
+        assert!(
+            format!("{err:#}").contains("exceeds the maximum"),
+            "{err:#}"
+        );
+        assert!(reader.read_message().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn content_type_roundtrip() {
+        let content_type = "application/vscode-jsonrpc; charset=utf-8";
+        let input = format!(
+            "Content-Length: {}\r\nContent-Type: {content_type}\r\n\r\n{MESSAGE}",
+            MESSAGE.len(),
+        );
+        let mut reader = LspReader::new(input.as_bytes(), "test");
+        let message = reader.read_message().await.unwrap().unwrap();
+        assert_eq!(reader.content_type(), Some(content_type));
+
+        let mut output = Vec::new();
+        let mut writer = LspWriter::new(&mut output, "test");
+        writer.set_content_type(reader.content_type());
+        writer.write_message(&message).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), input);
+    }
+
+    #[tokio::test]
+    async fn batches_are_split() {
+        let batch = format!(r#"[{MESSAGE},{{"jsonrpc":"2.0","method":"exit","params":null}}]"#);
+        let input = frame(&batch) + &frame(MESSAGE);
+        let results = read_all(input.as_bytes()).await;
+        assert_eq!(results.len(), 4);
+        let methods = results[..3]
+            .iter()
+            .map(|result| match result {
+                Ok(Some(Message::Notification(notif))) => notif.method.as_str(),
+                other => panic!("expected notification, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(methods, ["initialized", "exit", "initialized"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_batch_skips_message() {
+        for batch in ["[]", "[{not json}]", r#"[{"jsonrpc":"2.0"}]"#] {
+            let input = frame(batch) + &frame(MESSAGE);
+            let results = read_all(input.as_bytes()).await;
+            assert_eq!(results.len(), 3, "{batch}");
+            assert!(results[0].is_err(), "{batch}");
+            assert!(
+                matches!(results[1], Ok(Some(Message::Notification(_)))),
+                "{batch}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn bare_newlines_are_accepted() {
+        let len = MESSAGE.len();
+        for header in [
+            format!("Content-Length: {len}\n\n"),
+            format!("Content-Length: {len}\n\r\n"),
+            format!("Content-Length: {len}\r\n\n"),
+            format!("Content-Type: text\nContent-Length: {len}\r\n\n"),
+            format!("Content-Length: {len}\r\n"),
+            format!("Content-Length: {len}\n"),
+        ] {
+            let input = header.clone() + MESSAGE + &frame(MESSAGE);
+            let results = read_all(input.as_bytes()).await;
+            assert_eq!(results.len(), 3, "{header:?}");
+            for result in &results[..2] {
+                assert!(
+                    matches!(result, Ok(Some(Message::Notification(_)))),
+                    "{header:?}: {result:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn eof_in_header_is_an_error() {
+        let results = read_all(b"Content-Length: 5").await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn eof_between_headers_is_an_error() {
+        let results = read_all(b"Content-Length: 5\r\n").await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+    }
+
+    /// Delivers its input one byte per read, every other read isn't ready yet
+    struct Trickle {
+        input: Vec<u8>,
+        position: usize,
+        ready: bool,
+    }
+
+    impl tokio::io::AsyncRead for Trickle {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            if let Some(&byte) = self.input.get(self.position) {
+                buf.put_slice(&[byte]);
+                self.position += 1;
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_split_into_single_bytes() {
+        let content_type = "application/vscode-jsonrpc; charset=utf-8";
+        let input = format!(
+            "Content-Length: {}\r\nContent-Type: {content_type}\r\n\r\n{MESSAGE}",
+            MESSAGE.len(),
+        ) + &frame(MESSAGE);
+        let trickle = Trickle {
+            input: input.into_bytes(),
+            position: 0,
+            ready: false,
+        };
+        let mut reader = LspReader::new(tokio::io::BufReader::with_capacity(1, trickle), "test");
+        let message = reader.read_message().await.unwrap();
+        assert!(matches!(message, Some(Message::Notification(_))));
+        assert_eq!(reader.content_type(), Some(content_type));
+        let message = reader.read_message().await.unwrap();
+        assert!(matches!(message, Some(Message::Notification(_))));
+        assert!(reader.read_message().await.unwrap().is_none());
+
+        let trickle = Trickle {
+            input: b"Content-Length: 52\r\nContent-Type: text".to_vec(),
+            position: 0,
+            ready: false,
+        };
+        let mut reader = LspReader::new(tokio::io::BufReader::with_capacity(1, trickle), "test");
+        let err = reader.read_message().await.unwrap_err();
+        assert!(format!("{err:#}").contains("end of stream"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn eof_in_body_closes_reader() {
+        let input = frame(MESSAGE);
+        let results = read_all(&input.as_bytes()[..input.len() - 1]).await;
+        assert_eq!(results.len(), 1);
     }
 }
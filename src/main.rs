@@ -1,40 +1,65 @@
 use std::env;
+use std::path::PathBuf;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use ra_multiplex::config::Config;
+use ra_multiplex::config::{Config, LogFormat};
 use ra_multiplex::{ext, proxy, server};
-use tracing::info;
+use tracing::{error, info};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version = ra_multiplex::VERSION.as_str(), about, long_about = None)]
 struct Cli {
     /// No command defaults to client
     #[command(subcommand)]
     command: Option<Cmd>,
+
+    /// Port to listen on or connect to, overrides the port of `listen` and
+    /// `connect` config options
+    #[arg(long = "port", env = "RA_MUX_PORT", global = true)]
+    port: Option<u16>,
+
+    /// Log format, either "pretty" or "json", overrides the `log_format` config
+    /// option
+    #[arg(long = "log-format", env = "RA_MUX_LOG_FORMAT", global = true)]
+    log_format: Option<LogFormat>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Cmd {
     /// Connect to an ra-mux server [default]
     Client {
-        /// Path to the LSP server executable
+        /// Path to the LSP server executable [default: `server` from config]
         #[arg(
             long = "server-path",
             alias = "ra-mux-server",
             env = "RA_MUX_SERVER",
-            default_value = "rust-analyzer",
             name = "SERVER_PATH"
         )]
-        server: String,
+        server: Option<String>,
 
         /// Arguments passed to the LSP server
         #[arg(name = "SERVER_ARGS")]
         args: Vec<String>,
+
+        /// Start a language server just for this client instead of sharing
+        /// the workspace's
+        #[arg(long = "private")]
+        private: bool,
+
+        /// Name shown in `status` and the server logs [default: the editor
+        /// name and the workspace directory]
+        #[arg(long = "client-name", env = "RA_MUX_CLIENT_NAME")]
+        client_name: Option<String>,
     },
 
     /// Start a ra-mux server
-    Server {},
+    Server {
+        /// Give every client its own language server and relay messages
+        /// unchanged, for debugging
+        #[arg(long = "no-multiplex")]
+        no_multiplex: bool,
+    },
 
     /// Print server status
     Status {
@@ -43,6 +68,40 @@ enum Cmd {
         json: bool,
     },
 
+    /// Check the server health
+    ///
+    /// Exits with an error if the server can't be reached or any instance is
+    /// not running or not responding.
+    Health {
+        /// Output data as machine readable JSON
+        #[clap(long = "json", default_value = "false")]
+        json: bool,
+    },
+
+    /// Kill the language servers of a workspace
+    ///
+    /// Clients of the workspace are disconnected, when they reconnect a new
+    /// language server is started.
+    Kill {
+        /// Workspace root of the instances to kill, or a path inside it
+        workspace_root: PathBuf,
+    },
+
+    /// Keep the language servers of a workspace running when they're idle
+    Pin {
+        /// Workspace root of the instances to pin, or a path inside it
+        workspace_root: PathBuf,
+    },
+
+    /// Shut down the language servers of a workspace when they're idle again
+    ///
+    /// Instances without clients are shut down after `instance_timeout` from
+    /// now.
+    Unpin {
+        /// Workspace root of the instances to unpin, or a path inside it
+        workspace_root: PathBuf,
+    },
+
     /// Print server configuration
     Config {},
 
@@ -51,35 +110,159 @@ enum Cmd {
     /// For rust-analyzer send the `rust-analyzer/reloadWorkspace` extension request.
     /// Do nothing for other language servers.
     Reload {},
+
+    /// Stop accepting new clients and exit once the connected ones are gone
+    ///
+    /// Unlike SIGTERM this doesn't end active sessions, a supervisor can start
+    /// a new server once the old one exited.
+    Drain {
+        /// Seconds to wait for clients to disconnect before shutting down
+        /// anyway [default: wait indefinitely]
+        #[arg(long = "timeout")]
+        timeout: Option<u32>,
+    },
+
+    /// Start the language server for a workspace before any editor opens it
+    ///
+    /// The language server keeps running, even when `instance_timeout` is up,
+    /// until the first client connects to it.
+    Warmup {
+        /// Directory the editors will open
+        workspace_root: PathBuf,
+
+        /// Path to the LSP server executable [default: `server` from config]
+        #[arg(long = "server-path", env = "RA_MUX_SERVER", name = "SERVER_PATH")]
+        server: Option<String>,
+
+        /// Arguments passed to the LSP server
+        #[arg(name = "SERVER_ARGS")]
+        args: Vec<String>,
+    },
+
+    /// Print the messages exchanged with the language servers of a workspace
+    ///
+    /// Shows the direction, method, ID and size of every message until the
+    /// language servers exit or the command is interrupted. Messages are
+    /// skipped instead of slowing the language servers down when the output
+    /// doesn't keep up.
+    Trace {
+        /// Workspace root of the instances to trace, or a path inside it
+        workspace_root: PathBuf,
+
+        /// Output events as machine readable JSON, one per line
+        #[clap(long = "json", default_value = "false")]
+        json: bool,
+    },
+
+    /// Replace the language servers of a workspace with new processes
+    ///
+    /// Picks up a language server binary updated by `rustup update` or
+    /// similar. The new server starts while the old one keeps serving the
+    /// editors, they stay connected.
+    Handover {
+        /// Workspace root of the instances to hand over, or a path inside it
+        #[clap(required_unless_present = "all")]
+        workspace_root: Option<PathBuf>,
+
+        /// Hand over every instance
+        #[clap(long = "all", conflicts_with = "workspace_root")]
+        all: bool,
+    },
+
+    /// List the requests a workspace's language servers and clients haven't
+    /// answered yet
+    ///
+    /// Requests which stay around for long are never answered or weren't
+    /// cleaned up, their IDs are remembered until the server restarts.
+    DebugIdmap {
+        /// Workspace root of the instances to inspect, or a path inside it
+        workspace_root: PathBuf,
+
+        /// Most requests listed per instance and direction, the oldest first
+        #[clap(long = "limit", default_value = "20")]
+        limit: usize,
+
+        /// Output machine readable JSON
+        #[clap(long = "json", default_value = "false")]
+        json: bool,
+    },
+
+    /// Check the setup works, from reaching the server to a language server
+    /// answering `initialize` through it
+    ///
+    /// Starts a private instance for a throwaway cargo project in the temporary
+    /// directory and prints whether each step passed.
+    Doctor {},
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    let config = match Config::try_load() {
-        Ok(config) => {
-            config.init_logger();
-            config
-        }
-        Err(err) => {
-            let config = Config::default();
-            config.init_logger();
-            // Log only after the logger has been initialized
-            info!(?err, "cannot load config file, continuing with defaults");
-            config
-        }
+    let (mut config, load_err) = match Config::try_load() {
+        Ok(config) => (config, None),
+        Err(err) => (Config::default(), Some(err)),
     };
+    if let Some(log_format) = cli.log_format {
+        config.log_format = log_format;
+    }
+    config.init_logger();
+    if let Some(err) = load_err {
+        // Log only after the logger has been initialized
+        info!(?err, "cannot load config file, continuing with defaults");
+    }
+
+    if let Some(port) = cli.port {
+        config.set_port(port);
+    }
 
     match cli.command {
-        Some(Cmd::Server {}) => server::run(&config).await,
-        Some(Cmd::Client { server, args }) => proxy::run(&config, server, args).await,
+        Some(Cmd::Server { no_multiplex }) => {
+            config.no_multiplex = no_multiplex;
+            match server::run(&config).await {
+                Err(err) if err.is::<server::AddrInUse>() => {
+                    error!("{err}");
+                    std::process::exit(server::EXIT_ADDR_IN_USE);
+                }
+                result => result,
+            }
+        }
+        Some(Cmd::Client {
+            server,
+            args,
+            private,
+            client_name,
+        }) => proxy::run(&config, server, args, private, client_name).await,
         Some(Cmd::Status { json }) => ext::status(&config, json).await,
+        Some(Cmd::Health { json }) => ext::health(&config, json).await,
+        Some(Cmd::Kill { workspace_root }) => ext::kill(&config, workspace_root).await,
+        Some(Cmd::Pin { workspace_root }) => ext::pin(&config, workspace_root, true).await,
+        Some(Cmd::Unpin { workspace_root }) => ext::pin(&config, workspace_root, false).await,
         Some(Cmd::Config {}) => ext::config(&config).await,
         Some(Cmd::Reload {}) => ext::reload(&config).await,
+        Some(Cmd::Drain { timeout }) => ext::drain(&config, timeout).await,
+        Some(Cmd::Warmup {
+            workspace_root,
+            server,
+            args,
+        }) => ext::warmup(&config, workspace_root, server, args).await,
+        Some(Cmd::Trace {
+            workspace_root,
+            json,
+        }) => ext::trace(&config, workspace_root, json).await,
+        Some(Cmd::Handover {
+            workspace_root,
+            all: _,
+        }) => ext::handover(&config, workspace_root).await,
+        Some(Cmd::DebugIdmap {
+            workspace_root,
+            limit,
+            json,
+        }) => ext::id_map(&config, workspace_root, limit, json).await,
+        Some(Cmd::Doctor {}) => ext::doctor(&config).await,
         None => {
-            let server_path = env::var("RA_MUX_SERVER").unwrap_or_else(|_| "rust-analyzer".into());
-            proxy::run(&config, server_path, vec![]).await
+            let server_path = env::var("RA_MUX_SERVER").ok();
+            proxy::run(&config, server_path, vec![], false, None).await
         }
     }
 }
@@ -0,0 +1,358 @@
+//! Prometheus metrics endpoint
+//!
+//! A minimal HTTP server answering `GET /metrics` with the [text exposition
+//! format](https://prometheus.io/docs/instrumenting/exposition_formats/). It's
+//! only started when the `metrics_listen` option is set.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tokio::task;
+use tracing::{debug, info, instrument, warn, Instrument};
+
+use crate::config::Address;
+use crate::instance::{InstanceGauges, InstanceMap};
+use crate::socketwrapper::{ListenOptions, Listener, Stream};
+
+/// Bytes of messages written to language server stdin
+pub static BYTES_TO_SERVERS: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes of messages written to client connections
+pub static BYTES_TO_CLIENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Language servers successfully restarted after a crash
+pub static RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+/// Time from forwarding a client request to the server until its response
+/// arrives, by method
+static LATENCY: std::sync::Mutex<BTreeMap<String, Histogram>> =
+    std::sync::Mutex::new(BTreeMap::new());
+
+/// Upper bounds of the latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Clients can send any method, the ones after this many are counted
+/// together as `other`
+const MAX_LATENCY_METHODS: usize = 128;
+
+/// Scrapers sending anything bigger than this aren't Prometheus
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// How long a scraper can take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative counts of observations in each of [`LATENCY_BUCKETS`]
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Record how long the language server took to answer a request
+pub fn observe_latency(method: &str, latency: Duration) {
+    let mut histograms = LATENCY.lock().unwrap();
+    let method = if histograms.contains_key(method) || histograms.len() < MAX_LATENCY_METHODS {
+        method
+    } else {
+        "other"
+    };
+    histograms
+        .entry(method.to_owned())
+        .or_default()
+        .observe(latency.as_secs_f64());
+}
+
+#[instrument("metrics", skip_all)]
+pub async fn run(address: Address, instance_map: Arc<Mutex<InstanceMap>>) -> Result<()> {
+    let listener = Listener::bind(&address, &ListenOptions::default())
+        .await
+        .context("listen")?;
+    info!(socket = ?address, "serving metrics");
+    loop {
+        let (socket, _addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(?err, "metrics listener error");
+                continue;
+            }
+        };
+        let instance_map = instance_map.clone();
+        task::spawn(
+            async move {
+                if let Err(err) = serve(socket, &instance_map).await {
+                    debug!(?err, "metrics request failed");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Answer one HTTP request and close the connection
+async fn serve(socket: Stream, instance_map: &Mutex<InstanceMap>) -> Result<()> {
+    let (read, mut write) = socket.into_split();
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request(read))
+        .await
+        .context("request timed out")??;
+
+    let (status, body) = match request_line.split(' ').take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => {
+            let gauges = instance_map.lock().await.gauges().await;
+            ("200 OK", render(&gauges))
+        }
+        ["GET", _] => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {body}",
+        body.len(),
+    );
+    write.write_all(response.as_bytes()).await?;
+    write.shutdown().await?;
+    Ok(())
+}
+
+/// Read the request headers and return the request line
+async fn read_request(read: impl AsyncRead + Unpin) -> Result<String> {
+    let mut reader = BufReader::new(read).take(MAX_REQUEST_SIZE as u64);
+    let mut request_line = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("request ended before the end of headers");
+        }
+        if line.trim_end().is_empty() {
+            return Ok(request_line);
+        }
+        if request_line.is_empty() {
+            request_line = line.trim_end().to_owned();
+        }
+    }
+}
+
+/// Format all metrics in the Prometheus text format
+pub fn render(instances: &[InstanceGauges]) -> String {
+    let mut out = String::new();
+    let counter = |out: &mut String, name: &str, help: &str| {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} counter").unwrap();
+    };
+    let gauge = |out: &mut String, name: &str, help: &str| {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} gauge").unwrap();
+    };
+
+    gauge(
+        &mut out,
+        "ra_multiplex_instances",
+        "Live language server instances.",
+    );
+    writeln!(out, "ra_multiplex_instances {}", instances.len()).unwrap();
+
+    gauge(
+        &mut out,
+        "ra_multiplex_clients",
+        "Clients connected to an instance.",
+    );
+    for instance in instances {
+        let labels = labels(instance);
+        writeln!(out, "ra_multiplex_clients{{{labels}}} {}", instance.clients).unwrap();
+    }
+
+    gauge(
+        &mut out,
+        "ra_multiplex_pending_requests",
+        "Client requests waiting for a response from the language server.",
+    );
+    for instance in instances {
+        let labels = labels(instance);
+        let pending = instance.pending_requests;
+        writeln!(out, "ra_multiplex_pending_requests{{{labels}}} {pending}").unwrap();
+    }
+
+    gauge(
+        &mut out,
+        "ra_multiplex_pending_server_requests",
+        "Language server requests waiting for a response from the clients.",
+    );
+    for instance in instances {
+        let labels = labels(instance);
+        let pending = instance.pending_server_requests;
+        writeln!(
+            out,
+            "ra_multiplex_pending_server_requests{{{labels}}} {pending}"
+        )
+        .unwrap();
+    }
+
+    gauge(
+        &mut out,
+        "ra_multiplex_queued_messages",
+        "LSP messages waiting to be written in each direction.",
+    );
+    for instance in instances {
+        let labels = labels(instance);
+        for (direction, queued) in [
+            ("to_server", instance.queued_to_server),
+            ("to_client", instance.queued_to_clients),
+        ] {
+            writeln!(
+                out,
+                "ra_multiplex_queued_messages{{{labels},direction=\"{direction}\"}} {queued}"
+            )
+            .unwrap();
+        }
+    }
+
+    counter(
+        &mut out,
+        "ra_multiplex_relayed_bytes_total",
+        "Bytes of LSP messages relayed in each direction.",
+    );
+    for (direction, bytes) in [
+        ("to_server", &BYTES_TO_SERVERS),
+        ("to_client", &BYTES_TO_CLIENTS),
+    ] {
+        let bytes = bytes.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "ra_multiplex_relayed_bytes_total{{direction=\"{direction}\"}} {bytes}"
+        )
+        .unwrap();
+    }
+
+    counter(
+        &mut out,
+        "ra_multiplex_restarts_total",
+        "Crashed language servers which were restarted.",
+    );
+    let restarts = RESTARTS.load(Ordering::Relaxed);
+    writeln!(out, "ra_multiplex_restarts_total {restarts}").unwrap();
+
+    let name = "ra_multiplex_request_duration_seconds";
+    writeln!(
+        out,
+        "# HELP {name} Time until the language server answered a client request."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE {name} histogram").unwrap();
+    for (method, histogram) in LATENCY.lock().unwrap().iter() {
+        let method = escape(method);
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            writeln!(
+                out,
+                "{name}_bucket{{method=\"{method}\",le=\"{bound}\"}} {count}"
+            )
+            .unwrap();
+        }
+        let Histogram { count, sum, .. } = histogram;
+        writeln!(
+            out,
+            "{name}_bucket{{method=\"{method}\",le=\"+Inf\"}} {count}"
+        )
+        .unwrap();
+        writeln!(out, "{name}_sum{{method=\"{method}\"}} {sum}").unwrap();
+        writeln!(out, "{name}_count{{method=\"{method}\"}} {count}").unwrap();
+    }
+
+    out
+}
+
+fn labels(instance: &InstanceGauges) -> String {
+    format!(
+        "workspace=\"{}\",server=\"{}\"",
+        escape(&instance.workspace_root),
+        escape(&instance.server),
+    )
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+#[test]
+fn render_instance_gauges() {
+    let out = render(&[InstanceGauges {
+        workspace_root: "/home/user/\"proj\"".into(),
+        server: "rust-analyzer".into(),
+        clients: 2,
+        pending_requests: 3,
+        pending_server_requests: 1,
+        queued_to_server: 4,
+        queued_to_clients: 5,
+    }]);
+    assert!(out.contains("ra_multiplex_instances 1\n"), "{out}");
+    assert!(
+        out.contains(
+            "ra_multiplex_clients{workspace=\"/home/user/\\\"proj\\\"\",server=\"rust-analyzer\"} 2\n"
+        ),
+        "{out}"
+    );
+    assert!(
+        out.contains(
+            "ra_multiplex_pending_server_requests{workspace=\"/home/user/\\\"proj\\\"\",server=\"rust-analyzer\"} 1\n"
+        ),
+        "{out}"
+    );
+    assert!(
+        out.contains(
+            "ra_multiplex_queued_messages{workspace=\"/home/user/\\\"proj\\\"\",server=\"rust-analyzer\",direction=\"to_client\"} 5\n"
+        ),
+        "{out}"
+    );
+    assert!(
+        out.contains("# TYPE ra_multiplex_restarts_total counter\n"),
+        "{out}"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn render_latency_histogram() {
+    observe_latency("test/latency", Duration::from_millis(20));
+    observe_latency("test/latency", Duration::from_secs(20));
+    let out = render(&[]);
+    for line in [
+        "# TYPE ra_multiplex_request_duration_seconds histogram\n",
+        "ra_multiplex_request_duration_seconds_bucket{method=\"test/latency\",le=\"0.01\"} 0\n",
+        "ra_multiplex_request_duration_seconds_bucket{method=\"test/latency\",le=\"0.025\"} 1\n",
+        "ra_multiplex_request_duration_seconds_bucket{method=\"test/latency\",le=\"10\"} 1\n",
+        "ra_multiplex_request_duration_seconds_bucket{method=\"test/latency\",le=\"+Inf\"} 2\n",
+        "ra_multiplex_request_duration_seconds_count{method=\"test/latency\"} 2\n",
+    ] {
+        assert!(out.contains(line), "{out}");
+    }
+}
@@ -1,31 +1,54 @@
-use std::collections::BTreeMap;
 use std::env;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
 
 use anyhow::{bail, Context as _, Result};
-use tokio::io::{self, BufStream};
+use tokio::io::{self, BufReader, BufStream};
+use tokio::time::Instant;
+use tracing::{debug, info};
 
-use crate::config::Config;
+use crate::config::{Address, Config};
 use crate::lsp::ext::{LspMuxOptions, Request};
 use crate::lsp::jsonrpc::Message;
 use crate::lsp::transport::{LspReader, LspWriter};
 use crate::lsp::{InitializationOptions, InitializeParams};
-use crate::socketwrapper::Stream;
+use crate::socketwrapper::{OwnedReadHalf, Stream};
+
+/// Name the editor and the workspace it's used for, like `Neovim/ra-multiplex`
+fn default_client_name(params: &InitializeParams, cwd: Option<&str>) -> Option<String> {
+    let editor = params.client_info.as_ref().map(|info| info.name.as_str());
+    let folder = match params.workspace_folders.first() {
+        Some(folder) => Some(folder.name.as_str()),
+        None => cwd
+            .and_then(|cwd| Path::new(cwd).file_name())
+            .and_then(|name| name.to_str()),
+    };
+    match (editor, folder) {
+        (Some(editor), Some(folder)) => Some(format!("{editor}/{folder}")),
+        (Some(name), None) | (None, Some(name)) => Some(name.to_owned()),
+        (None, None) => None,
+    }
+}
+
+pub async fn run(
+    config: &Config,
+    server: Option<String>,
+    args: Vec<String>,
+    private: bool,
+    client_name: Option<String>,
+) -> Result<()> {
+    let (server, args) = config.server_command(server, args);
 
-pub async fn run(config: &Config, server: String, args: Vec<String>) -> Result<()> {
     let cwd = env::current_dir()
         .ok()
         .and_then(|path| path.to_str().map(String::from));
 
-    let mut env = BTreeMap::new();
-    for key in &config.pass_environment {
-        if let Ok(val) = std::env::var(key) {
-            env.insert(key.clone(), val);
-        }
-    }
+    let env = config.passed_environment();
 
-    let mut stream = Stream::connect(&config.connect)
-        .await
-        .context("connecting to server")?;
+    let token = config.auth_token().context("auth token")?;
+
+    let stream = connect(config).await.context("connecting to server")?;
     let mut stdio = BufStream::new(io::join(io::stdin(), io::stdout()));
 
     // Wait for the client to send `initialize` request.
@@ -38,31 +61,151 @@ pub async fn run(config: &Config, server: String, args: Vec<String>) -> Result<(
     // Patch `initializationOptions` with our own data.
     let mut params = serde_json::from_value::<InitializeParams>(req.params)
         .context("parse initialize request params")?;
+    let client_name = client_name.or_else(|| default_client_name(&params, cwd.as_deref()));
     params
         .initialization_options
         .get_or_insert_with(InitializationOptions::default)
         .lsp_mux
         .get_or_insert_with(|| LspMuxOptions {
             version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+            token,
             method: Request::Connect {
                 server,
                 args,
                 env,
                 cwd,
+                private,
+                compression: config.compression,
+                client_name,
             },
         });
     req.params = serde_json::to_value(params).expect("BUG: invalid data");
 
     // Forward the modified `initialize` request.
-    let mut writer = LspWriter::new(&mut stream, "lspmux");
+    let (read, write) = stream.into_split();
+    let mut writer = LspWriter::new(write, "lspmux");
     writer
         .write_message(&req.into())
         .await
         .context("forward initialize request")?;
+    let mut read = BufReader::new(read);
+    let mut write = writer.into_inner();
+
+    if let Some(compression) = config.compression {
+        // Nothing else is sent until the server confirmed it, the editor
+        // waits for the `initialize` response anyway.
+        let mut reader = LspReader::new(&mut read, "lspmux");
+        match reader
+            .read_message()
+            .await?
+            .context("server closed the connection")?
+        {
+            Message::Notification(notif) if notif.method == "$/lspmux/compression" => {
+                debug!(?compression, "compressing connection");
+                read = BufReader::new(OwnedReadHalf::zstd(read));
+                write = write.zstd();
+            }
+            message => {
+                info!("server doesn't support compression");
+                LspWriter::new(&mut stdio, "client")
+                    .write_message(&message)
+                    .await
+                    .context("forward server message")?;
+            }
+        }
+    }
 
     // Forward everything else unmodified.
-    io::copy_bidirectional(&mut stream, &mut stdio)
+    io::copy_bidirectional(&mut io::join(read, write), &mut stdio)
         .await
         .context("io error")?;
     Ok(())
 }
+
+/// The longest delay between two connection attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long we wait for a server we started to accept connections
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connect to the server
+///
+/// If the server isn't reachable it's started if `spawn_server` is set and we
+/// keep retrying with an exponential backoff for `connect_retry` seconds.
+pub(crate) async fn connect(config: &Config) -> Result<Stream> {
+    let mut deadline = Instant::now() + Duration::from_secs(config.connect_retry.into());
+    let mut delay = Duration::from_millis(50);
+    let mut spawned = false;
+    loop {
+        let err = match Stream::connect(&config.connect).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => err,
+        };
+        if config.spawn_server && !spawned {
+            info!(?err, "server is not running, starting it");
+            spawn_server(config).context("starting server")?;
+            spawned = true;
+            deadline = deadline.max(Instant::now() + SPAWN_TIMEOUT);
+        }
+        if Instant::now() + delay > deadline {
+            return Err(err);
+        }
+        debug!(?err, ?delay, "server is not reachable, retrying");
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+/// Start `ra-multiplex server` in the background
+///
+/// The server keeps running after we exit, it shuts down its instances after
+/// the usual `instance_timeout` but the server itself needs to be stopped.
+fn spawn_server(config: &Config) -> Result<()> {
+    let exe = env::current_exe().context("finding own executable")?;
+    let mut command = std::process::Command::new(exe);
+    command
+        .arg("server")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // The server must listen where we connect, `--port` might have only been
+    // given to us.
+    if let Address::Tcp(_, port) = config.connect {
+        command.arg("--port").arg(port.to_string());
+    }
+    // Don't take the server down with the editor's process group.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    command.spawn().context("spawning server")?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn client_names_default_to_the_editor_and_workspace() {
+    let params = |params| serde_json::from_value::<InitializeParams>(params).unwrap();
+    let neovim = params(serde_json::json!({
+        "processId": null,
+        "clientInfo": { "name": "Neovim" },
+        "rootUri": null,
+        "initializationOptions": null,
+        "capabilities": {},
+        "workspaceFolders": [{ "uri": "file:///home/user/project", "name": "project" }],
+    }));
+    assert_eq!(
+        default_client_name(&neovim, Some("/home/user/other")).as_deref(),
+        Some("Neovim/project")
+    );
+
+    let anonymous = params(serde_json::json!({
+        "processId": null,
+        "rootUri": null,
+        "initializationOptions": null,
+        "capabilities": {},
+    }));
+    assert_eq!(
+        default_client_name(&anonymous, Some("/home/user/other")).as_deref(),
+        Some("other")
+    );
+    assert_eq!(default_client_name(&anonymous, None), None);
+}
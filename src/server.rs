@@ -1,36 +1,182 @@
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fmt, io};
 
 use anyhow::{Context, Result};
-use tokio::task;
-use tracing::{error, info, info_span, warn, Instrument};
+use tokio::process::Command;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::{select, task};
+use tracing::{error, field, info, info_span, warn, Instrument};
 
 use crate::client;
-use crate::config::Config;
-use crate::instance::InstanceMap;
+use crate::config::{Address, Config};
+use crate::ext;
+use crate::instance::{self, InstanceKey, InstanceMap};
+use crate::lsp;
+use crate::lsp::ext::StatusResponse;
+use crate::metrics;
 use crate::socketwrapper::Listener;
+use crate::state;
+
+/// How often a draining server checks whether the clients are gone
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long `<server> --version` may take
+const SERVER_VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a server already listening on our address may take to answer
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Exit code of `ra-multiplex server` when its `listen` address is taken,
+/// the same as `EADDRINUSE` on Linux
+pub const EXIT_ADDR_IN_USE: i32 = 98;
+
+/// The `listen` address is taken, most likely by a server started earlier
+#[derive(Debug)]
+pub struct AddrInUse {
+    address: Address,
+    /// Another ra-multiplex server answered on the address
+    running: bool,
+}
+
+impl fmt::Display for AddrInUse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let address = &self.address;
+        if self.running {
+            write!(
+                f,
+                "another ra-multiplex server is already listening on {address}, clients can use it as it is, stop it first to start a new one"
+            )
+        } else {
+            write!(
+                f,
+                "{address} is already in use by another program, set a different `listen` address or `--port`"
+            )
+        }
+    }
+}
+
+impl std::error::Error for AddrInUse {}
 
 pub async fn run(config: &Config) -> Result<()> {
+    log_versions(config).await;
+    let auth_token = config.auth_token().context("auth token")?.map(Arc::new);
+    // Bound first so a server which can't listen doesn't start any instances.
+    let listener = match systemd_listener()? {
+        Some(listener) => {
+            info!(
+                multiplex = !config.no_multiplex,
+                "listening on socket from systemd"
+            );
+            listener
+        }
+        None => {
+            let listener = match Listener::bind(&config.listen, &config.into()).await {
+                Ok(listener) => listener,
+                Err(err) if is_addr_in_use(&err) => {
+                    return Err(AddrInUse {
+                        address: config.listen.clone(),
+                        running: probe_server(config).await,
+                    }
+                    .into());
+                }
+                Err(err) => return Err(err).context("listen"),
+            };
+            info!(socket = ?config.listen, multiplex = !config.no_multiplex, "listening");
+            listener
+        }
+    };
     let instance_map = InstanceMap::new(config).await;
+    if config.prewarm_instances {
+        prewarm_instances(&instance_map);
+    }
+    warmup_workspaces(config, &instance_map);
     let next_client_id = AtomicUsize::new(0);
     let next_client_id = || next_client_id.fetch_add(1, Ordering::Relaxed);
 
-    let listener = Listener::bind(&config.listen).await.context("listen")?;
-    info!(socket = ?config.listen, "listening");
+    if let Some(metrics_listen) = config.metrics_listen.clone() {
+        let instance_map = instance_map.clone();
+        task::spawn(async move {
+            if let Err(err) = metrics::run(metrics_listen, instance_map).await {
+                error!(?err, "metrics endpoint failed");
+            }
+        });
+    }
+
+    // Every connection holds a permit until it's closed.
+    let connections = Arc::new(Semaphore::new(config.max_clients));
+
+    if config.no_multiplex {
+        warn!("multiplexing is disabled, every client gets its own language server");
+    }
+    if !config.rewrite_request_ids {
+        warn!("request id rewriting is disabled, clients must not reuse each other's request ids");
+    }
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let drain_requested = instance_map.lock().await.drain_requested();
+    let mut draining = false;
+    let mut drain_poll = tokio::time::interval(DRAIN_POLL_INTERVAL);
     loop {
-        match listener.accept().await {
+        let accepted = select! {
+            accepted = listener.accept() => accepted,
+            signal = &mut shutdown => {
+                signal.context("waiting for shutdown signal")?;
+                break;
+            }
+            () = drain_requested.notified(), if !draining => {
+                // Keep accepting connections for status and other commands,
+                // new clients are refused.
+                info!("draining, waiting for clients to disconnect");
+                draining = true;
+                continue;
+            }
+            _ = drain_poll.tick(), if draining => {
+                let deadline = instance_map.lock().await.drain_deadline();
+                if connections.available_permits() == config.max_clients {
+                    info!("all clients disconnected");
+                    break;
+                }
+                if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                    let clients = config.max_clients - connections.available_permits();
+                    warn!(clients, "drain timed out, disconnecting remaining clients");
+                    break;
+                }
+                continue;
+            }
+        };
+        match accepted {
             Ok((socket, _addr)) => {
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    warn!(
+                        max_clients = config.max_clients,
+                        "too many clients, refusing connection"
+                    );
+                    continue;
+                };
                 let client_id = next_client_id();
                 let instance_map = instance_map.clone();
+                let auth_token = auth_token.clone();
 
                 task::spawn(
                     async move {
                         info!("client connected");
-                        match client::process(socket, client_id, instance_map).await {
+                        match client::process(socket, client_id, instance_map, auth_token).await {
                             Ok(_) => {}
                             Err(err) => error!("client error: {err:?}"),
                         }
+                        drop(permit);
                     }
-                    .instrument(info_span!("client", %client_id)),
+                    // The workspace is recorded once the client connects to
+                    // an instance to show which clients share it.
+                    .instrument(info_span!(
+                        "client",
+                        %client_id,
+                        name = field::Empty,
+                        workspace = field::Empty,
+                    )),
                 );
             }
             Err(err) => match err.kind() {
@@ -44,4 +190,173 @@ pub async fn run(config: &Config) -> Result<()> {
             },
         }
     }
+
+    info!("shutting down");
+    drop(listener);
+    if config.prewarm_instances {
+        save_instances(&instance_map).await;
+    }
+    InstanceMap::shutdown(&instance_map).await;
+    Ok(())
+}
+
+/// Start the instances saved by the previous server in the background
+fn prewarm_instances(instance_map: &Arc<Mutex<InstanceMap>>) {
+    let Some(path) = state::default_path() else {
+        warn!("no home directory, not starting saved instances");
+        return;
+    };
+    let saved = match state::load(&path) {
+        Ok(saved) => saved,
+        Err(err) => {
+            warn!(?err, "ignoring saved instances");
+            return;
+        }
+    };
+    for saved in saved {
+        let key = InstanceKey {
+            server: saved.server,
+            args: saved.args,
+            env: saved.env,
+            workspace_root: saved.workspace_root,
+            private: None,
+        };
+        let instance_map = instance_map.clone();
+        let span = info_span!("prewarm", path = ?key.workspace_root);
+        task::spawn(
+            async move {
+                info!("starting saved instance");
+                if let Err(err) = instance::get_or_spawn(instance_map, key, saved.init_params).await
+                {
+                    warn!(?err, "failed to start saved instance");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Start the configured `server` for the `warmup` workspaces in the background
+fn warmup_workspaces(config: &Config, instance_map: &Arc<Mutex<InstanceMap>>) {
+    for workspace_root in &config.warmup {
+        let command = (
+            config.server.clone(),
+            config.server_args.clone(),
+            config.passed_environment(),
+        );
+        let instance_map = instance_map.clone();
+        let span = info_span!("warmup", path = ?workspace_root);
+        let workspace_root = workspace_root.clone();
+        task::spawn(
+            async move {
+                info!("warming up");
+                if let Err(err) = client::warmup(instance_map, command, workspace_root).await {
+                    warn!(?err, "warmup failed");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Save the running instances for [`prewarm_instances`] of the next server
+async fn save_instances(instance_map: &Mutex<InstanceMap>) {
+    let Some(path) = state::default_path() else {
+        warn!("no home directory, not saving instances");
+        return;
+    };
+    let saved = instance_map.lock().await.saved_instances();
+    match state::save(&path, saved) {
+        Ok(()) => info!(?path, "saved instances"),
+        Err(err) => warn!(?err, "failed to save instances"),
+    }
+}
+
+/// The bind failed because the address is taken
+fn is_addr_in_use(err: &anyhow::Error) -> bool {
+    err.root_cause()
+        .downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::AddrInUse)
+}
+
+/// Is a ra-multiplex server answering on the `listen` address
+async fn probe_server(config: &Config) -> bool {
+    let mut config = config.clone();
+    config.connect = config.listen.clone();
+    let status = ext::ext_request::<StatusResponse>(&config, lsp::ext::Request::Status {});
+    matches!(tokio::time::timeout(PROBE_TIMEOUT, status).await, Ok(Ok(_)))
+}
+
+/// Listening socket passed by systemd socket activation
+fn systemd_listener() -> Result<Option<Listener>> {
+    #[cfg(target_family = "unix")]
+    return Listener::from_systemd().context("systemd socket activation");
+    #[cfg(not(target_family = "unix"))]
+    Ok(None)
+}
+
+/// Wait for SIGINT (Ctrl-C) or on unix for SIGTERM
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(target_family = "unix")]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+        select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(target_family = "unix"))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Log our version and the version of the default language server
+///
+/// Bug reports need both, and a server still running an older language server
+/// than the one in `PATH` is easy to miss.
+async fn log_versions(config: &Config) {
+    let Some(path) = find_executable(&config.server) else {
+        warn!(version = %*crate::VERSION, server = ?config.server, "language server not found");
+        return;
+    };
+    let server_version = server_version(&path).await;
+    info!(version = %*crate::VERSION, server = ?path, %server_version, "starting");
+}
+
+/// Output of `<server> --version` or why it's unknown
+pub(crate) async fn server_version(path: &Path) -> String {
+    let output = tokio::time::timeout(
+        SERVER_VERSION_TIMEOUT,
+        Command::new(path).arg("--version").output(),
+    )
+    .await;
+    match output {
+        Ok(Ok(output)) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_owned()
+        }
+        Ok(Ok(output)) => format!("unknown, `--version` failed with {}", output.status),
+        Ok(Err(err)) => format!("unknown, `--version` failed: {err}"),
+        Err(_) => "unknown, `--version` timed out".to_owned(),
+    }
+}
+
+/// Resolve `program` like a shell would
+pub(crate) fn find_executable(program: &str) -> Option<PathBuf> {
+    let program = Path::new(program);
+    if program.components().count() > 1 {
+        return program.is_file().then(|| program.to_owned());
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+#[cfg(all(test, unix))]
+#[test]
+fn executables_are_found_in_path() {
+    assert!(find_executable("sh").is_some_and(|path| path.is_absolute()));
+    assert_eq!(find_executable("/bin/sh"), Some(PathBuf::from("/bin/sh")));
+    assert_eq!(find_executable("ra-multiplex-no-such-server"), None);
 }
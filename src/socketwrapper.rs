@@ -5,13 +5,15 @@ use std::task::{Context, Poll};
 use std::{io, net};
 
 use anyhow::{Context as _, Result};
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
 use pin_project_lite::pin_project;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
 use tokio::net::{tcp, TcpListener, TcpStream};
 #[cfg(target_family = "unix")]
 use tokio::net::{unix, UnixListener, UnixStream};
 
-use crate::config::Address;
+use crate::config::{Address, Config};
 
 pub enum SocketAddr {
     Ip(#[allow(dead_code)] net::SocketAddr),
@@ -38,6 +40,7 @@ pin_project! {
     pub enum OwnedReadHalf {
         Tcp{#[pin] tcp: tcp::OwnedReadHalf},
         Unix{#[pin] unix: unix::OwnedReadHalf},
+        Zstd{#[pin] zstd: Box<ZstdDecoder<BufReader<OwnedReadHalf>>>},
     }
 }
 #[cfg(not(target_family = "unix"))]
@@ -45,6 +48,18 @@ pin_project! {
     #[project = OwnedReadHalfProj]
     pub enum OwnedReadHalf {
         Tcp{#[pin] tcp: tcp::OwnedReadHalf},
+        Zstd{#[pin] zstd: Box<ZstdDecoder<BufReader<OwnedReadHalf>>>},
+    }
+}
+
+impl OwnedReadHalf {
+    /// Decompress everything read from now on
+    ///
+    /// Takes the buffered reader so data read ahead isn't lost.
+    pub fn zstd(reader: BufReader<OwnedReadHalf>) -> Self {
+        OwnedReadHalf::Zstd {
+            zstd: Box::new(ZstdDecoder::new(reader)),
+        }
     }
 }
 
@@ -58,6 +73,7 @@ impl AsyncRead for OwnedReadHalf {
             OwnedReadHalfProj::Tcp { tcp } => tcp.poll_read(cx, buf),
             #[cfg(target_family = "unix")]
             OwnedReadHalfProj::Unix { unix } => unix.poll_read(cx, buf),
+            OwnedReadHalfProj::Zstd { zstd } => zstd.poll_read(cx, buf),
         }
     }
 }
@@ -68,6 +84,7 @@ pin_project! {
     pub enum OwnedWriteHalf {
         Tcp{#[pin] tcp: tcp::OwnedWriteHalf},
         Unix{#[pin] unix: unix::OwnedWriteHalf},
+        Zstd{#[pin] zstd: Box<ZstdEncoder<OwnedWriteHalf>>},
     }
 }
 #[cfg(not(target_family = "unix"))]
@@ -75,6 +92,19 @@ pin_project! {
     #[project = OwnedWriteHalfProj]
     pub enum OwnedWriteHalf {
         Tcp{#[pin] tcp: tcp::OwnedWriteHalf},
+        Zstd{#[pin] zstd: Box<ZstdEncoder<OwnedWriteHalf>>},
+    }
+}
+
+impl OwnedWriteHalf {
+    /// Compress everything written from now on
+    ///
+    /// Flushing ends the current zstd block so every flushed message can be
+    /// decompressed right away.
+    pub fn zstd(self) -> Self {
+        OwnedWriteHalf::Zstd {
+            zstd: Box::new(ZstdEncoder::new(self)),
+        }
     }
 }
 
@@ -88,6 +118,7 @@ impl AsyncWrite for OwnedWriteHalf {
             OwnedWriteHalfProj::Tcp { tcp } => tcp.poll_write(cx, buf),
             #[cfg(target_family = "unix")]
             OwnedWriteHalfProj::Unix { unix } => unix.poll_write(cx, buf),
+            OwnedWriteHalfProj::Zstd { zstd } => zstd.poll_write(cx, buf),
         }
     }
 
@@ -100,6 +131,7 @@ impl AsyncWrite for OwnedWriteHalf {
             OwnedWriteHalfProj::Tcp { tcp } => tcp.poll_write_vectored(cx, bufs),
             #[cfg(target_family = "unix")]
             OwnedWriteHalfProj::Unix { unix } => unix.poll_write_vectored(cx, bufs),
+            OwnedWriteHalfProj::Zstd { zstd } => zstd.poll_write_vectored(cx, bufs),
         }
     }
 
@@ -108,6 +140,7 @@ impl AsyncWrite for OwnedWriteHalf {
             OwnedWriteHalfProj::Tcp { tcp } => tcp.poll_flush(cx),
             #[cfg(target_family = "unix")]
             OwnedWriteHalfProj::Unix { unix } => unix.poll_flush(cx),
+            OwnedWriteHalfProj::Zstd { zstd } => zstd.poll_flush(cx),
         }
     }
 
@@ -116,6 +149,7 @@ impl AsyncWrite for OwnedWriteHalf {
             OwnedWriteHalfProj::Tcp { tcp } => tcp.poll_shutdown(cx),
             #[cfg(target_family = "unix")]
             OwnedWriteHalfProj::Unix { unix } => unix.poll_shutdown(cx),
+            OwnedWriteHalfProj::Zstd { zstd } => zstd.poll_shutdown(cx),
         }
     }
 }
@@ -234,30 +268,115 @@ pub enum Listener {
     Unix(UnixListener),
 }
 
+/// How a listening socket is set up
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+    /// Whether an IPv6 TCP socket refuses IPv4 connections, `None` leaves it
+    /// to the system default
+    pub ipv6_only: Option<bool>,
+    /// Length of the queue of connections waiting to be accepted
+    pub backlog: u32,
+    /// Set `SO_REUSEADDR` on TCP sockets
+    pub reuse_address: bool,
+    /// Replace a unix socket file no server is listening on
+    pub remove_stale_socket: bool,
+}
+
+impl Default for ListenOptions {
+    /// The same as [`TcpListener::bind`] and [`UnixListener::bind`]
+    fn default() -> Self {
+        ListenOptions {
+            ipv6_only: None,
+            backlog: 1024,
+            reuse_address: true,
+            remove_stale_socket: true,
+        }
+    }
+}
+
+impl From<&Config> for ListenOptions {
+    fn from(config: &Config) -> Self {
+        ListenOptions {
+            ipv6_only: config.listen_ipv6_only,
+            backlog: config.listen_backlog,
+            reuse_address: config.listen_reuse_address,
+            remove_stale_socket: config.listen_remove_stale_socket,
+        }
+    }
+}
+
 impl Listener {
-    pub async fn bind(addr: &Address) -> Result<Listener> {
+    /// Bind a listening socket
+    pub async fn bind(addr: &Address, options: &ListenOptions) -> Result<Listener> {
         match addr {
-            Address::Tcp(ip_addr, port) => TcpListener::bind((*ip_addr, *port))
-                .await
+            Address::Tcp(ip_addr, port) => bind_tcp(net::SocketAddr::new(*ip_addr, *port), options)
                 .with_context(|| format!("binding to tcp socket {ip_addr}:{port}"))
                 .map(Listener::Tcp),
             #[cfg(target_family = "unix")]
             Address::Unix(path) => {
-                match fs::remove_file(path) {
-                    Ok(()) => (),
-                    Err(e) if e.kind() == io::ErrorKind::NotFound => (),
-                    Err(e) => {
-                        return Err(e)
-                            .with_context(|| format!("removing old unix socket file {path:?}"))
+                // Removing the socket file of a running server would leave it
+                // unreachable, only stale ones are replaced.
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::from(io::ErrorKind::AddrInUse))
+                        .with_context(|| format!("binding to unix socket {path:?}"));
+                }
+                if options.remove_stale_socket {
+                    match fs::remove_file(path) {
+                        Ok(()) => (),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                        Err(e) => {
+                            return Err(e)
+                                .with_context(|| format!("removing old unix socket file {path:?}"))
+                        }
                     }
                 }
-                UnixListener::bind(path)
+                bind_unix(path, options.backlog)
                     .with_context(|| format!("binding to unix socket {path:?}"))
                     .map(Listener::Unix)
             }
         }
     }
 
+    /// Take over the listening socket passed by systemd socket activation
+    ///
+    /// Returns `None` if the process wasn't started by a socket unit. Only the
+    /// first passed socket is used.
+    #[cfg(target_family = "unix")]
+    pub fn from_systemd() -> Result<Option<Listener>> {
+        use std::os::fd::{FromRawFd, IntoRawFd};
+
+        let fds = systemd_listen_fds(
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+            std::process::id(),
+        );
+        if fds == 0 {
+            return Ok(None);
+        }
+        // Don't let the language servers think they're socket activated too.
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+
+        // SAFETY: systemd passes the sockets starting at this fd and nothing
+        // else in the process uses it.
+        let tcp = unsafe { net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+        // Systemd doesn't set close-on-exec, the language servers would keep
+        // the socket open. Cloning does and the original is closed.
+        let listener = if tcp.local_addr().is_ok() {
+            let tcp = tcp.try_clone().context("duplicating systemd socket")?;
+            tcp.set_nonblocking(true)?;
+            Listener::Tcp(TcpListener::from_std(tcp)?)
+        } else {
+            // SAFETY: the fd is still the same open socket, it wasn't closed.
+            let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+            let unix = unix.try_clone().context("duplicating systemd socket")?;
+            unix.set_nonblocking(true)?;
+            Listener::Unix(UnixListener::from_std(unix)?)
+        };
+        Ok(Some(listener))
+    }
+
     pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
         match self {
             Listener::Tcp(tcp) => {
@@ -272,3 +391,202 @@ impl Listener {
         }
     }
 }
+
+/// First file descriptor of sockets passed by systemd
+#[cfg(target_family = "unix")]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Number of sockets systemd passed to the process `pid` according to the
+/// `LISTEN_PID` and `LISTEN_FDS` environment variables
+#[cfg(target_family = "unix")]
+fn systemd_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> u32 {
+    // The variables are inherited by children which didn't unset them, they're
+    // only meant for the process systemd started.
+    if listen_pid.and_then(|listen_pid| listen_pid.parse().ok()) != Some(pid) {
+        return 0;
+    }
+    listen_fds.and_then(|fds| fds.parse().ok()).unwrap_or(0)
+}
+
+/// Bind a TCP listener
+///
+/// `IPV6_V6ONLY` is only set on IPv6 sockets with `ipv6_only` set. Windows
+/// `SO_REUSEADDR` allows stealing the address of a running server, it's only
+/// set on unix like [`TcpListener::bind`] does it.
+fn bind_tcp(addr: net::SocketAddr, options: &ListenOptions) -> io::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if let Some(ipv6_only) = options.ipv6_only.filter(|_| addr.is_ipv6()) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    #[cfg(target_family = "unix")]
+    socket.set_reuse_address(options.reuse_address)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog(options.backlog))?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(target_family = "unix")]
+fn bind_unix(path: &std::path::Path, backlog_len: u32) -> io::Result<UnixListener> {
+    use socket2::{Domain, SockAddr, Socket, Type};
+
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(backlog(backlog_len))?;
+    // Newer socket2 releases only convert to the file descriptor.
+    let fd = std::os::fd::OwnedFd::from(socket);
+    UnixListener::from_std(fd.into())
+}
+
+/// The system caps larger values to its maximum, `somaxconn` on linux
+fn backlog(backlog: u32) -> i32 {
+    backlog.try_into().unwrap_or(i32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+
+    use super::*;
+    use crate::config::Config;
+
+    /// Local address used for outgoing traffic if the machine has any network
+    fn non_loopback_address() -> Option<IpAddr> {
+        // Connecting an UDP socket doesn't send anything, it only picks a route.
+        let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+        socket.connect("192.0.2.1:9").ok()?;
+        let ip = socket.local_addr().ok()?.ip();
+        (!ip.is_loopback() && !ip.is_unspecified()).then_some(ip)
+    }
+
+    fn port(listener: &Listener) -> u16 {
+        match listener {
+            Listener::Tcp(tcp) => tcp.local_addr().unwrap().port(),
+            #[cfg(target_family = "unix")]
+            Listener::Unix(_) => panic!("not a tcp listener"),
+        }
+    }
+
+    #[tokio::test]
+    async fn default_listener_refuses_other_interfaces() {
+        let Address::Tcp(ip_addr, _) = Config::default().listen else {
+            panic!("default listen address is not tcp");
+        };
+        let listener = Listener::bind(&Address::Tcp(ip_addr, 0), &ListenOptions::default())
+            .await
+            .unwrap();
+        let port = port(&listener);
+
+        Stream::connect(&Address::Tcp(ip_addr, port)).await.unwrap();
+        let Some(other) = non_loopback_address() else {
+            eprintln!("no non-loopback interface, skipping");
+            return;
+        };
+        let err = TcpStream::connect((other, port)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    fn addr_in_use(err: anyhow::Error) -> bool {
+        err.root_cause()
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::AddrInUse)
+    }
+
+    #[tokio::test]
+    async fn taken_addresses_are_in_use() {
+        let ip_addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let listener = Listener::bind(&Address::Tcp(ip_addr, 0), &ListenOptions::default())
+            .await
+            .unwrap();
+        let addr = Address::Tcp(ip_addr, port(&listener));
+        let err = Listener::bind(&addr, &ListenOptions::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(addr_in_use(err));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn running_unix_sockets_arent_replaced() {
+        let path = std::env::temp_dir().join(format!("ra-multiplex-bind-{}", std::process::id()));
+        let addr = Address::Unix(path.clone());
+        let listener = Listener::bind(&addr, &ListenOptions::default())
+            .await
+            .unwrap();
+        let err = Listener::bind(&addr, &ListenOptions::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(addr_in_use(err));
+
+        // The file of a server which is gone is stale.
+        drop(listener);
+        let keep_stale = ListenOptions {
+            remove_stale_socket: false,
+            ..ListenOptions::default()
+        };
+        let err = Listener::bind(&addr, &keep_stale).await.err().unwrap();
+        assert!(addr_in_use(err));
+        Listener::bind(&addr, &ListenOptions::default())
+            .await
+            .unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn systemd_sockets_are_only_for_their_process() {
+        assert_eq!(systemd_listen_fds(Some("42"), Some("1"), 42), 1);
+        assert_eq!(systemd_listen_fds(Some("41"), Some("1"), 42), 0);
+        assert_eq!(systemd_listen_fds(None, Some("1"), 42), 0);
+        assert_eq!(systemd_listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(systemd_listen_fds(Some("42"), Some("x"), 42), 0);
+    }
+
+    #[tokio::test]
+    async fn ipv6_loopback() {
+        let ip_addr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let Ok(listener) =
+            Listener::bind(&Address::Tcp(ip_addr, 0), &ListenOptions::default()).await
+        else {
+            eprintln!("no ipv6 loopback, skipping");
+            return;
+        };
+        let addr = Address::Tcp(ip_addr, port(&listener));
+
+        let (connected, accepted) = tokio::join!(Stream::connect(&addr), listener.accept(),);
+        connected.unwrap();
+        accepted.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dual_stack_listener() {
+        let ip_addr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+        let ipv4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let ipv6_only = |ipv6_only| ListenOptions {
+            ipv6_only: Some(ipv6_only),
+            ..ListenOptions::default()
+        };
+        let Ok(listener) = Listener::bind(&Address::Tcp(ip_addr, 0), &ipv6_only(false)).await
+        else {
+            eprintln!("no ipv6, skipping");
+            return;
+        };
+        let addr = Address::Tcp(ipv4, port(&listener));
+        let (connected, accepted) = tokio::join!(Stream::connect(&addr), listener.accept(),);
+        connected.unwrap();
+        accepted.unwrap();
+
+        let listener = Listener::bind(&Address::Tcp(ip_addr, 0), &ipv6_only(true))
+            .await
+            .unwrap();
+        let err = TcpStream::connect((ipv4, port(&listener)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,141 @@
+//! Instances remembered across server restarts
+//!
+//! With `prewarm_instances` the server writes the instances it was running to
+//! a state file when it shuts down cleanly and starts them again on the next
+//! start, the editors reconnecting find their language server already
+//! indexing.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::lsp;
+
+/// State files older than this are ignored, the workspaces probably aren't in
+/// use anymore
+const MAX_AGE: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct State {
+    /// UTC unix timestamp of when the state was saved
+    saved: i64,
+    instances: Vec<SavedInstance>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedInstance {
+    pub server: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub workspace_root: String,
+    /// Params the language server was initialized with
+    pub init_params: lsp::InitializeParams,
+}
+
+/// Default location of the state file
+pub fn default_path() -> Option<PathBuf> {
+    let pkg_name = env!("CARGO_PKG_NAME");
+    let dirs = ProjectDirs::from("", "", pkg_name)?;
+    Some(dirs.data_local_dir().join("instances.json"))
+}
+
+/// Write the state file
+///
+/// The environment of the language servers can contain secrets, the file is
+/// only readable by the user.
+pub fn save(path: &Path, instances: Vec<SavedInstance>) -> Result<()> {
+    let state = State {
+        saved: time::OffsetDateTime::now_utc().unix_timestamp(),
+        instances,
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating {dir:?}"))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("opening {path:?}"))?;
+    file.write_all(&serde_json::to_vec(&state).unwrap())
+        .with_context(|| format!("writing {path:?}"))
+}
+
+/// Read the state file and remove it
+///
+/// A missing, stale or corrupt state file has no instances. Instances whose
+/// workspace root is gone are skipped.
+pub fn load(path: &Path) -> Result<Vec<SavedInstance>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("reading {path:?}")),
+    };
+    // Instances which aren't used after this start shouldn't come back forever.
+    fs::remove_file(path).with_context(|| format!("removing {path:?}"))?;
+    let state =
+        serde_json::from_slice::<State>(&data).with_context(|| format!("parsing {path:?}"))?;
+    let age = time::OffsetDateTime::now_utc().unix_timestamp() - state.saved;
+    if age > MAX_AGE {
+        return Ok(Vec::new());
+    }
+    Ok(state
+        .instances
+        .into_iter()
+        .filter(|instance| Path::new(&instance.workspace_root).is_dir())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved_instance(workspace_root: &Path) -> SavedInstance {
+        let init_params = serde_json::from_value(serde_json::json!({
+            "processId": null,
+            "rootUri": null,
+            "initializationOptions": null,
+            "capabilities": {},
+        }))
+        .unwrap();
+        SavedInstance {
+            server: "rust-analyzer".into(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            workspace_root: workspace_root.to_str().unwrap().into(),
+            init_params,
+        }
+    }
+
+    #[test]
+    fn saved_instances_are_loaded_once() {
+        let dir = std::env::temp_dir().join(format!("ra-multiplex-state-{}", std::process::id()));
+        let path = dir.join("instances.json");
+        let gone = dir.join("gone");
+        save(&path, vec![saved_instance(&dir), saved_instance(&gone)]).unwrap();
+
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].workspace_root, dir.to_str().unwrap());
+        assert!(load(&path).unwrap().is_empty());
+
+        let stale = State {
+            saved: time::OffsetDateTime::now_utc().unix_timestamp() - MAX_AGE - 1,
+            instances: vec![saved_instance(&dir)],
+        };
+        fs::write(&path, serde_json::to_vec(&stale).unwrap()).unwrap();
+        assert!(load(&path).unwrap().is_empty());
+
+        fs::write(&path, "{").unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
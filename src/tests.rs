@@ -0,0 +1,2997 @@
+//! End-to-end tests running clients against a fake language server
+//!
+//! The fake server is a tiny `sh` script which connects its stdin and stdout to
+//! a pair of named pipes, the test then plays the language server over those
+//! pipes while clients connect through the real `client::process`.
+
+use std::fs;
+use std::net::Shutdown;
+use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::BufReader;
+use tokio::net::unix::pipe;
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use tokio::task;
+
+use crate::config::{
+    Address, Config, DiagnosticSeverity, NullIdResponses, RateLimitAction, RestartMessage,
+    ServerRequests,
+};
+use crate::instance::InstanceMap;
+use crate::lsp::ext::{self, LspMuxOptions, RejectReason, Rejection, StatusResponse};
+use crate::lsp::jsonrpc::{
+    self, Message, Notification, Request, RequestId, ResponseError, ResponseSuccess, Version,
+};
+use crate::lsp::transport::{LspReader, LspWriter};
+use crate::lsp::{InitializationOptions, InitializeParams};
+use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf, Stream};
+use crate::{client, proxy, server};
+
+/// Connects the server stdio to the named pipes passed as `$1` and `$2`
+///
+/// Background jobs get their stdin redirected from `/dev/null` so we have to
+/// duplicate it first. Only the background job keeps the stdin open, when it
+/// can't write to `$1` anymore the server stdin is closed.
+const FAKE_SERVER: &str = r#"exec 3<&0; cat <&3 > "$1" & exec cat "$2" 0<&- 3<&-"#;
+
+pub struct TestEnv {
+    pub dir: PathBuf,
+    pub instance_map: Arc<Mutex<InstanceMap>>,
+    /// Secret the clients must present, none by default
+    pub auth_token: Option<Arc<String>>,
+    next_client_id: usize,
+}
+
+impl TestEnv {
+    pub async fn new() -> TestEnv {
+        TestEnv::with_config(Config::default()).await
+    }
+
+    pub async fn with_config(config: Config) -> TestEnv {
+        static NEXT_ENV: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "ra-multiplex-test-{}-{}",
+            std::process::id(),
+            NEXT_ENV.fetch_add(1, Ordering::Relaxed),
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        let env = TestEnv {
+            dir,
+            instance_map: InstanceMap::new(&config).await,
+            auth_token: None,
+            next_client_id: 0,
+        };
+        env.create_pipes("server");
+        env
+    }
+
+    /// Create the named pipes of a fake server opened with
+    /// [`TestEnv::open_server_named`]
+    pub fn create_pipes(&self, name: &str) {
+        for fifo in [format!("{name}-stdin"), format!("{name}-stdout")] {
+            let path = self.dir.join(fifo);
+            let status = Command::new("mkfifo").arg(path).status().unwrap();
+            assert!(status.success(), "mkfifo failed");
+        }
+    }
+
+    pub fn options(&self) -> LspMuxOptions {
+        LspMuxOptions {
+            version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+            token: None,
+            method: ext::Request::Connect {
+                server: "sh".into(),
+                args: vec![
+                    "-c".into(),
+                    FAKE_SERVER.into(),
+                    "sh".into(),
+                    self.dir.join("server-stdin").to_str().unwrap().into(),
+                    self.dir.join("server-stdout").to_str().unwrap().into(),
+                ],
+                env: Default::default(),
+                cwd: Some(self.dir.to_str().unwrap().into()),
+                private: false,
+                compression: None,
+                client_name: None,
+            },
+        }
+    }
+
+    /// Options for a client with its own private instance
+    pub fn private_options(&self) -> LspMuxOptions {
+        let mut options = self.options();
+        let ext::Request::Connect { private, .. } = &mut options.method else {
+            unreachable!();
+        };
+        *private = true;
+        options
+    }
+
+    /// Connect a new client and send its `initialize` request
+    ///
+    /// If this is the first client of an instance the test must serve the
+    /// server side of the handshake with [`TestEnv::server`] before calling
+    /// [`TestClient::initialized`].
+    pub async fn client(&mut self) -> TestClient {
+        self.client_with(self.options()).await
+    }
+
+    pub async fn client_with(&mut self, options: LspMuxOptions) -> TestClient {
+        self.client_with_capabilities(options, None).await
+    }
+
+    pub async fn client_with_capabilities(
+        &mut self,
+        options: LspMuxOptions,
+        capabilities: Option<Value>,
+    ) -> TestClient {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let client_id = self.next_client_id;
+        self.next_client_id += 1;
+        task::spawn(client::process(
+            Stream::Unix { unix: theirs },
+            client_id,
+            self.instance_map.clone(),
+            self.auth_token.clone(),
+        ));
+
+        let socket = ours.as_fd().try_clone_to_owned().unwrap().into();
+        let (read, write) = Stream::Unix { unix: ours }.into_split();
+        let mut client = TestClient {
+            reader: LspReader::new(BufReader::new(read), "test-client"),
+            writer: LspWriter::new(write, "test-client"),
+            socket,
+            init_id: RequestId::Number(100 + client_id as i64),
+        };
+        client.initialize(options, capabilities).await;
+        client
+    }
+
+    /// Open the fake server pipes and answer the instance handshake
+    pub async fn server(&self) -> FakeServer {
+        self.server_named("server").await
+    }
+
+    pub async fn server_named(&self, name: &str) -> FakeServer {
+        let mut server = self.open_server_named(name).await;
+        let req = server.request().await;
+        assert_eq!(req.method, "initialize");
+        server
+            .send(ResponseSuccess {
+                jsonrpc: Version,
+                result: json!({ "capabilities": { "hoverProvider": true } }),
+                id: req.id,
+            })
+            .await;
+        assert_eq!(server.notification().await.method, "initialized");
+        server
+    }
+
+    /// Open the fake server pipes without doing the handshake
+    pub async fn open_server(&self) -> FakeServer {
+        self.open_server_named("server").await
+    }
+
+    pub async fn open_server_named(&self, name: &str) -> FakeServer {
+        let stdin = self.dir.join(format!("{name}-stdin"));
+        let stdout = self.dir.join(format!("{name}-stdout"));
+        // Opening named pipes blocks until the other end is opened too.
+        let (stdin, stdout) = task::spawn_blocking(move || {
+            let stdin = fs::File::open(stdin).unwrap();
+            let stdout = fs::OpenOptions::new().write(true).open(stdout).unwrap();
+            (stdin, stdout)
+        })
+        .await
+        .unwrap();
+
+        FakeServer {
+            reader: LspReader::new(
+                BufReader::new(pipe::Receiver::from_file(stdin).unwrap()),
+                "test-server",
+            ),
+            writer: LspWriter::new(pipe::Sender::from_file(stdout).unwrap(), "test-server"),
+        }
+    }
+
+    pub async fn status(&self) -> StatusResponse {
+        let instance_map = self.instance_map.clone();
+        task::spawn_blocking(move || instance_map.blocking_lock().get_status())
+            .await
+            .unwrap()
+    }
+
+    /// Make the fake server exit and wait until the instance notices
+    ///
+    /// Opening the pipes again before the old server is gone would connect
+    /// them to the old server.
+    pub async fn crash(&self, server: FakeServer) {
+        // The fake server exits when we stop feeding its stdout.
+        drop(server);
+        while InstanceMap::health(&self.instance_map).await.instances[0].running {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Wait until there are exactly `count` instances
+    pub async fn wait_for_instances(&self, count: usize) {
+        while self.status().await.instances.len() != count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Wait until the instance has exactly `count` clients
+    pub async fn wait_for_clients(&self, count: usize) {
+        while self.status().await.instances[0].clients.len() != count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub struct TestClient {
+    reader: LspReader<BufReader<OwnedReadHalf>>,
+    writer: LspWriter<OwnedWriteHalf>,
+    socket: std::os::unix::net::UnixStream,
+    /// Every client uses a different `initialize` request ID
+    init_id: RequestId,
+}
+
+impl TestClient {
+    async fn initialize(&mut self, options: LspMuxOptions, capabilities: Option<Value>) {
+        let params = InitializeParams {
+            initialization_options: Some(InitializationOptions {
+                lsp_mux: Some(options),
+                other_options: Default::default(),
+            }),
+            process_id: None,
+            client_info: None,
+            locale: None,
+            root_path: None,
+            root_uri: None,
+            capabilities,
+            trace: None,
+            workspace_folders: Vec::new(),
+        };
+        self.send(Request {
+            jsonrpc: Version,
+            method: "initialize".into(),
+            params: serde_json::to_value(params).unwrap(),
+            id: self.init_id.clone(),
+        })
+        .await;
+    }
+
+    /// Wait for the `initialize` response and finish the handshake
+    pub async fn initialized(&mut self) -> Value {
+        let res = self.response().await;
+        assert_eq!(res.id, self.init_id);
+        self.notify("initialized", json!({})).await;
+        res.result
+    }
+
+    pub async fn send(&mut self, message: impl Into<Message>) {
+        self.writer.write_message(&message.into()).await.unwrap();
+    }
+
+    /// Expect the server to refuse the `initialize` request and disconnect
+    pub async fn rejected(&mut self) -> RejectReason {
+        let Message::ResponseError(res) = self.recv().await else {
+            panic!("expected error response");
+        };
+        assert_eq!(res.id.as_ref(), Some(&self.init_id));
+        assert!(self.reader.read_message().await.unwrap().is_none());
+        let data = res.error.data.expect("missing rejection data");
+        serde_json::from_value::<Rejection>(data).unwrap().reason
+    }
+
+    /// Switch to zstd after the server confirmed it
+    pub fn compressed(self) -> TestClient {
+        let read = OwnedReadHalf::zstd(self.reader.into_inner());
+        TestClient {
+            reader: LspReader::new(BufReader::new(read), "test-client"),
+            writer: LspWriter::new(self.writer.into_inner().zstd(), "test-client"),
+            ..self
+        }
+    }
+
+    /// Stop reading, further writes to this client will fail
+    pub fn shutdown_read(&self) {
+        self.socket.shutdown(Shutdown::Read).unwrap();
+    }
+
+    /// Close the connection for writing, the client can still read
+    pub fn shutdown_write(&self) {
+        self.socket.shutdown(Shutdown::Write).unwrap();
+    }
+
+    pub async fn request(&mut self, id: i64, method: &str, params: Value) {
+        self.send(Request {
+            jsonrpc: Version,
+            method: method.into(),
+            params,
+            id: RequestId::Number(id),
+        })
+        .await;
+    }
+
+    pub async fn notify(&mut self, method: &str, params: Value) {
+        self.send(Notification {
+            jsonrpc: Version,
+            method: method.into(),
+            params,
+        })
+        .await;
+    }
+
+    pub async fn recv(&mut self) -> Message {
+        self.reader
+            .read_message()
+            .await
+            .unwrap()
+            .expect("client closed")
+    }
+
+    pub async fn response(&mut self) -> ResponseSuccess {
+        match self.recv().await {
+            Message::ResponseSuccess(res) => res,
+            other => panic!("expected response, got {other:?}"),
+        }
+    }
+}
+
+pub struct FakeServer {
+    reader: LspReader<BufReader<pipe::Receiver>>,
+    writer: LspWriter<pipe::Sender>,
+}
+
+impl FakeServer {
+    pub async fn send(&mut self, message: impl Into<Message>) {
+        self.writer.write_message(&message.into()).await.unwrap();
+    }
+
+    pub async fn recv(&mut self) -> Message {
+        self.reader
+            .read_message()
+            .await
+            .unwrap()
+            .expect("server stdin closed")
+    }
+
+    pub async fn request(&mut self) -> Request {
+        match self.recv().await {
+            Message::Request(req) => req,
+            other => panic!("expected request, got {other:?}"),
+        }
+    }
+
+    pub async fn notification(&mut self) -> Notification {
+        match self.recv().await {
+            Message::Notification(notif) => notif,
+            other => panic!("expected notification, got {other:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn clients_in_the_same_workspace_share_an_instance() {
+    let mut env = TestEnv::new().await;
+
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+
+    // The second client is answered from the cached `initialize` response, the
+    // server is not involved at all.
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    // Both clients are now routed to the same server.
+    first.request(1, "test/first", json!(null)).await;
+    assert_eq!(server.request().await.method, "test/first");
+    second.request(1, "test/second", json!(null)).await;
+    assert_eq!(server.request().await.method, "test/second");
+
+    let status = env.status().await;
+    assert_eq!(status.instances.len(), 1);
+    assert_eq!(status.instances[0].clients.len(), 2);
+}
+
+#[tokio::test]
+async fn responses_are_routed_to_the_requesting_client() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+    let mut third = env.client().await;
+    third.initialized().await;
+
+    // All clients use the same request ID.
+    first.request(1, "test/request", json!(null)).await;
+    let first_req = server.request().await;
+    second.request(1, "test/request", json!(null)).await;
+    let second_req = server.request().await;
+    third.request(1, "test/request", json!(null)).await;
+    let third_req = server.request().await;
+    assert_ne!(first_req.id, second_req.id);
+
+    // Responding to a client which has already disconnected doesn't affect
+    // the other clients.
+    drop(third);
+    for (req, result) in [
+        (third_req, "third"),
+        (second_req, "second"),
+        (first_req, "first"),
+    ] {
+        server
+            .send(ResponseSuccess {
+                jsonrpc: Version,
+                result: json!(result),
+                id: req.id,
+            })
+            .await;
+    }
+
+    let res = second.response().await;
+    assert_eq!(
+        (res.id, res.result),
+        (RequestId::Number(1), json!("second"))
+    );
+    let res = first.response().await;
+    assert_eq!((res.id, res.result), (RequestId::Number(1), json!("first")));
+}
+
+#[tokio::test]
+async fn stalled_clients_dont_block_broadcasts() {
+    let mut env = TestEnv::with_config(Config {
+        client_write_timeout: 1,
+        ..Config::default()
+    })
+    .await;
+    let mut healthy = env.client().await;
+    let mut server = env.server().await;
+    healthy.initialized().await;
+    let mut stalled = env.client().await;
+    stalled.initialized().await;
+
+    // The stalled client never reads, eventually filling up the socket buffer.
+    let payload = "x".repeat(16 * 1024);
+    for _ in 0..512 {
+        server
+            .send(Notification {
+                jsonrpc: Version,
+                method: "window/logMessage".into(),
+                params: json!({ "type": 4, "message": payload }),
+            })
+            .await;
+        match healthy.recv().await {
+            Message::Notification(notif) => assert_eq!(notif.method, "window/logMessage"),
+            other => panic!("expected notification, got {other:?}"),
+        }
+    }
+
+    // And gets disconnected.
+    env.wait_for_clients(1).await;
+    drop(stalled);
+}
+
+#[tokio::test]
+async fn clients_reading_in_bursts_stay_connected() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    // Far more than fits into the socket buffer arrives while the client is
+    // busy, like diagnostics for a whole workspace.
+    let payload = "x".repeat(16 * 1024);
+    for _ in 0..1024 {
+        server
+            .send(Notification {
+                jsonrpc: Version,
+                method: "textDocument/publishDiagnostics".into(),
+                params: json!({ "uri": "file:///lib.rs", "diagnostics": [], "message": payload }),
+            })
+            .await;
+    }
+    let queued = || async { env.status().await.instances[0].clients[0].queued_messages };
+    while queued().await == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for _ in 0..1024 {
+        match client.recv().await {
+            Message::Notification(notif) => {
+                assert_eq!(notif.method, "textDocument/publishDiagnostics")
+            }
+            other => panic!("expected notification, got {other:?}"),
+        }
+    }
+    assert_eq!(queued().await, 0);
+    env.wait_for_clients(1).await;
+}
+
+#[tokio::test]
+async fn half_open_clients_are_detached() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+    second
+        .notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": "file:///lib.rs",
+                    "languageId": "rust",
+                    "version": 0,
+                    "text": "",
+                }
+            }),
+        )
+        .await;
+    assert_eq!(server.notification().await.method, "textDocument/didOpen");
+
+    // The second client can still write but we can't write to it anymore.
+    second.shutdown_read();
+    server
+        .send(Notification {
+            jsonrpc: Version,
+            method: "window/logMessage".into(),
+            params: json!({ "type": 4, "message": "hello" }),
+        })
+        .await;
+    assert!(matches!(first.recv().await, Message::Notification(_)));
+
+    // Both directions get torn down and its files are closed.
+    let notif = server.notification().await;
+    assert_eq!(notif.method, "textDocument/didClose");
+    assert_eq!(notif.params["textDocument"]["uri"], "file:///lib.rs");
+    env.wait_for_clients(1).await;
+}
+
+#[tokio::test]
+async fn half_closed_clients_get_their_responses() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    client.request(1, "test/request", json!(null)).await;
+    client.shutdown_write();
+    let req = server.request().await;
+    server.send(ResponseSuccess::null(req.id)).await;
+
+    // The connection is closed once the response is delivered.
+    assert_eq!(client.response().await.id, RequestId::Number(1));
+    assert!(client.reader.read_message().await.unwrap().is_none());
+    env.wait_for_clients(0).await;
+}
+
+#[tokio::test]
+async fn pending_requests_are_cancelled_on_disconnect() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    second.request(1, "test/answered", json!(null)).await;
+    let answered = server.request().await;
+    server
+        .send(ResponseSuccess::null(answered.id.clone()))
+        .await;
+    second.response().await;
+
+    second.request(2, "test/pending", json!(null)).await;
+    let pending = server.request().await;
+    first.request(1, "test/other", json!(null)).await;
+    assert_eq!(server.request().await.method, "test/other");
+
+    // Only the unanswered request of the disconnected client is cancelled,
+    // once it had a moment to read the response in case it only half-closed
+    // the connection.
+    drop(second);
+    let notif = server.notification().await;
+    assert_eq!(notif.method, "$/cancelRequest");
+    assert_eq!(notif.params, json!({ "id": pending.id }));
+    env.wait_for_clients(1).await;
+}
+
+#[tokio::test]
+async fn client_cancellations_use_the_server_request_id() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    client.request(1, "test/slow", json!(null)).await;
+    let req = server.request().await;
+    client.notify("$/cancelRequest", json!({ "id": 1 })).await;
+    let notif = server.notification().await;
+    assert_eq!(notif.method, "$/cancelRequest");
+    assert_eq!(notif.params, json!({ "id": req.id }));
+
+    // Requests which were already answered can't be cancelled anymore.
+    server.send(ResponseSuccess::null(req.id)).await;
+    client.response().await;
+    client.notify("$/cancelRequest", json!({ "id": 1 })).await;
+    client.notify("$/cancelRequest", json!({ "id": 7 })).await;
+    client.notify("test/after", json!(null)).await;
+    assert_eq!(server.notification().await.method, "test/after");
+}
+
+#[tokio::test]
+async fn null_id_errors_are_broadcast() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    server.send(parse_error()).await;
+    for client in [&mut first, &mut second] {
+        match client.recv().await {
+            Message::ResponseError(res) => assert_eq!(res.id, None),
+            other => panic!("expected error response, got {other:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn null_id_errors_can_be_dropped() {
+    let mut env = TestEnv::with_config(Config {
+        null_id_responses: NullIdResponses::Drop,
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    server.send(parse_error()).await;
+    server
+        .send(Notification {
+            jsonrpc: Version,
+            method: "window/logMessage".into(),
+            params: json!({ "type": 4, "message": "hello" }),
+        })
+        .await;
+    assert!(matches!(client.recv().await, Message::Notification(_)));
+}
+
+#[tokio::test]
+async fn request_ids_can_be_forwarded_unchanged() {
+    let mut env = TestEnv::with_config(Config {
+        rewrite_request_ids: false,
+        ..Config::default()
+    })
+    .await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    first.request(1, "test/request", json!(null)).await;
+    second.request(2, "test/request", json!(null)).await;
+    let mut ids = vec![server.request().await.id, server.request().await.id];
+    ids.sort_by_key(|id| format!("{id:?}"));
+    assert_eq!(ids, [RequestId::Number(1), RequestId::Number(2)]);
+    for id in [2, 1] {
+        server
+            .send(ResponseSuccess {
+                jsonrpc: Version,
+                result: json!(id),
+                id: RequestId::Number(id),
+            })
+            .await;
+    }
+    assert_eq!(second.response().await.result, json!(2));
+    assert_eq!(first.response().await.result, json!(1));
+}
+
+#[tokio::test]
+async fn capabilities_are_overridden() {
+    let config = "capabilities_override = { textDocument = { inlayHint = {} } }";
+    let mut env = TestEnv::with_config(toml::from_str(config).unwrap()).await;
+    let _client = env.client().await;
+    let mut server = env.open_server().await;
+    let init = server.request().await;
+    assert_eq!(
+        init.params["capabilities"],
+        json!({ "textDocument": { "inlayHint": {} } })
+    );
+}
+
+#[tokio::test]
+async fn conflicting_clients_are_refused_in_strict_mode() {
+    let mut env = TestEnv::with_config(toml::from_str("strict_initialize = true").unwrap()).await;
+    let capabilities = json!({ "window": { "workDoneProgress": true } });
+    let mut first = env
+        .client_with_capabilities(env.options(), Some(capabilities.clone()))
+        .await;
+    let _server = env.server().await;
+    first.initialized().await;
+
+    // Less capable clients are fine.
+    let mut second = env.client().await;
+    second.initialized().await;
+    let mut third = env
+        .client_with_capabilities(env.options(), Some(capabilities))
+        .await;
+    third.initialized().await;
+
+    let capabilities = json!({ "window": { "showDocument": { "support": true } } });
+    let mut conflicting = env
+        .client_with_capabilities(env.options(), Some(capabilities))
+        .await;
+    assert_eq!(
+        conflicting.rejected().await,
+        RejectReason::InitializeConflict
+    );
+}
+
+#[tokio::test]
+async fn telemetry_goes_to_the_file_instead_of_clients() {
+    let path = std::env::temp_dir().join(format!(
+        "ra-multiplex-telemetry-{}.jsonl",
+        std::process::id()
+    ));
+    _ = fs::remove_file(&path);
+    let mut env = TestEnv::with_config(Config {
+        telemetry_file: Some(path.clone()),
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    server
+        .send(Notification {
+            jsonrpc: Version,
+            method: "telemetry/event".into(),
+            params: json!({ "event": "indexed" }),
+        })
+        .await;
+    server
+        .send(Notification {
+            jsonrpc: Version,
+            method: "window/logMessage".into(),
+            params: json!({ "type": 4, "message": "hello" }),
+        })
+        .await;
+    match client.recv().await {
+        Message::Notification(notif) => assert_eq!(notif.method, "window/logMessage"),
+        other => panic!("expected notification, got {other:?}"),
+    }
+    // The file is written by its own task.
+    let mut telemetry = String::new();
+    while telemetry.is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        telemetry = fs::read_to_string(&path).unwrap_or_default();
+    }
+    fs::remove_file(&path).unwrap();
+    assert_eq!(telemetry, "{\"event\":\"indexed\"}\n");
+}
+
+#[tokio::test]
+async fn less_severe_diagnostics_are_filtered() {
+    let mut env = TestEnv::with_config(Config {
+        diagnostics_severity: Some(DiagnosticSeverity::Warning),
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    server
+        .send(Notification {
+            jsonrpc: Version,
+            method: "textDocument/publishDiagnostics".into(),
+            params: json!({
+                "uri": "file:///lib.rs",
+                "version": 3,
+                "diagnostics": [
+                    { "message": "error", "severity": 1 },
+                    { "message": "hint", "severity": 4 },
+                    { "message": "warning", "severity": 2 },
+                    { "message": "information", "severity": 3 },
+                    { "message": "unknown" },
+                ],
+            }),
+        })
+        .await;
+    let Message::Notification(notif) = client.recv().await else {
+        panic!("expected notification");
+    };
+    assert_eq!(
+        notif.params,
+        json!({
+            "uri": "file:///lib.rs",
+            "version": 3,
+            "diagnostics": [
+                { "message": "error", "severity": 1 },
+                { "message": "warning", "severity": 2 },
+                { "message": "unknown" },
+            ],
+        })
+    );
+}
+
+#[tokio::test]
+async fn trace_levels_are_kept_per_client() {
+    let mut env = TestEnv::new().await;
+    let mut messages = env.client().await;
+    let mut server = env.server().await;
+    messages.initialized().await;
+    let mut verbose = env.client().await;
+    verbose.initialized().await;
+    let mut off = env.client().await;
+    off.initialized().await;
+    env.wait_for_clients(3).await;
+
+    messages
+        .notify("$/setTrace", json!({ "value": "messages" }))
+        .await;
+    let notif = server.notification().await;
+    assert_eq!(notif.method, "$/setTrace");
+    assert_eq!(notif.params, json!({ "value": "messages" }));
+    verbose
+        .notify("$/setTrace", json!({ "value": "verbose" }))
+        .await;
+    assert_eq!(server.notification().await.params["value"], "verbose");
+
+    let notification = |method: &str, params| Notification {
+        jsonrpc: Version,
+        method: method.into(),
+        params,
+    };
+    server
+        .send(notification(
+            "$/logTrace",
+            json!({ "message": "hover", "verbose": "details" }),
+        ))
+        .await;
+    server.send(notification("$/custom", json!({}))).await;
+    let Message::Notification(notif) = verbose.recv().await else {
+        panic!("expected notification");
+    };
+    assert_eq!(
+        notif.params,
+        json!({ "message": "hover", "verbose": "details" })
+    );
+    let Message::Notification(notif) = messages.recv().await else {
+        panic!("expected notification");
+    };
+    assert_eq!(notif.params, json!({ "message": "hover" }));
+    let Message::Notification(notif) = off.recv().await else {
+        panic!("expected notification");
+    };
+    assert_eq!(notif.method, "$/custom");
+
+    // The server goes back to the level of the remaining clients.
+    drop(verbose);
+    loop {
+        let notif = server.notification().await;
+        if notif.method == "$/setTrace" {
+            assert_eq!(notif.params["value"], "messages");
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn exiting_clients_leave_the_server_running() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+    env.wait_for_clients(2).await;
+
+    // `shutdown` is answered for the server and closes the connection.
+    first.request(1, "shutdown", json!(null)).await;
+    assert_eq!(first.response().await.id, RequestId::Number(1));
+    assert!(first.reader.read_message().await.unwrap().is_none());
+
+    // So does `exit` without `shutdown`.
+    second.notify("exit", json!(null)).await;
+    assert!(second.reader.read_message().await.unwrap().is_none());
+    env.wait_for_clients(0).await;
+
+    // The server didn't get either of them.
+    let mut third = env.client().await;
+    third.initialized().await;
+    third.request(1, "test/request", json!(null)).await;
+    assert_eq!(server.request().await.method, "test/request");
+}
+
+fn server_request(id: i64, method: &str) -> Request {
+    Request {
+        jsonrpc: Version,
+        method: method.into(),
+        params: json!({}),
+        id: RequestId::Number(id),
+    }
+}
+
+#[tokio::test]
+async fn server_requests_are_answered_by_the_first_client() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+    env.wait_for_clients(2).await;
+
+    server
+        .send(server_request(1, "workspace/configuration"))
+        .await;
+    let Message::Request(req) = first.recv().await else {
+        panic!("expected server request");
+    };
+    assert_eq!(req.method, "workspace/configuration");
+    first
+        .send(ResponseSuccess {
+            jsonrpc: Version,
+            result: json!([{ "check": "clippy" }]),
+            id: req.id,
+        })
+        .await;
+    match server.recv().await {
+        Message::ResponseSuccess(res) => {
+            assert_eq!(res.id, RequestId::Number(1));
+            assert_eq!(res.result, json!([{ "check": "clippy" }]));
+        }
+        other => panic!("expected response, got {other:?}"),
+    }
+
+    // The second client never sees it, the next message is this one.
+    server
+        .send(Notification {
+            jsonrpc: Version,
+            method: "window/logMessage".into(),
+            params: json!({ "type": 4, "message": "hello" }),
+        })
+        .await;
+    assert!(matches!(second.recv().await, Message::Notification(_)));
+}
+
+#[tokio::test]
+async fn pending_requests_are_listed() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    env.wait_for_clients(1).await;
+
+    client.request(5, "textDocument/hover", json!({})).await;
+    let forwarded = server.request().await;
+    server
+        .send(server_request(1, "workspace/configuration"))
+        .await;
+    let Message::Request(_) = client.recv().await else {
+        panic!("expected server request");
+    };
+
+    let workspace_root = env.dir.to_str().unwrap().to_owned();
+    let id_map = || LspMuxOptions {
+        version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+        token: None,
+        method: ext::Request::IdMap {
+            workspace_root: workspace_root.clone(),
+            limit: 10,
+        },
+    };
+    let mut ctl = env.client_with(id_map()).await;
+    let res = serde_json::from_value::<ext::IdMapResponse>(ctl.response().await.result).unwrap();
+    let instance = &res.instances[0];
+    assert_eq!(instance.client_requests, 1);
+    let req = &instance.oldest_client_requests[0];
+    assert_eq!(req.id, RequestId::Number(5));
+    assert_eq!(req.server_id, forwarded.id);
+    assert_eq!(req.method, "textDocument/hover");
+    assert_eq!(instance.server_requests, 1);
+    let req = &instance.oldest_server_requests[0];
+    assert_eq!(req.id, RequestId::Number(1));
+    assert_eq!(req.method, "workspace/configuration");
+    assert_eq!(req.clients.len(), 1);
+
+    server.send(ResponseSuccess::null(forwarded.id)).await;
+    client.recv().await;
+    let mut ctl = env.client_with(id_map()).await;
+    let res = serde_json::from_value::<ext::IdMapResponse>(ctl.response().await.result).unwrap();
+    assert_eq!(res.instances[0].client_requests, 0);
+    assert!(res.instances[0].oldest_client_requests.is_empty());
+}
+
+#[tokio::test]
+async fn server_requests_are_routed_by_method() {
+    let mut env = TestEnv::with_config(Config {
+        server_request_routes: [
+            ("window/showMessageRequest".into(), ServerRequests::Drop),
+            ("workspace/configuration".into(), ServerRequests::Broadcast),
+        ]
+        .into(),
+        ..Config::default()
+    })
+    .await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+    env.wait_for_clients(2).await;
+
+    server
+        .send(server_request(1, "window/showMessageRequest"))
+        .await;
+    match server.recv().await {
+        Message::ResponseError(res) => assert_eq!(res.id, Some(RequestId::Number(1))),
+        other => panic!("expected error response, got {other:?}"),
+    }
+
+    server
+        .send(server_request(2, "workspace/configuration"))
+        .await;
+    for client in [&mut first, &mut second] {
+        let Message::Request(req) = client.recv().await else {
+            panic!("expected server request");
+        };
+        assert_eq!(req.method, "workspace/configuration");
+    }
+}
+
+#[tokio::test]
+async fn broadcast_server_requests_use_the_first_response() {
+    let mut env = TestEnv::with_config(Config {
+        server_requests: ServerRequests::Broadcast,
+        ..Config::default()
+    })
+    .await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+    env.wait_for_clients(2).await;
+
+    server
+        .send(server_request(1, "window/showMessageRequest"))
+        .await;
+    for (client, answer) in [(&mut second, "second"), (&mut first, "first")] {
+        let Message::Request(req) = client.recv().await else {
+            panic!("expected server request");
+        };
+        client
+            .send(ResponseSuccess {
+                jsonrpc: Version,
+                result: json!({ "title": answer }),
+                id: req.id,
+            })
+            .await;
+    }
+
+    // Only the first response is forwarded.
+    server
+        .send(server_request(2, "window/showMessageRequest"))
+        .await;
+    let Message::Request(req) = first.recv().await else {
+        panic!("expected server request");
+    };
+    first.send(ResponseSuccess::null(req.id)).await;
+    for (id, result) in [(1, json!({ "title": "second" })), (2, json!(null))] {
+        match server.recv().await {
+            Message::ResponseSuccess(res) => {
+                assert_eq!((res.id, res.result), (RequestId::Number(id), result));
+            }
+            other => panic!("expected response, got {other:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn edits_go_to_the_client_running_the_command() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    second
+        .request(1, "workspace/executeCommand", json!({ "command": "fix" }))
+        .await;
+    let command = server.request().await;
+    server.send(server_request(7, "workspace/applyEdit")).await;
+    let Message::Request(req) = second.recv().await else {
+        panic!("expected server request");
+    };
+    assert_eq!(req.method, "workspace/applyEdit");
+    second
+        .send(ResponseSuccess {
+            jsonrpc: Version,
+            result: json!({ "applied": true }),
+            id: req.id,
+        })
+        .await;
+    match server.recv().await {
+        Message::ResponseSuccess(res) => {
+            assert_eq!(res.id, RequestId::Number(7));
+            assert_eq!(res.result, json!({ "applied": true }));
+        }
+        other => panic!("expected response, got {other:?}"),
+    }
+    server.send(ResponseSuccess::null(command.id)).await;
+    assert_eq!(second.response().await.id, RequestId::Number(1));
+
+    // The first client never sees the edit, the next message is this one.
+    server
+        .send(Notification {
+            jsonrpc: Version,
+            method: "window/logMessage".into(),
+            params: json!({ "type": 4, "message": "hello" }),
+        })
+        .await;
+    assert!(matches!(first.recv().await, Message::Notification(_)));
+}
+
+#[tokio::test]
+async fn server_requests_fail_when_the_client_disconnects() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    env.wait_for_clients(1).await;
+
+    server.send(server_request(1, "window/showDocument")).await;
+    assert!(matches!(client.recv().await, Message::Request(_)));
+    drop(client);
+    match server.recv().await {
+        Message::ResponseError(res) => {
+            assert_eq!(res.id, Some(RequestId::Number(1)));
+            assert_eq!(res.error.code, -32803);
+        }
+        other => panic!("expected error response, got {other:?}"),
+    }
+}
+
+fn parse_error() -> ResponseError {
+    ResponseError {
+        jsonrpc: Version,
+        error: jsonrpc::Error {
+            code: -32700,
+            message: "Parse error".into(),
+            data: None,
+        },
+        id: None,
+    }
+}
+
+#[tokio::test]
+async fn client_progress_tokens_are_routed_to_their_client() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    // Both clients pick the same token.
+    let params = json!({ "textDocument": { "uri": "file:///lib.rs" }, "workDoneToken": 1 });
+    first.request(1, "test/progress", params.clone()).await;
+    let first_token = server.request().await.params["workDoneToken"].clone();
+    second.request(1, "test/progress", params).await;
+    let second_token = server.request().await.params["workDoneToken"].clone();
+    assert_ne!(first_token, second_token);
+
+    for (token, message) in [(second_token, "second"), (json!("server"), "everyone")] {
+        server
+            .send(Notification {
+                jsonrpc: Version,
+                method: "$/progress".into(),
+                params: json!({ "token": token, "value": { "kind": "begin", "title": message } }),
+            })
+            .await;
+    }
+
+    let Message::Notification(notif) = second.recv().await else {
+        panic!("expected notification");
+    };
+    assert_eq!(notif.params["token"], 1);
+    assert_eq!(notif.params["value"]["title"], "second");
+    for client in [&mut first, &mut second] {
+        let Message::Notification(notif) = client.recv().await else {
+            panic!("expected notification");
+        };
+        assert_eq!(notif.params["token"], "server");
+    }
+}
+
+#[tokio::test]
+async fn progress_cancellations_use_the_server_token() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    let params = json!({ "textDocument": { "uri": "file:///lib.rs" }, "workDoneToken": 1 });
+    client.request(1, "test/progress", params).await;
+    let client_token = server.request().await.params["workDoneToken"].clone();
+    client
+        .notify("window/workDoneProgress/cancel", json!({ "token": 1 }))
+        .await;
+    let notif = server.notification().await;
+    assert_eq!(notif.method, "window/workDoneProgress/cancel");
+    assert_eq!(notif.params["token"], client_token);
+
+    server
+        .send(Request {
+            jsonrpc: Version,
+            method: "window/workDoneProgress/create".into(),
+            params: json!({ "token": "server" }),
+            id: RequestId::Number(1),
+        })
+        .await;
+    assert!(matches!(server.recv().await, Message::ResponseSuccess(_)));
+    assert!(matches!(client.recv().await, Message::Request(_)));
+    client
+        .notify(
+            "window/workDoneProgress/cancel",
+            json!({ "token": "server" }),
+        )
+        .await;
+    assert_eq!(server.notification().await.params["token"], "server");
+}
+
+#[tokio::test]
+async fn status_shows_server_progress() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    let values = [
+        json!({ "kind": "begin", "title": "Indexing", "percentage": 0 }),
+        json!({ "kind": "report", "message": "1/2 (core)", "percentage": 42 }),
+        json!({ "kind": "end" }),
+    ];
+    let mut progress = Vec::new();
+    for value in values {
+        server
+            .send(Notification {
+                jsonrpc: Version,
+                method: "$/progress".into(),
+                params: json!({ "token": "rustAnalyzer/Indexing", "value": value }),
+            })
+            .await;
+        client.recv().await;
+        progress.push(env.status().await.instances[0].progress.clone());
+    }
+    let indexing = |message: Option<&str>, percentage| ext::Progress {
+        title: "Indexing".into(),
+        message: message.map(Into::into),
+        percentage: Some(percentage),
+    };
+    assert_eq!(
+        progress,
+        [
+            vec![indexing(None, 0)],
+            vec![indexing(Some("1/2 (core)"), 42)],
+            vec![],
+        ]
+    );
+}
+
+#[tokio::test]
+async fn idle_instances_are_shut_down_gracefully() {
+    let mut env = TestEnv::with_config(Config {
+        instance_timeout: Some(0),
+        gc_interval: 1,
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    drop(client);
+
+    let req = server.request().await;
+    assert_eq!(req.method, "shutdown");
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(server.notification().await.method, "exit");
+
+    // The fake server exits when we stop feeding its stdout.
+    drop(server);
+    env.wait_for_instances(0).await;
+}
+
+#[tokio::test]
+async fn pinned_instances_survive_idle_shutdown() {
+    let mut env = TestEnv::with_config(Config {
+        instance_timeout: Some(0),
+        gc_interval: 1,
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    let workspace_root = env.dir.to_str().unwrap().to_owned();
+    let pin = |pinned| LspMuxOptions {
+        version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+        token: None,
+        method: ext::Request::Pin {
+            workspace_root: workspace_root.clone(),
+            pinned,
+        },
+    };
+    let mut ctl = env.client_with(pin(true)).await;
+    let res = serde_json::from_value::<ext::PinResponse>(ctl.response().await.result).unwrap();
+    assert!(res.instances[0].pinned);
+    drop(client);
+
+    // Wait for the garbage collector to see the idle instance a few times.
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    let status = env.status().await;
+    assert!(status.instances[0].pinned && status.instances[0].clients.is_empty());
+
+    let mut ctl = env.client_with(pin(false)).await;
+    ctl.response().await;
+    let req = server.request().await;
+    assert_eq!(req.method, "shutdown");
+}
+
+#[tokio::test]
+async fn project_configs_override_the_instance_timeout() {
+    let mut env = TestEnv::with_config(Config {
+        instance_timeout: Some(0),
+        gc_interval: 1,
+        ..Config::default()
+    })
+    .await;
+    let project = env.dir.join(".ra-multiplex.toml");
+    fs::write(&project, "instance_timeout = \"never\"").unwrap();
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    drop(client);
+
+    // Wait for the garbage collector to see the idle instance a few times.
+    env.wait_for_clients(0).await;
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(env.status().await.instances.len(), 1);
+
+    // The project config is read again when the last client leaves.
+    let mut client = env.client().await;
+    client.initialized().await;
+    fs::write(&project, "instance_timeout = 1").unwrap();
+    drop(client);
+    let req = server.request().await;
+    assert_eq!(req.method, "shutdown");
+}
+
+#[tokio::test]
+async fn private_instances_arent_shared() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    // The private client spawns its own server with the same pipes.
+    let _private = env.client_with(env.private_options()).await;
+    assert_eq!(server.request().await.method, "initialize");
+}
+
+#[tokio::test]
+async fn private_instances_shut_down_with_their_client() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client_with(env.private_options()).await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    env.wait_for_clients(1).await;
+
+    // Instances are normally kept around after the last client leaves.
+    drop(client);
+    let req = server.request().await;
+    assert_eq!(req.method, "shutdown");
+}
+
+#[tokio::test]
+async fn servers_outside_the_allow_list_are_refused() {
+    let mut env = TestEnv::with_config(Config {
+        allowed_servers: Some(["rust-analyzer".to_owned()].into()),
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+
+    assert_eq!(client.rejected().await, RejectReason::ServerFailed);
+    assert!(env.status().await.instances.is_empty());
+}
+
+#[tokio::test]
+async fn project_config_overrides_the_client_invocation() {
+    let mut env = TestEnv::with_config(Config {
+        trust_project_config: true,
+        ..Config::default()
+    })
+    .await;
+    let mut options = env.options();
+    let ext::Request::Connect { args, .. } = &mut options.method else {
+        unreachable!();
+    };
+    let project_args = std::mem::replace(args, vec!["-c".into(), "exit 1".into()]);
+    let project = toml::to_string(&toml::toml! { args = project_args }).unwrap();
+    fs::write(env.dir.join(".ra-multiplex.toml"), project).unwrap();
+
+    let mut client = env.client_with(options).await;
+    let _server = env.server().await;
+    client.initialized().await;
+}
+
+#[tokio::test]
+async fn untrusted_project_configs_are_ignored() {
+    let mut env = TestEnv::new().await;
+    fs::write(
+        env.dir.join(".ra-multiplex.toml"),
+        "server = \"/bin/false\"",
+    )
+    .unwrap();
+
+    let mut client = env.client().await;
+    let _server = env.server().await;
+    client.initialized().await;
+}
+
+#[tokio::test]
+async fn project_config_cant_escape_the_allow_list() {
+    let mut env = TestEnv::with_config(Config {
+        allowed_servers: Some(["sh".to_owned()].into()),
+        ..Config::default()
+    })
+    .await;
+    fs::write(env.dir.join(".ra-multiplex.toml"), "server = \"/bin/true\"").unwrap();
+    let mut client = env.client().await;
+
+    assert_eq!(client.rejected().await, RejectReason::ServerFailed);
+    assert!(env.status().await.instances.is_empty());
+}
+
+#[tokio::test]
+async fn allow_lists_dont_trust_project_args_and_env() {
+    let mut env = TestEnv::with_config(Config {
+        allowed_servers: Some(["sh".to_owned()].into()),
+        ..Config::default()
+    })
+    .await;
+    fs::write(
+        env.dir.join(".ra-multiplex.toml"),
+        "args = [\"-c\", \"exit 1\"]\nenv = { LD_PRELOAD = \"/tmp/evil.so\" }",
+    )
+    .unwrap();
+    let mut client = env.client().await;
+    let _server = env.server().await;
+    client.initialized().await;
+
+    let ext::Request::Connect { args, .. } = env.options().method else {
+        unreachable!();
+    };
+    let instance = env.status().await.instances.remove(0);
+    assert_eq!(instance.args, args);
+    assert!(!instance.env.contains_key("LD_PRELOAD"));
+}
+
+#[tokio::test]
+async fn shutdown_flushes_responses_and_closes_clients() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    client.request(1, "test/pending", json!(null)).await;
+    let pending = server.request().await;
+
+    let instance_map = env.instance_map.clone();
+    let shutdown = task::spawn(async move { InstanceMap::shutdown(&instance_map).await });
+
+    let req = server.request().await;
+    assert_eq!(req.method, "shutdown");
+    server.send(ResponseSuccess::null(pending.id)).await;
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(server.notification().await.method, "exit");
+    drop(server);
+    shutdown.await.unwrap();
+
+    assert_eq!(client.response().await.id, RequestId::Number(1));
+    assert!(client.reader.read_message().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn crashed_servers_are_restarted() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    client
+        .notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": "file:///lib.rs",
+                    "languageId": "rust",
+                    "version": 0,
+                    "text": "fn main() {}\n",
+                }
+            }),
+        )
+        .await;
+    assert_eq!(server.notification().await.method, "textDocument/didOpen");
+    client
+        .notify(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": "file:///lib.rs", "version": 1 },
+                "contentChanges": [{
+                    "range": {
+                        "start": { "line": 0, "character": 3 },
+                        "end": { "line": 0, "character": 7 },
+                    },
+                    "text": "test",
+                }],
+            }),
+        )
+        .await;
+    assert_eq!(server.notification().await.method, "textDocument/didChange");
+
+    env.crash(server).await;
+
+    // A new server is initialized and gets the current document text.
+    let mut server = env.server().await;
+    let notif = server.notification().await;
+    assert_eq!(notif.method, "textDocument/didOpen");
+    assert_eq!(notif.params["textDocument"]["version"], 1);
+    assert_eq!(notif.params["textDocument"]["text"], "fn test() {}\n");
+
+    // The client is only told about the restart.
+    let notif = match client.recv().await {
+        Message::Notification(notif) => notif,
+        message => panic!("expected notification, got {message:?}"),
+    };
+    assert_eq!(notif.method, "window/showMessage");
+    assert_eq!(notif.params["message"], "sh restarted, re-indexing");
+    client.request(1, "test/request", json!(null)).await;
+    let req = server.request().await;
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(client.response().await.id, RequestId::Number(1));
+}
+
+#[tokio::test]
+async fn handed_over_servers_get_the_current_documents() {
+    // Every start of this server uses the next pair of pipes.
+    const SERVER: &str = r#"n=$(cat "$2" 2>/dev/null || echo 0); echo $((n + 1)) > "$2"
+exec 3<&0; cat <&3 > "$1$n-stdin" & exec cat "$1$n-stdout" 0<&- 3<&-"#;
+    let mut env = TestEnv::new().await;
+    env.create_pipes("server0");
+    env.create_pipes("server1");
+    let mut options = env.options();
+    let ext::Request::Connect { args, .. } = &mut options.method else {
+        unreachable!();
+    };
+    *args = vec![
+        "-c".into(),
+        SERVER.into(),
+        "sh".into(),
+        env.dir.join("server").to_str().unwrap().into(),
+        env.dir.join("count").to_str().unwrap().into(),
+    ];
+    let mut client = env.client_with(options).await;
+    let mut old = env.server_named("server0").await;
+    client.initialized().await;
+    client
+        .notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": "file:///lib.rs",
+                    "languageId": "rust",
+                    "version": 0,
+                    "text": "fn main() {}\n",
+                }
+            }),
+        )
+        .await;
+    assert_eq!(old.notification().await.method, "textDocument/didOpen");
+    client.request(1, "test/request", json!(null)).await;
+    old.request().await;
+
+    let mut ctl = env
+        .client_with(LspMuxOptions {
+            version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+            token: None,
+            method: ext::Request::Handover {
+                workspace_root: Some(env.dir.to_str().unwrap().into()),
+            },
+        })
+        .await;
+    // The old server keeps serving while the new one initializes.
+    let change = |version: u64, text: &str| {
+        json!({
+            "textDocument": { "uri": "file:///lib.rs", "version": version },
+            "contentChanges": [{ "text": text }],
+        })
+    };
+    client
+        .notify("textDocument/didChange", change(1, "fn test() {}\n"))
+        .await;
+    assert_eq!(old.notification().await.method, "textDocument/didChange");
+
+    let mut new = env.server_named("server1").await;
+    let notif = new.notification().await;
+    assert_eq!(notif.method, "textDocument/didOpen");
+    assert_eq!(notif.params["textDocument"]["version"], 1);
+    assert_eq!(notif.params["textDocument"]["text"], "fn test() {}\n");
+
+    let res = match ctl.recv().await {
+        Message::ResponseSuccess(res) => res,
+        message => panic!("expected handover response, got {message:?}"),
+    };
+    let res = serde_json::from_value::<ext::HandoverResponse>(res.result).unwrap();
+    assert_eq!(res.instances.len(), 1);
+    assert!(res.instances[0].pid.is_some(), "{res:?}");
+    assert_ne!(res.instances[0].pid, Some(res.instances[0].old_pid));
+
+    // The request the old server didn't answer fails.
+    match client.recv().await {
+        Message::ResponseError(err) => {
+            assert_eq!(err.id, Some(RequestId::Number(1)));
+            assert_eq!(err.error.code, -32801);
+        }
+        message => panic!("expected error response, got {message:?}"),
+    }
+
+    client
+        .notify("textDocument/didChange", change(2, "fn main() {}\n"))
+        .await;
+    let notif = new.notification().await;
+    assert_eq!(notif.method, "textDocument/didChange");
+    assert_eq!(notif.params["textDocument"]["version"], 2);
+
+    // The old server's stdin is closed.
+    assert!(old.reader.read_message().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn unresponsive_servers_are_restarted() {
+    let mut env = TestEnv::with_config(Config {
+        watchdog_interval: Some(1),
+        watchdog_threshold: 2,
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    // An error is still an answer.
+    let ping = server.request().await;
+    assert_eq!(ping.method, "$/lspmux/ping");
+    server
+        .send(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: -32601,
+                message: "Method not found".into(),
+                data: None,
+            },
+            id: Some(ping.id),
+        })
+        .await;
+    for _ in 0..2 {
+        assert_eq!(server.request().await.method, "$/lspmux/ping");
+    }
+    // The fake server is still there, the watchdog kills it.
+    while InstanceMap::health(&env.instance_map).await.instances[0].running {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    drop(server);
+
+    let mut server = env.server().await;
+    client.request(1, "test/request", json!(null)).await;
+    let mut req = server.request().await;
+    while req.method == "$/lspmux/ping" {
+        req = server.request().await;
+    }
+    assert_eq!(req.method, "test/request");
+}
+
+#[tokio::test]
+async fn servers_closing_stdin_are_restarted() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let server = env.server().await;
+    client.initialized().await;
+
+    // The fake server keeps running with its stdin closed once it fails to
+    // forward a message to the pipe nobody reads.
+    let FakeServer { reader, writer } = server;
+    drop(reader);
+    while InstanceMap::health(&env.instance_map).await.instances[0].running {
+        client.notify("test/notification", json!(null)).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    drop(writer);
+
+    // The message which failed to send goes to the restarted server.
+    let mut server = env.server().await;
+    assert_eq!(server.notification().await.method, "test/notification");
+}
+
+#[tokio::test]
+async fn late_clients_get_the_cached_initialize_response() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    let first_result = first.initialized().await;
+    drop(first);
+    env.wait_for_clients(0).await;
+
+    let mut second = env.client().await;
+    let second_result = second.initialized().await;
+    assert_eq!(first_result, second_result);
+    assert_eq!(second_result["capabilities"]["hoverProvider"], true);
+
+    // The server never saw the second `initialize` or `initialized`.
+    second.request(1, "test/request", json!(null)).await;
+    assert_eq!(server.request().await.method, "test/request");
+}
+
+#[tokio::test]
+async fn shared_documents_are_opened_and_closed_once() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    let open = json!({
+        "textDocument": {
+            "uri": "file:///lib.rs",
+            "languageId": "rust",
+            "version": 0,
+            "text": "",
+        }
+    });
+    let close = json!({ "textDocument": { "uri": "file:///lib.rs" } });
+    first.notify("textDocument/didOpen", open.clone()).await;
+    assert_eq!(server.notification().await.method, "textDocument/didOpen");
+    // Clients are handled concurrently, make sure the second one is an owner
+    // before the first one closes the file.
+    second.notify("textDocument/didOpen", open).await;
+    while env.status().await.instances[0]
+        .clients
+        .iter()
+        .any(|client| client.files.is_empty())
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    first.notify("textDocument/didClose", close.clone()).await;
+    second
+        .notify(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": "file:///lib.rs", "version": 1 },
+                "contentChanges": [{ "text": "fn main() {}" }],
+            }),
+        )
+        .await;
+    second.notify("textDocument/didClose", close).await;
+
+    // Only the last owner closes the file on the server.
+    for method in ["textDocument/didChange", "textDocument/didClose"] {
+        assert_eq!(server.notification().await.method, method);
+    }
+}
+
+#[tokio::test]
+async fn shared_workspace_folders_are_removed_by_the_last_client() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    let folder = json!({ "uri": "file:///other", "name": "other" });
+    let add = json!({ "event": { "added": [folder], "removed": [] } });
+    let remove = json!({ "event": { "added": [], "removed": [folder] } });
+    first
+        .notify("workspace/didChangeWorkspaceFolders", add.clone())
+        .await;
+    let notif = server.notification().await;
+    assert_eq!(notif.method, "workspace/didChangeWorkspaceFolders");
+    assert_eq!(notif.params["event"]["added"], json!([folder]));
+
+    // Clients are handled concurrently, a following notification shows when
+    // the server would've gotten the change.
+    second
+        .notify("workspace/didChangeWorkspaceFolders", add)
+        .await;
+    second.notify("test/marker", json!(null)).await;
+    assert_eq!(server.notification().await.method, "test/marker");
+    first
+        .notify("workspace/didChangeWorkspaceFolders", remove)
+        .await;
+    first.notify("test/marker", json!(null)).await;
+    assert_eq!(server.notification().await.method, "test/marker");
+
+    drop(second);
+    let notif = server.notification().await;
+    assert_eq!(notif.method, "workspace/didChangeWorkspaceFolders");
+    assert_eq!(notif.params["event"]["removed"], json!([folder]));
+}
+
+#[tokio::test]
+async fn requests_over_the_rate_limit_are_dropped() {
+    let mut env = TestEnv::with_config(Config {
+        rate_limit: Some(1),
+        rate_limit_action: RateLimitAction::Drop,
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    client.request(1, "test/allowed", json!(null)).await;
+    client.request(2, "test/dropped", json!(null)).await;
+    // Notifications aren't limited.
+    client.notify("test/notification", json!(null)).await;
+    match client.recv().await {
+        Message::ResponseError(res) => {
+            assert_eq!(res.id, Some(RequestId::Number(2)));
+            assert_eq!(res.error.code, -32802);
+        }
+        other => panic!("expected error response, got {other:?}"),
+    }
+    assert_eq!(server.request().await.method, "test/allowed");
+    assert_eq!(server.notification().await.method, "test/notification");
+}
+
+#[tokio::test]
+async fn only_the_rate_limited_methods_are_limited() {
+    let mut env = TestEnv::with_config(Config {
+        rate_limit: Some(1),
+        rate_limit_action: RateLimitAction::Drop,
+        rate_limited_methods: Some(["test/background".to_owned()].into()),
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    client.request(1, "test/background", json!(null)).await;
+    client.request(2, "test/background", json!(null)).await;
+    client.request(3, "test/interactive", json!(null)).await;
+    client.request(4, "test/interactive", json!(null)).await;
+    match client.recv().await {
+        Message::ResponseError(res) => assert_eq!(res.id, Some(RequestId::Number(2))),
+        other => panic!("expected error response, got {other:?}"),
+    }
+    assert_eq!(server.request().await.method, "test/background");
+    assert_eq!(server.request().await.method, "test/interactive");
+    assert_eq!(server.request().await.method, "test/interactive");
+}
+
+#[tokio::test]
+async fn methods_outside_the_policy_are_refused() {
+    let mut env = TestEnv::with_config(
+        toml::from_str(
+            r#"
+            allowed_methods = ["test/allowed", "test/notification", "workspace/executeCommand"]
+            denied_methods = ["workspace/executeCommand"]
+            "#,
+        )
+        .unwrap(),
+    )
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    client
+        .request(1, "workspace/executeCommand", json!(null))
+        .await;
+    client.request(2, "test/other", json!(null)).await;
+    client.notify("test/dropped", json!(null)).await;
+    client.request(3, "test/allowed", json!(null)).await;
+    client.notify("test/notification", json!(null)).await;
+    for id in [1, 2] {
+        match client.recv().await {
+            Message::ResponseError(res) => {
+                assert_eq!(res.id, Some(RequestId::Number(id)));
+                assert_eq!(res.error.code, -32601);
+            }
+            other => panic!("expected error response, got {other:?}"),
+        }
+    }
+    assert_eq!(server.request().await.method, "test/allowed");
+    assert_eq!(server.notification().await.method, "test/notification");
+
+    // Shutting down is always possible.
+    client.request(4, "shutdown", json!(null)).await;
+    assert_eq!(client.response().await.id, RequestId::Number(4));
+}
+
+#[tokio::test]
+async fn full_document_changes_are_coalesced() {
+    let mut env = TestEnv::with_config(Config {
+        coalesce_changes: Some(10_000),
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    let change = |uri: &str, version: i64, change: Value| {
+        json!({
+            "textDocument": { "uri": uri, "version": version },
+            "contentChanges": [change],
+        })
+    };
+    for version in 1..=3 {
+        let text = json!({ "text": format!("fn v{version}() {{}}") });
+        let params = change("file:///lib.rs", version, text);
+        client.notify("textDocument/didChange", params).await;
+    }
+    // A change of another document flushes the held one.
+    let params = change("file:///main.rs", 1, json!({ "text": "" }));
+    client.notify("textDocument/didChange", params).await;
+    let notif = server.notification().await;
+    assert_eq!(notif.params["textDocument"]["version"], 3);
+    assert_eq!(notif.params["contentChanges"][0]["text"], "fn v3() {}");
+
+    // Incremental changes aren't held and a request flushes the held change.
+    let range =
+        json!({ "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } });
+    let params = change(
+        "file:///main.rs",
+        2,
+        json!({ "range": range, "text": "//" }),
+    );
+    client.notify("textDocument/didChange", params).await;
+    client.request(1, "textDocument/hover", json!(null)).await;
+    assert_eq!(
+        server.notification().await.params["textDocument"]["version"],
+        1
+    );
+    assert_eq!(
+        server.notification().await.params["textDocument"]["version"],
+        2
+    );
+    assert_eq!(server.request().await.method, "textDocument/hover");
+}
+
+#[tokio::test]
+async fn held_changes_are_sent_after_the_window() {
+    let mut env = TestEnv::with_config(Config {
+        coalesce_changes: Some(20),
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    let params = json!({
+        "textDocument": { "uri": "file:///lib.rs", "version": 1 },
+        "contentChanges": [{ "text": "" }],
+    });
+    client.notify("textDocument/didChange", params).await;
+    assert_eq!(server.notification().await.method, "textDocument/didChange");
+}
+
+#[tokio::test]
+async fn requests_of_crashed_servers_fail() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    client.request(1, "test/answered", json!(null)).await;
+    let answered = server.request().await;
+    client.request(2, "test/lost", json!(null)).await;
+    server.request().await;
+    // The response written right before the crash still arrives.
+    server.send(ResponseSuccess::null(answered.id)).await;
+    env.crash(server).await;
+
+    assert_eq!(client.response().await.id, RequestId::Number(1));
+    match client.recv().await {
+        Message::ResponseError(res) => {
+            assert_eq!(res.id, Some(RequestId::Number(2)));
+            assert_eq!(res.error.code, -32603);
+        }
+        other => panic!("expected error response, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn messages_wait_for_a_restarting_server() {
+    let mut env = TestEnv::with_config(Config {
+        restart_message: RestartMessage::Off,
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let server = env.server().await;
+    client.initialized().await;
+    env.crash(server).await;
+
+    client.notify("test/notification", json!(null)).await;
+    client.request(1, "test/request", json!(null)).await;
+
+    let mut server = env.server().await;
+    assert_eq!(server.notification().await.method, "test/notification");
+    let req = server.request().await;
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(client.response().await.id, RequestId::Number(1));
+}
+
+#[tokio::test]
+async fn clients_flooding_a_restarting_server_are_paused() {
+    let mut env = TestEnv::with_config(Config {
+        restart_message: RestartMessage::Off,
+        server_queue_high_water: 16,
+        server_queue_low_water: 4,
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let server = env.server().await;
+    client.initialized().await;
+    env.crash(server).await;
+
+    // The messages over the high water mark wait in the socket buffers.
+    let count = 64;
+    for i in 0..count {
+        client.notify("test/notification", json!(i)).await;
+    }
+    client.request(1, "test/request", json!(null)).await;
+    let queued = || async { env.status().await.instances[0].queued_messages };
+    while queued().await < 16 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(queued().await, 16);
+
+    let mut server = env.server().await;
+    for i in 0..count {
+        let notif = server.notification().await;
+        assert_eq!(notif.params, json!(i));
+    }
+    let req = server.request().await;
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(client.response().await.id, RequestId::Number(1));
+}
+
+#[tokio::test]
+async fn cancellations_skip_the_messages_of_paused_clients() {
+    let mut env = TestEnv::with_config(Config {
+        restart_message: RestartMessage::Off,
+        server_queue_high_water: 16,
+        server_queue_low_water: 4,
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let server = env.server().await;
+    client.initialized().await;
+    env.crash(server).await;
+
+    client.request(1, "test/request", json!(null)).await;
+    for i in 0..32 {
+        client.notify("test/notification", json!(i)).await;
+    }
+    client.notify("$/cancelRequest", json!({ "id": 1 })).await;
+    let queued = || async { env.status().await.instances[0].queued_messages };
+    while queued().await < 17 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut server = env.server().await;
+    assert_eq!(server.request().await.method, "test/request");
+    for i in 0..15 {
+        assert_eq!(server.notification().await.params, json!(i));
+    }
+    assert_eq!(server.notification().await.method, "$/cancelRequest");
+    for i in 15..32 {
+        assert_eq!(server.notification().await.params, json!(i));
+    }
+}
+
+#[tokio::test]
+async fn health_reports_instances_which_are_not_running() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let server = env.server().await;
+    client.initialized().await;
+
+    let health = InstanceMap::health(&env.instance_map).await;
+    assert!(health.healthy, "{health:?}");
+    assert_eq!(health.instances.len(), 1);
+
+    // The instance is restarting until we answer the new handshake.
+    env.crash(server).await;
+    let health = InstanceMap::health(&env.instance_map).await;
+    assert!(!health.instances[0].running, "{health:?}");
+    assert!(health.instances[0].responsive, "{health:?}");
+
+    let _server = env.server().await;
+    while !InstanceMap::health(&env.instance_map).await.healthy {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn concurrent_clients_share_one_server() {
+    let mut env = TestEnv::new().await;
+
+    // All clients connect and send their first request before the server
+    // has answered the handshake.
+    let mut clients = Vec::new();
+    for i in 0..10 {
+        let mut client = env.client().await;
+        client.notify("initialized", json!({})).await;
+        client.request(1, "test/request", json!(i)).await;
+        clients.push(client);
+    }
+
+    // Only one server is spawned, further `initialize` requests would show
+    // up here before the client requests.
+    let mut server = env.server().await;
+    for _ in 0..10 {
+        let req = server.request().await;
+        assert_eq!(req.method, "test/request");
+        server
+            .send(ResponseSuccess {
+                jsonrpc: Version,
+                result: req.params,
+                id: req.id,
+            })
+            .await;
+    }
+
+    for (i, client) in clients.iter_mut().enumerate() {
+        assert_eq!(client.response().await.id, client.init_id);
+        let res = client.response().await;
+        assert_eq!((res.id, res.result), (RequestId::Number(1), json!(i)));
+    }
+    let status = env.status().await;
+    assert_eq!(status.instances.len(), 1);
+    assert_eq!(status.instances[0].clients.len(), 10);
+}
+
+#[tokio::test]
+async fn last_configuration_wins_until_its_client_leaves() {
+    let mut env = TestEnv::new().await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+
+    let method = "workspace/didChangeConfiguration";
+    first.notify(method, json!({ "settings": "first" })).await;
+    assert_eq!(server.notification().await.params["settings"], "first");
+    second.notify(method, json!({ "settings": "second" })).await;
+    assert_eq!(server.notification().await.params["settings"], "second");
+
+    // The server gets the configuration of the remaining client back.
+    drop(second);
+    let notif = server.notification().await;
+    assert_eq!(notif.method, method);
+    assert_eq!(notif.params["settings"], "first");
+}
+
+#[tokio::test]
+async fn passthrough_mode_relays_messages_unchanged() {
+    let config = Config {
+        no_multiplex: true,
+        ..Config::default()
+    };
+    let mut env = TestEnv::with_config(config).await;
+    let mut client = env.client().await;
+    let mut server = env.open_server().await;
+
+    // The server answers the client's own handshake.
+    let req = server.request().await;
+    assert_eq!(req.id, client.init_id);
+    assert_eq!(req.params["initializationOptions"]["lspMux"], Value::Null);
+    server
+        .send(ResponseSuccess {
+            jsonrpc: Version,
+            result: json!({ "capabilities": {} }),
+            id: req.id,
+        })
+        .await;
+    assert_eq!(client.initialized().await, json!({ "capabilities": {} }));
+    assert_eq!(server.notification().await.method, "initialized");
+
+    // Request IDs are not rewritten and the instance isn't shared.
+    client.request(1, "test/request", json!(null)).await;
+    let req = server.request().await;
+    assert_eq!(req.id, RequestId::Number(1));
+    assert!(env.status().await.instances.is_empty());
+
+    server
+        .send(ResponseSuccess {
+            jsonrpc: Version,
+            result: json!("ok"),
+            id: req.id,
+        })
+        .await;
+    let res = client.response().await;
+    assert_eq!((res.id, res.result), (RequestId::Number(1), json!("ok")));
+}
+
+#[tokio::test]
+async fn status_lists_instances_and_their_clients() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let _server = env.server().await;
+    client.initialized().await;
+    env.wait_for_clients(1).await;
+
+    let mut status_client = env
+        .client_with(LspMuxOptions {
+            version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+            token: None,
+            method: ext::Request::Status {},
+        })
+        .await;
+    // Status responses always use ID 0.
+    let res = match status_client.recv().await {
+        Message::ResponseSuccess(res) => res,
+        message => panic!("expected status response, got {message:?}"),
+    };
+    let status = serde_json::from_value::<StatusResponse>(res.result).unwrap();
+    assert_eq!(status.instances.len(), 1);
+    let instance = &status.instances[0];
+    assert_eq!(instance.workspace_root, env.dir.to_str().unwrap());
+    assert!(instance.started <= instance.last_used, "{instance:?}");
+    let ids = instance
+        .clients
+        .iter()
+        .map(|client| client.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [0]);
+}
+
+#[tokio::test]
+async fn starting_instances_dont_block_other_workspaces() {
+    let mut env = TestEnv::new().await;
+    let other_env = TestEnv::new().await;
+
+    let mut client = env.client().await;
+    let mut second_client = env.client().await;
+    let mut server = env.open_server().await;
+    let init = server.request().await;
+    assert_eq!(init.method, "initialize");
+
+    // While the first server is still initializing other workspaces and the
+    // status can still be served.
+    let mut other_client = env.client_with(other_env.options()).await;
+    let _other_server = other_env.server().await;
+    other_client.initialized().await;
+    assert_eq!(env.status().await.instances.len(), 1);
+
+    server
+        .send(ResponseSuccess {
+            jsonrpc: Version,
+            result: json!({ "capabilities": {} }),
+            id: init.id,
+        })
+        .await;
+    assert_eq!(server.notification().await.method, "initialized");
+    // Both clients share the instance and its only handshake.
+    client.initialized().await;
+    second_client.initialized().await;
+    let clients = |status: StatusResponse| {
+        status
+            .instances
+            .into_iter()
+            .find(|instance| instance.workspace_root == env.dir.to_str().unwrap())
+            .unwrap()
+            .clients
+            .len()
+    };
+    while clients(env.status().await) != 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(env.status().await.instances.len(), 2);
+}
+
+#[tokio::test]
+async fn killing_an_instance_leaves_other_workspaces_alone() {
+    let mut env = TestEnv::new().await;
+    let other_env = TestEnv::new().await;
+
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    // Both instances live in the first environment's map.
+    let mut other_client = env.client_with(other_env.options()).await;
+    let mut other_server = other_env.server().await;
+    other_client.initialized().await;
+    env.wait_for_instances(2).await;
+
+    let kill = |workspace_root: &str| LspMuxOptions {
+        version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+        token: None,
+        method: ext::Request::Kill {
+            workspace_root: workspace_root.into(),
+        },
+    };
+    let mut ctl = env.client_with(kill("/no/such/workspace")).await;
+    assert!(matches!(ctl.recv().await, Message::ResponseError(_)));
+
+    // Paths inside a workspace select its instance like `reload` does.
+    let mut ctl = env
+        .client_with(kill(env.dir.join("src").to_str().unwrap()))
+        .await;
+    let res = match ctl.recv().await {
+        Message::ResponseSuccess(res) => res,
+        message => panic!("expected kill response, got {message:?}"),
+    };
+    let res = serde_json::from_value::<ext::KillResponse>(res.result).unwrap();
+    assert_eq!(res.instances.len(), 1);
+    assert_eq!(res.instances[0].workspace_root, env.dir.to_str().unwrap());
+
+    // The killed instance shuts down and its client is disconnected.
+    let req = server.request().await;
+    assert_eq!(req.method, "shutdown");
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(server.notification().await.method, "exit");
+    drop(server);
+    assert!(client.reader.read_message().await.unwrap().is_none());
+    env.wait_for_instances(1).await;
+
+    other_client.request(1, "test/request", json!(null)).await;
+    let req = other_server.request().await;
+    other_server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(other_client.response().await.id, RequestId::Number(1));
+}
+
+#[tokio::test]
+async fn draining_servers_refuse_new_clients() {
+    let mut env = TestEnv::new().await;
+    env.instance_map.lock().await.drain(None);
+
+    let mut client = env.client().await;
+    assert_eq!(client.rejected().await, RejectReason::Draining);
+    assert!(env.status().await.draining);
+}
+
+#[tokio::test]
+async fn clients_with_incompatible_protocol_versions_are_refused() {
+    let mut env = TestEnv::new().await;
+
+    let mut options = env.options();
+    options.version = "2".into();
+    let mut client = env.client_with(options).await;
+    assert_eq!(client.rejected().await, RejectReason::IncompatibleVersion);
+    assert!(env.status().await.instances.is_empty());
+
+    // A newer minor version is still compatible.
+    let mut options = env.options();
+    options.version = format!("{}.1", LspMuxOptions::PROTOCOL_VERSION);
+    let mut client = env.client_with(options).await;
+    let _server = env.server().await;
+    client.initialized().await;
+}
+
+#[tokio::test]
+async fn clients_without_the_auth_token_are_refused() {
+    let mut env = TestEnv::new().await;
+    env.auth_token = Some(Arc::new("secret".into()));
+
+    for token in [None, Some("wrong")] {
+        let mut options = env.options();
+        options.token = token.map(String::from);
+        let mut client = env.client_with(options).await;
+        assert_eq!(client.rejected().await, RejectReason::Unauthorized);
+    }
+    assert!(env.status().await.instances.is_empty());
+
+    let mut options = env.options();
+    options.token = Some("secret".into());
+    let mut client = env.client_with(options).await;
+    let _server = env.server().await;
+    client.initialized().await;
+}
+
+#[tokio::test]
+async fn clients_retry_connecting_until_the_server_is_up() {
+    let env = TestEnv::new().await;
+    let socket = env.dir.join("server.sock");
+    let config = Config {
+        connect: Address::Unix(socket.clone()),
+        ..Config::default()
+    };
+    assert!(proxy::connect(&config).await.is_err());
+
+    let config = Config {
+        connect_retry: 5,
+        ..config
+    };
+    let listener = task::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let listener = tokio::net::UnixListener::bind(socket).unwrap();
+        listener.accept().await.unwrap()
+    });
+    proxy::connect(&config).await.unwrap();
+    listener.await.unwrap();
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_refused() {
+    use tokio::io::AsyncReadExt;
+
+    let env = TestEnv::new().await;
+    let socket = env.dir.join("server.sock");
+    let config = Config {
+        listen: Address::Unix(socket.clone()),
+        max_clients: 2,
+        ..Config::default()
+    };
+    let server = task::spawn(async move { server::run(&config).await });
+    while !socket.exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // A refused connection is closed right away, accepted ones wait for the
+    // `initialize` request.
+    async fn is_refused(stream: &mut UnixStream) -> bool {
+        let mut buf = [0];
+        let read = stream.read(&mut buf);
+        matches!(
+            tokio::time::timeout(Duration::from_millis(100), read).await,
+            Ok(Ok(0)),
+        )
+    }
+    let mut first = UnixStream::connect(&socket).await.unwrap();
+    let mut second = UnixStream::connect(&socket).await.unwrap();
+    let mut third = UnixStream::connect(&socket).await.unwrap();
+    assert!(!is_refused(&mut first).await);
+    assert!(!is_refused(&mut second).await);
+    assert!(is_refused(&mut third).await);
+
+    // Closing a connection makes room for a new one.
+    drop(first);
+    loop {
+        let mut next = UnixStream::connect(&socket).await.unwrap();
+        if !is_refused(&mut next).await {
+            break;
+        }
+    }
+    server.abort();
+}
+
+#[tokio::test]
+async fn draining_servers_exit_once_clients_are_gone() {
+    let env = TestEnv::new().await;
+    let socket = env.dir.join("server.sock");
+    let config = Config {
+        listen: Address::Unix(socket.clone()),
+        connect: Address::Unix(socket.clone()),
+        handshake_timeout: 60,
+        ..Config::default()
+    };
+    let server = {
+        let config = config.clone();
+        task::spawn(async move { server::run(&config).await })
+    };
+    while !socket.exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let client = UnixStream::connect(&socket).await.unwrap();
+
+    crate::ext::drain(&config, None).await.unwrap();
+    let status = crate::ext::ext_request::<StatusResponse>(&config, ext::Request::Status {})
+        .await
+        .unwrap();
+    assert!(status.draining);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!server.is_finished());
+
+    drop(client);
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn doctor_initializes_a_throwaway_project() {
+    let env = TestEnv::new().await;
+    let socket = env.dir.join("server.sock");
+    let config = Config {
+        listen: Address::Unix(socket.clone()),
+        connect: Address::Unix(socket.clone()),
+        server: "sh".into(),
+        server_args: vec![
+            "-c".into(),
+            FAKE_SERVER.into(),
+            "sh".into(),
+            env.dir.join("server-stdin").to_str().unwrap().into(),
+            env.dir.join("server-stdout").to_str().unwrap().into(),
+        ],
+        ..Config::default()
+    };
+    let _server = {
+        let config = config.clone();
+        task::spawn(async move { server::run(&config).await })
+    };
+    while !socket.exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let doctor = task::spawn(async move { crate::ext::doctor(&config).await });
+    let _server = env.server().await;
+    doctor.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn silent_connections_time_out() {
+    let env = TestEnv::with_config(Config {
+        handshake_timeout: 1,
+        ..Config::default()
+    })
+    .await;
+    let (_ours, theirs) = UnixStream::pair().unwrap();
+    let result = client::process(
+        Stream::Unix { unix: theirs },
+        0,
+        env.instance_map.clone(),
+        None,
+    )
+    .await;
+    let err = result.unwrap_err();
+    assert!(format!("{err:#}").contains("timed out"), "{err:#}");
+}
+
+#[tokio::test]
+async fn clients_sending_huge_messages_are_disconnected() {
+    use std::io::Write;
+
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    let mut other = env.client().await;
+    other.initialized().await;
+    env.wait_for_clients(2).await;
+
+    (&client.socket)
+        .write_all(b"Content-Length: 4000000000\r\n\r\n{")
+        .unwrap();
+    assert!(client.reader.read_message().await.unwrap().is_none());
+    env.wait_for_clients(1).await;
+
+    other.request(1, "test/request", json!(null)).await;
+    let req = server.request().await;
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(other.response().await.id, RequestId::Number(1));
+}
+
+#[tokio::test]
+async fn connections_can_be_compressed() {
+    let mut env = TestEnv::new().await;
+    let mut options = env.options();
+    let ext::Request::Connect { compression, .. } = &mut options.method else {
+        unreachable!();
+    };
+    *compression = Some(ext::Compression::Zstd);
+    let mut client = env.client_with(options).await;
+    // The confirmation is the last uncompressed message.
+    let Message::Notification(notif) = client.recv().await else {
+        panic!("expected notification");
+    };
+    assert_eq!(notif.method, "$/lspmux/compression");
+    assert_eq!(notif.params, json!({ "compression": "zstd" }));
+    let mut client = client.compressed();
+
+    let mut server = env.server().await;
+    client.initialized().await;
+    client
+        .request(1, "test/request", json!("x".repeat(4096)))
+        .await;
+    let req = server.request().await;
+    assert_eq!(req.params, json!("x".repeat(4096)));
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(client.response().await.id, RequestId::Number(1));
+}
+
+#[tokio::test]
+async fn batches_are_routed_as_individual_messages() {
+    use std::io::Write;
+
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    let batch = r#"[{"jsonrpc":"2.0","method":"test/first","params":null,"id":1},{"jsonrpc":"2.0","method":"test/second","params":null,"id":2}]"#;
+    (&client.socket)
+        .write_all(format!("Content-Length: {}\r\n\r\n{batch}", batch.len()).as_bytes())
+        .unwrap();
+
+    // Both requests get their IDs tagged like any other request.
+    for (id, method) in [(1, "test/first"), (2, "test/second")] {
+        let req = server.request().await;
+        assert_eq!(req.method, method);
+        assert_ne!(req.id, RequestId::Number(id));
+        server.send(ResponseSuccess::null(req.id)).await;
+        assert_eq!(client.response().await.id, RequestId::Number(id));
+    }
+}
+
+#[tokio::test]
+async fn trace_observers_see_messages_in_both_directions() {
+    let mut env = TestEnv::new().await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+    env.wait_for_clients(1).await;
+
+    let mut observer = env
+        .client_with(LspMuxOptions {
+            version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+            token: None,
+            method: ext::Request::Trace {
+                workspace_root: env.dir.to_str().unwrap().to_owned(),
+            },
+        })
+        .await;
+    let res =
+        serde_json::from_value::<ext::TraceResponse>(observer.response().await.result).unwrap();
+    assert_eq!(res.instances.len(), 1);
+
+    client.request(1, "test/request", json!(null)).await;
+    let req = server.request().await;
+    server.send(ResponseSuccess::null(req.id.clone())).await;
+    client.response().await;
+
+    let mut events = Vec::new();
+    for _ in 0..2 {
+        let Message::Notification(notif) = observer.recv().await else {
+            panic!("expected trace notification");
+        };
+        assert_eq!(notif.method, "$/lspmux/trace");
+        events.push(serde_json::from_value::<ext::TraceEvent>(notif.params).unwrap());
+    }
+    assert_eq!(events[0].direction, ext::TraceDirection::ToServer);
+    assert_eq!(events[0].kind, ext::TraceKind::Request);
+    assert_eq!(events[0].method.as_deref(), Some("test/request"));
+    assert_eq!(events[0].id.as_ref(), Some(&req.id));
+    assert!(events[0].size > 0);
+    assert_eq!(events[1].direction, ext::TraceDirection::FromServer);
+    assert_eq!(events[1].kind, ext::TraceKind::Response);
+    assert_eq!(events[1].pid, res.instances[0].pid);
+
+    // Observers going away don't affect the clients.
+    drop(observer);
+    client.request(2, "test/request", json!(null)).await;
+    let req = server.request().await;
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(client.response().await.id, RequestId::Number(2));
+}
+
+#[tokio::test]
+async fn warmed_up_instances_wait_for_their_first_client() {
+    let mut env = TestEnv::with_config(Config {
+        instance_timeout: Some(0),
+        gc_interval: 1,
+        ..Config::default()
+    })
+    .await;
+    let ext::Request::Connect { server, args, .. } = env.options().method else {
+        unreachable!();
+    };
+    let warmup = LspMuxOptions {
+        version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+        token: None,
+        method: ext::Request::Warmup {
+            server,
+            args,
+            env: Default::default(),
+            workspace_root: env.dir.to_str().unwrap().to_owned(),
+        },
+    };
+    let mut ctl = env.client_with(warmup).await;
+    let mut server = env.open_server().await;
+    let req = server.request().await;
+    assert_eq!(req.method, "initialize");
+    let root_uri = format!("file://{}", env.dir.to_str().unwrap());
+    assert_eq!(req.params["rootUri"], json!(root_uri));
+    // Features like settings pulls depend on the first client's capabilities.
+    assert_eq!(
+        req.params["capabilities"]["workspace"]["configuration"],
+        true
+    );
+    assert_eq!(
+        req.params["capabilities"]["window"]["workDoneProgress"],
+        true
+    );
+    server
+        .send(ResponseSuccess {
+            jsonrpc: Version,
+            result: json!({ "capabilities": {} }),
+            id: req.id,
+        })
+        .await;
+    assert_eq!(server.notification().await.method, "initialized");
+    let res = serde_json::from_value::<ext::WarmupResponse>(ctl.response().await.result).unwrap();
+    assert!(res.instance.pinned);
+
+    // The garbage collector leaves the instance alone without any clients.
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(env.status().await.instances[0].pinned);
+
+    // The first client takes over, the instance is shut down after it leaves.
+    let mut client = env.client().await;
+    client.initialized().await;
+    env.wait_for_clients(1).await;
+    assert!(!env.status().await.instances[0].pinned);
+    drop(client);
+    assert_eq!(server.request().await.method, "shutdown");
+}
+
+#[tokio::test]
+async fn clients_are_told_why_the_server_didnt_start() {
+    let mut env = TestEnv::new().await;
+    let not_executable = env.dir.join("not-executable");
+    fs::write(&not_executable, "").unwrap();
+    for (server, args, reason) in [
+        (
+            "ra-multiplex-missing-server",
+            vec![],
+            RejectReason::ServerNotFound,
+        ),
+        ("/nonexistent/server", vec![], RejectReason::ServerNotFound),
+        (
+            not_executable.to_str().unwrap(),
+            vec![],
+            RejectReason::ServerPermissionDenied,
+        ),
+        ("sh", vec!["-c", "exit 3"], RejectReason::ServerExited),
+    ] {
+        let mut options = env.options();
+        let ext::Request::Connect {
+            server: options_server,
+            args: options_args,
+            ..
+        } = &mut options.method
+        else {
+            unreachable!();
+        };
+        *options_server = server.into();
+        *options_args = args.into_iter().map(String::from).collect();
+        let mut client = env.client_with(options).await;
+        assert_eq!(client.rejected().await, reason, "{server}");
+    }
+}
+
+#[tokio::test]
+async fn client_names_are_shown_in_status() {
+    let mut env = TestEnv::new().await;
+    let mut options = env.options();
+    let ext::Request::Connect { client_name, .. } = &mut options.method else {
+        unreachable!();
+    };
+    *client_name = Some("Neovim/lib.rs".into());
+    let mut named = env.client_with(options).await;
+    let _server = env.server().await;
+    named.initialized().await;
+    let mut unnamed = env.client().await;
+    unnamed.initialized().await;
+    env.wait_for_clients(2).await;
+
+    let mut clients = env.status().await.instances.remove(0).clients;
+    clients.sort_by_key(|client| client.id);
+    assert_eq!(clients[0].name.as_deref(), Some("Neovim/lib.rs"));
+    assert_eq!(clients[1].name, None);
+}
+
+#[tokio::test]
+async fn clients_need_an_existing_workspace_folder() {
+    let mut env = TestEnv::new().await;
+    let file = env.dir.join("file");
+    fs::write(&file, "").unwrap();
+    let with_cwd = |env: &TestEnv, cwd: String| {
+        let mut options = env.options();
+        let ext::Request::Connect {
+            cwd: options_cwd, ..
+        } = &mut options.method
+        else {
+            unreachable!();
+        };
+        *options_cwd = Some(cwd);
+        options
+    };
+    for cwd in [env.dir.join("missing"), file] {
+        let options = with_cwd(&env, cwd.to_str().unwrap().into());
+        let mut client = env.client_with(options).await;
+        assert_eq!(
+            client.rejected().await,
+            RejectReason::InvalidWorkspace,
+            "{cwd:?}"
+        );
+    }
+    assert!(env.status().await.instances.is_empty());
+
+    // A trailing slash is the same workspace.
+    let mut client = env.client().await;
+    let _server = env.server().await;
+    client.initialized().await;
+    let options = with_cwd(&env, format!("{}/", env.dir.to_str().unwrap()));
+    let mut client = env.client_with(options).await;
+    client.initialized().await;
+    env.wait_for_clients(2).await;
+}
+
+#[tokio::test]
+async fn in_flight_requests_are_limited_and_shared_fairly() {
+    let mut env = TestEnv::with_config(Config {
+        max_in_flight_requests: Some(1),
+        ..Config::default()
+    })
+    .await;
+    let mut first = env.client().await;
+    let mut server = env.server().await;
+    first.initialized().await;
+    let mut second = env.client().await;
+    second.initialized().await;
+    env.wait_for_clients(2).await;
+
+    for id in 1..=3 {
+        first.request(id, "test/first", json!(id)).await;
+    }
+    let mut req = server.request().await;
+    assert_eq!(req.params, json!(1));
+    // The first client's other requests are already waiting, clients take
+    // turns.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    second.request(1, "test/second", json!(1)).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut order = Vec::new();
+    for _ in 0..3 {
+        server.send(ResponseSuccess::null(req.id)).await;
+        req = server.request().await;
+        order.push((req.method.clone(), req.params.clone()));
+    }
+    server.send(ResponseSuccess::null(req.id)).await;
+    assert_eq!(
+        order,
+        [
+            ("test/first".to_owned(), json!(2)),
+            ("test/second".to_owned(), json!(1)),
+            ("test/first".to_owned(), json!(3)),
+        ]
+    );
+    for id in 1..=3 {
+        assert_eq!(first.response().await.id, RequestId::Number(id));
+    }
+    assert_eq!(second.response().await.id, RequestId::Number(1));
+}
+
+#[tokio::test]
+async fn cancels_get_through_while_requests_wait() {
+    let mut env = TestEnv::with_config(Config {
+        max_in_flight_requests: Some(1),
+        ..Config::default()
+    })
+    .await;
+    let mut client = env.client().await;
+    let mut server = env.server().await;
+    client.initialized().await;
+
+    client.request(1, "test/slow", json!(null)).await;
+    let slow = server.request().await;
+    client.request(2, "test/waiting", json!(null)).await;
+    // The client is still read from while its request waits for the server.
+    client.notify("$/cancelRequest", json!({ "id": 1 })).await;
+    let cancel = server.notification().await;
+    assert_eq!(cancel.method, "$/cancelRequest");
+    assert_eq!(cancel.params["id"], serde_json::to_value(&slow.id).unwrap());
+    // The waiting request is answered without reaching the server.
+    client.notify("$/cancelRequest", json!({ "id": 2 })).await;
+    match client.recv().await {
+        Message::ResponseError(res) => {
+            assert_eq!(res.id, Some(RequestId::Number(2)));
+            assert_eq!(res.error.code, -32800);
+        }
+        other => panic!("expected error response, got {other:?}"),
+    }
+
+    server.send(ResponseSuccess::null(slow.id)).await;
+    assert_eq!(client.response().await.id, RequestId::Number(1));
+    client.request(3, "test/next", json!(null)).await;
+    assert_eq!(server.request().await.method, "test/next");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn server_environment_is_merged_from_config_client_and_project() {
+    let mut env = TestEnv::with_config(Config {
+        server_env: [("FROM_CONFIG", "config"), ("OVERRIDDEN", "config")]
+            .map(|(key, val)| (key.to_owned(), val.to_owned()))
+            .into(),
+        server_clear_env: true,
+        trust_project_config: true,
+        ..Config::default()
+    })
+    .await;
+    fs::write(
+        env.dir.join(".ra-multiplex.toml"),
+        "env = { OVERRIDDEN = \"project\" }",
+    )
+    .unwrap();
+    let mut options = env.options();
+    let ext::Request::Connect {
+        env: client_env, ..
+    } = &mut options.method
+    else {
+        unreachable!();
+    };
+    client_env.insert("OVERRIDDEN".into(), "client".into());
+    client_env.insert("FROM_CLIENT".into(), "client".into());
+    let mut client = env.client_with(options).await;
+    let _server = env.server().await;
+    client.initialized().await;
+
+    let pid = env.status().await.instances[0].pid;
+    let environ = fs::read(format!("/proc/{pid}/environ")).unwrap();
+    let environ = String::from_utf8(environ).unwrap();
+    let environ = environ.split('\0').collect::<Vec<_>>();
+    for var in [
+        "FROM_CONFIG=config",
+        "FROM_CLIENT=client",
+        "OVERRIDDEN=project",
+    ] {
+        assert!(environ.contains(&var), "{var} missing in {environ:?}");
+    }
+    // Cargo sets it for the tests, the language server doesn't inherit it.
+    if std::env::var_os("CARGO_MANIFEST_DIR").is_some() {
+        assert!(
+            !environ
+                .iter()
+                .any(|var| var.starts_with("CARGO_MANIFEST_DIR=")),
+            "{environ:?}"
+        );
+    }
+}
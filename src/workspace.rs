@@ -0,0 +1,188 @@
+//! Finding the project root of a workspace folder
+//!
+//! Editors opened in different subdirectories of the same project should share
+//! one language server instance, so instances are keyed by the project root
+//! instead of the folder the client sent.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, InstanceRoot};
+
+/// Files marking the root of a project for language servers other than
+/// rust-analyzer, matched against the server file name
+const ROOT_MARKERS: &[(&str, &[&str])] = &[
+    ("clangd", &["compile_commands.json", ".clangd"]),
+    ("gopls", &["go.work", "go.mod"]),
+];
+
+/// Find the project root containing `path`
+///
+/// For rust-analyzer this is the cargo workspace root, the closest directory
+/// with a `Cargo.toml` containing a `[workspace]` table or otherwise the
+/// topmost directory with a `Cargo.toml`. Other servers use the closest
+/// directory containing one of their [`ROOT_MARKERS`]. If nothing is found
+/// `path` itself is the root.
+///
+/// The `instance_root` option can pick the closest `Cargo.toml` or `path`
+/// itself instead, and `root_markers` replaces the markers of every server.
+///
+/// Symlinks are resolved so different paths to the same directory end up with
+/// the same root.
+pub fn find_root(path: &Path, server: &str, config: &Config) -> PathBuf {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    let server_name = Path::new(server)
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or(server);
+
+    let root = match (config.instance_root, &config.root_markers) {
+        (InstanceRoot::Folder, _) => None,
+        (_, Some(markers)) => closest_marker(&path, markers),
+        (InstanceRoot::Workspace, None) if server_name == "rust-analyzer" => {
+            cargo_workspace_root(&path)
+        }
+        (InstanceRoot::Nearest, None) if server_name == "rust-analyzer" => {
+            closest_marker(&path, &["Cargo.toml"])
+        }
+        (_, None) => ROOT_MARKERS
+            .iter()
+            .find(|(name, _)| *name == server_name)
+            .and_then(|(_, markers)| closest_marker(&path, markers)),
+    };
+    root.map(Path::to_owned).unwrap_or(path)
+}
+
+fn closest_marker<'a>(path: &'a Path, markers: &[impl AsRef<Path>]) -> Option<&'a Path> {
+    path.ancestors()
+        .find(|dir| markers.iter().any(|marker| dir.join(marker).exists()))
+}
+
+fn cargo_workspace_root(path: &Path) -> Option<&Path> {
+    let mut topmost = None;
+    for dir in path.ancestors() {
+        let Ok(manifest) = fs::read_to_string(dir.join("Cargo.toml")) else {
+            continue;
+        };
+        if is_workspace_manifest(&manifest) {
+            return Some(dir);
+        }
+        topmost = Some(dir);
+    }
+    topmost
+}
+
+fn is_workspace_manifest(manifest: &str) -> bool {
+    match toml::from_str::<toml::Value>(manifest) {
+        Ok(manifest) => manifest.get("workspace").is_some(),
+        // Don't let a broken manifest hide the workspace.
+        Err(_) => manifest.lines().any(|line| line.trim() == "[workspace]"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn tempdir() -> PathBuf {
+        static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "ra-multiplex-workspace-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed),
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn cargo_workspace_root() {
+        let config = Config::default();
+        let dir = tempdir();
+        write(&dir.join("Cargo.toml"), "[workspace]\nmembers = [\"a\"]\n");
+        write(&dir.join("a/Cargo.toml"), "[package]\nname = \"a\"\n");
+        fs::create_dir_all(dir.join("a/src")).unwrap();
+
+        assert_eq!(find_root(&dir.join("a/src"), "rust-analyzer", &config), dir);
+        assert_eq!(find_root(&dir, "/usr/bin/rust-analyzer", &config), dir);
+
+        // Without a `[workspace]` the topmost package wins.
+        write(&dir.join("Cargo.toml"), "[package]\nname = \"root\"\n");
+        assert_eq!(find_root(&dir.join("a/src"), "rust-analyzer", &config), dir);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn other_servers() {
+        let config = Config::default();
+        let dir = tempdir();
+        write(&dir.join("compile_commands.json"), "[]");
+        fs::create_dir_all(dir.join("src")).unwrap();
+
+        assert_eq!(find_root(&dir.join("src"), "clangd", &config), dir);
+        // Unknown servers use the path as is.
+        assert_eq!(
+            find_root(&dir.join("src"), "pylsp", &config),
+            dir.join("src")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_resolved() {
+        let config = Config::default();
+        let dir = tempdir();
+        fs::create_dir_all(dir.join("project")).unwrap();
+        std::os::unix::fs::symlink(dir.join("project"), dir.join("link")).unwrap();
+
+        assert_eq!(
+            find_root(&dir.join("link"), "pylsp", &config),
+            dir.join("project")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn instance_root_granularity() {
+        let dir = tempdir();
+        write(&dir.join("Cargo.toml"), "[workspace]\nmembers = [\"a\"]\n");
+        write(&dir.join("a/Cargo.toml"), "[package]\nname = \"a\"\n");
+        write(&dir.join("a/BUILD"), "");
+        fs::create_dir_all(dir.join("a/src")).unwrap();
+
+        let config = Config {
+            instance_root: InstanceRoot::Nearest,
+            ..Config::default()
+        };
+        assert_eq!(
+            find_root(&dir.join("a/src"), "rust-analyzer", &config),
+            dir.join("a")
+        );
+        let config = Config {
+            instance_root: InstanceRoot::Folder,
+            ..Config::default()
+        };
+        assert_eq!(
+            find_root(&dir.join("a/src"), "rust-analyzer", &config),
+            dir.join("a/src")
+        );
+        let config = Config {
+            root_markers: Some(vec!["BUILD".into()]),
+            ..Config::default()
+        };
+        assert_eq!(
+            find_root(&dir.join("a/src"), "pylsp", &config),
+            dir.join("a")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}